@@ -0,0 +1,74 @@
+#![no_main]
+
+use advoid::dns::StubRequestHandler;
+use advoid::upstream::UpstreamClient;
+use hickory_client::op::{DnsResponse, Message, MessageType};
+use hickory_client::rr::{DNSClass, Name, Record, RecordType};
+use hickory_proto::rr::rdata::opt::EdnsOption;
+use hickory_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use libfuzzer_sys::fuzz_target;
+use std::io;
+use std::sync::{Arc, OnceLock};
+
+/// Answers every query with an empty response, without touching the network.
+struct EmptyUpstream;
+
+#[async_trait::async_trait]
+impl UpstreamClient for EmptyUpstream {
+    async fn query(
+        &self,
+        _name: Name,
+        _query_class: DNSClass,
+        _query_type: RecordType,
+        _options: &[EdnsOption],
+    ) -> anyhow::Result<DnsResponse> {
+        let mut message = Message::new();
+        message.set_message_type(MessageType::Response);
+        Ok(DnsResponse::from_message(message)?)
+    }
+}
+
+/// Serializes the response the way the UDP listener would, then drops it.
+#[derive(Clone)]
+struct Discard;
+
+#[async_trait::async_trait]
+impl ResponseHandler for Discard {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut bytes = Vec::new();
+        Ok(response.destructive_emit(&mut BinEncoder::new(&mut bytes))?)
+    }
+}
+
+fn handler() -> &'static StubRequestHandler {
+    static HANDLER: OnceLock<StubRequestHandler> = OnceLock::new();
+    HANDLER.get_or_init(|| {
+        let blocklist = advoid::blocklist::parse("ads.example\n# comment\ntracker.example\n");
+        StubRequestHandler::new(Arc::new(EmptyUpstream), blocklist)
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Messages hickory can't parse are answered with FORMERR before reaching the handler.
+    let Ok(message) = MessageRequest::read(&mut BinDecoder::new(data)) else {
+        return;
+    };
+    let request = Request::new(message, "192.0.2.1:53000".parse().unwrap(), Protocol::Udp);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(handler().handle_request(&request, Discard));
+});
@@ -0,0 +1,122 @@
+use hickory_client::rr::{Name, RData, Record, RecordType};
+use hickory_proto::rr::rdata::TXT;
+use rustc_hash::FxHashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a challenge is answered when it isn't cleaned up.
+const LIFETIME: Duration = Duration::from_secs(3600);
+
+/// TTL of the answers, short so a changed challenge is seen right away.
+const TTL: u32 = 10;
+
+/// TXT records for ACME DNS-01 challenges (`_acme-challenge.<domain>`) under the configured
+/// zones, added and removed through the admin API while a certificate is issued.
+pub struct AcmeChallenges {
+    zones: Vec<Name>,
+    records: Mutex<FxHashMap<Name, Vec<(String, Instant)>>>,
+}
+
+impl AcmeChallenges {
+    pub fn new(zones: Vec<Name>) -> Self {
+        AcmeChallenges {
+            zones: zones.iter().map(Name::to_lowercase).collect(),
+            records: Mutex::default(),
+        }
+    }
+
+    /// Answers TXT queries for `name` with `value` until cleaned up or an hour has passed.
+    pub fn present(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        let name = self.parse_name(name)?;
+        anyhow::ensure!(
+            !value.is_empty() && value.len() <= 255,
+            "challenge value must be 1 to 255 bytes"
+        );
+
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, values| {
+            values.retain(|(_, expires)| *expires > now);
+            !values.is_empty()
+        });
+        let values = records.entry(name).or_default();
+        values.retain(|(it, _)| it != value);
+        values.push((value.to_string(), now + LIFETIME));
+        Ok(())
+    }
+
+    /// Stops answering `value` for `name`, or every value without one, and returns whether
+    /// there was anything to remove.
+    pub fn clean_up(&self, name: &str, value: Option<&str>) -> anyhow::Result<bool> {
+        let name = self.parse_name(name)?;
+        let mut records = self.records.lock().unwrap();
+        let Some(values) = records.get_mut(&name) else {
+            return Ok(false);
+        };
+        let before = values.len();
+        values.retain(|(it, _)| value.is_some_and(|value| it != value));
+        let removed = values.len() < before;
+        if values.is_empty() {
+            records.remove(&name);
+        }
+        Ok(removed)
+    }
+
+    /// The answer to a `query_type` query for `name`, or `None` if no challenge is presented
+    /// for it.
+    pub(crate) fn lookup(&self, name: &Name, query_type: RecordType) -> Option<Vec<Record>> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        let values = records.get(&name.to_lowercase())?;
+        let live = values.iter().filter(|(_, expires)| *expires > now);
+        live.clone().next()?;
+        Some(match query_type {
+            RecordType::TXT | RecordType::ANY => live
+                .map(|(value, _)| {
+                    let txt = RData::TXT(TXT::new(vec![value.clone()]));
+                    Record::from_rdata(name.clone(), TTL, txt)
+                })
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    fn parse_name(&self, name: &str) -> anyhow::Result<Name> {
+        let mut name = Name::from_str(name)?.to_lowercase();
+        name.set_fqdn(true);
+        anyhow::ensure!(
+            self.zones.iter().any(|zone| zone.zone_of(&name)),
+            "{} is not under an ACME zone",
+            name
+        );
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_presented_challenges_until_cleaned_up() {
+        let acme = AcmeChallenges::new(vec![Name::from_str("example.lan.").unwrap()]);
+        let name = Name::from_str("_acme-challenge.Example.lan.").unwrap();
+
+        assert!(acme.present("_acme-challenge.other.", "token").is_err());
+        acme.present("_acme-challenge.example.lan", "first")
+            .unwrap();
+        acme.present("_acme-challenge.example.lan", "second")
+            .unwrap();
+        assert_eq!(acme.lookup(&name, RecordType::TXT).unwrap().len(), 2);
+        assert_eq!(acme.lookup(&name, RecordType::A).unwrap(), Vec::new());
+
+        assert!(acme
+            .clean_up("_acme-challenge.example.lan", Some("first"))
+            .unwrap());
+        assert_eq!(acme.lookup(&name, RecordType::TXT).unwrap().len(), 1);
+        assert!(acme.clean_up("_acme-challenge.example.lan", None).unwrap());
+        assert!(acme.lookup(&name, RecordType::TXT).is_none());
+        assert!(!acme.clean_up("_acme-challenge.example.lan", None).unwrap());
+    }
+}
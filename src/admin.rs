@@ -0,0 +1,441 @@
+use crate::acme::AcmeChallenges;
+use crate::blocklist::{ListSource, ListStats};
+use crate::cache::{CacheEntry, ResponseCache};
+#[cfg(feature = "remote-blocklist")]
+use crate::clock::unix_time;
+use crate::history::{History, HistoryEntry};
+use crate::http::with_access_control;
+use crate::overrides::{OverrideEntries, OverrideList, Overrides};
+#[cfg(feature = "remote-blocklist")]
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, State};
+#[cfg(feature = "remote-blocklist")]
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use hickory_client::rr::Name;
+#[cfg(feature = "remote-blocklist")]
+use ring::hmac;
+#[cfg(feature = "remote-blocklist")]
+use rustc_hash::FxHashMap;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+#[cfg(feature = "remote-blocklist")]
+use std::sync::Mutex;
+use tokio::net::TcpListener;
+
+/// Larger request bodies are rejected with 413 before they're read in full.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+struct AdminState {
+    cache: Option<Arc<ResponseCache>>,
+    history: Option<Arc<History>>,
+    lists: Arc<Vec<ListSource>>,
+    overrides: Option<Arc<Overrides>>,
+    acme: Option<Arc<AcmeChallenges>>,
+    #[cfg(feature = "remote-blocklist")]
+    webhook: Option<Arc<Webhook>>,
+}
+
+/// Refreshes a list source when its provider signals that it changed, instead of waiting for
+/// the next SIGHUP.
+///
+/// Requests must carry `X-Signature-Timestamp: <UNIX SECONDS>` and `X-Signature-256:
+/// sha256=<HEX>`, the HMAC-SHA256 of `<timestamp>\n<list name>\n<body>` keyed with the shared
+/// secret. Requests signed more than `SIGNATURE_WINDOW` away from now, or replayed within it,
+/// are rejected.
+#[cfg(feature = "remote-blocklist")]
+pub struct Webhook {
+    key: hmac::Key,
+    sources: Arc<Vec<ListSource>>,
+    /// Signatures accepted within the window, with their timestamps.
+    seen: Mutex<FxHashMap<Vec<u8>, u64>>,
+}
+
+/// How far the timestamp of a webhook request may be from now, in seconds.
+#[cfg(feature = "remote-blocklist")]
+const SIGNATURE_WINDOW: u64 = 300;
+
+#[cfg(feature = "remote-blocklist")]
+impl Webhook {
+    pub fn new(secret: &[u8], sources: Arc<Vec<ListSource>>) -> Self {
+        Webhook {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            sources,
+            seen: Mutex::default(),
+        }
+    }
+
+    fn verify(&self, headers: &HeaderMap, name: &str, body: &[u8], now: u64) -> bool {
+        let header = |name| headers.get(name).and_then(|it| it.to_str().ok());
+        let Some(timestamp) = header("x-signature-timestamp").and_then(|it| it.parse::<u64>().ok())
+        else {
+            return false;
+        };
+        let Some(signature) = header("x-signature-256")
+            .and_then(|it| it.strip_prefix("sha256="))
+            .and_then(decode_hex)
+        else {
+            return false;
+        };
+        if timestamp.abs_diff(now) > SIGNATURE_WINDOW {
+            return false;
+        }
+
+        let mut message = format!("{}\n{}\n", timestamp, name).into_bytes();
+        message.extend_from_slice(body);
+        if hmac::verify(&self.key, &message, &signature).is_err() {
+            return false;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, it| it.abs_diff(now) <= SIGNATURE_WINDOW);
+        seen.insert(signature, timestamp).is_none()
+    }
+}
+
+#[cfg(feature = "remote-blocklist")]
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The admin API, requiring `Authorization: Bearer <token>` when a token is given and
+/// answering clients above the rate limit with 429.
+pub struct AdminServer {
+    endpoint: SocketAddr,
+    state: AdminState,
+    token: Option<String>,
+    rate_limit: Option<NonZeroU32>,
+}
+
+impl AdminServer {
+    pub fn new(endpoint: SocketAddr, lists: Arc<Vec<ListSource>>) -> Self {
+        AdminServer {
+            endpoint,
+            state: AdminState {
+                cache: None,
+                history: None,
+                lists,
+                overrides: None,
+                acme: None,
+                #[cfg(feature = "remote-blocklist")]
+                webhook: None,
+            },
+            token: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Requires `token` from clients and limits each to `rate_limit` requests per second.
+    pub fn with_access_control(
+        mut self,
+        token: Option<String>,
+        rate_limit: Option<NonZeroU32>,
+    ) -> Self {
+        self.token = token;
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.state.cache = Some(cache);
+        self
+    }
+
+    pub fn with_history(mut self, history: Arc<History>) -> Self {
+        self.state.history = Some(history);
+        self
+    }
+
+    pub fn with_overrides(mut self, overrides: Arc<Overrides>) -> Self {
+        self.state.overrides = Some(overrides);
+        self
+    }
+
+    pub fn with_acme_challenges(mut self, acme: Arc<AcmeChallenges>) -> Self {
+        self.state.acme = Some(acme);
+        self
+    }
+
+    #[cfg(feature = "remote-blocklist")]
+    pub fn with_webhook(mut self, webhook: Webhook) -> Self {
+        self.state.webhook = Some(Arc::new(webhook));
+        self
+    }
+
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let app = with_access_control(
+            admin_app(self.state.clone()),
+            webhook_app(self.state),
+            "admin",
+            self.token.as_deref(),
+            self.rate_limit,
+        );
+        let listener = TcpListener::bind(self.endpoint).await?;
+
+        tracing::debug!("admin api listening on {}", listener.local_addr()?);
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+fn admin_app(state: AdminState) -> Router {
+    let app = Router::new()
+        .route("/cache", get(list_cache).delete(flush_cache))
+        .route("/cache/:name", delete(flush_name))
+        .route("/history", get(find_history))
+        .route("/lists", get(list_stats))
+        .route("/overrides", get(list_overrides))
+        .route(
+            "/overrides/:list/:name",
+            put(add_override).delete(remove_override),
+        )
+        .route(
+            "/acme/:name",
+            post(present_challenge).delete(clean_up_challenge),
+        );
+    app.layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(state)
+}
+
+/// Routes webhook senders call, authenticated by their signature instead of the bearer token.
+fn webhook_app(state: AdminState) -> Router {
+    let app = Router::new();
+    #[cfg(feature = "remote-blocklist")]
+    let app = app.route("/lists/:name/refresh", post(refresh_lists));
+    app.layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(state)
+}
+
+fn cache(state: &AdminState) -> Result<&ResponseCache, (StatusCode, &'static str)> {
+    state
+        .cache
+        .as_deref()
+        .ok_or((StatusCode::NOT_FOUND, "response cache is disabled\n"))
+}
+
+async fn list_cache(
+    State(state): State<AdminState>,
+) -> Result<Json<Vec<CacheEntry>>, (StatusCode, &'static str)> {
+    Ok(Json(cache(&state)?.entries()))
+}
+
+async fn flush_cache(
+    State(state): State<AdminState>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    cache(&state)?.clear();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn flush_name(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+) -> Result<String, (StatusCode, &'static str)> {
+    let mut name =
+        Name::from_utf8(&name).map_err(|_| (StatusCode::BAD_REQUEST, "invalid name\n"))?;
+    name.set_fqdn(true);
+    let removed = cache(&state)?.remove_name(&name);
+    Ok(format!("{}\n", removed))
+}
+
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    name: String,
+}
+
+async fn find_history(
+    State(state): State<AdminState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryEntry>>, (StatusCode, &'static str)> {
+    let history = state
+        .history
+        .as_deref()
+        .ok_or((StatusCode::NOT_FOUND, "history is disabled\n"))?;
+    Ok(Json(history.find(&query.name)))
+}
+
+async fn list_stats(State(state): State<AdminState>) -> Json<Vec<ListStats>> {
+    Json(state.lists.iter().map(ListSource::stats).collect())
+}
+
+fn overrides(state: &AdminState) -> Result<&Overrides, (StatusCode, String)> {
+    state.overrides.as_deref().ok_or((
+        StatusCode::NOT_FOUND,
+        "overrides are disabled\n".to_string(),
+    ))
+}
+
+async fn list_overrides(
+    State(state): State<AdminState>,
+) -> Result<Json<OverrideEntries>, (StatusCode, String)> {
+    Ok(Json(overrides(&state)?.entries().await))
+}
+
+async fn add_override(
+    State(state): State<AdminState>,
+    Path((list, name)): Path<(OverrideList, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match set_override(&state, list, &name, true).await? {
+        true => Ok(StatusCode::CREATED),
+        false => Ok(StatusCode::NO_CONTENT),
+    }
+}
+
+async fn remove_override(
+    State(state): State<AdminState>,
+    Path((list, name)): Path<(OverrideList, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match set_override(&state, list, &name, false).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err((StatusCode::NOT_FOUND, "no such override\n".to_string())),
+    }
+}
+
+async fn set_override(
+    state: &AdminState,
+    list: OverrideList,
+    name: &str,
+    present: bool,
+) -> Result<bool, (StatusCode, String)> {
+    match overrides(state)?.set(list, name, present).await {
+        Ok(Some(changed)) => Ok(changed),
+        Ok(None) => Err((StatusCode::BAD_REQUEST, "invalid domain name\n".to_string())),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("could not save the overrides: {}\n", e),
+        )),
+    }
+}
+
+fn acme(state: &AdminState) -> Result<&AcmeChallenges, (StatusCode, String)> {
+    state.acme.as_deref().ok_or((
+        StatusCode::NOT_FOUND,
+        "ACME challenges are disabled\n".to_string(),
+    ))
+}
+
+async fn present_challenge(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    body: String,
+) -> Result<StatusCode, (StatusCode, String)> {
+    acme(&state)?
+        .present(&name, body.trim())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{}\n", e)))?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn clean_up_challenge(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    body: String,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let value = Some(body.trim()).filter(|it| !it.is_empty());
+    match acme(&state)?.clean_up(&name, value) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "no such challenge\n".to_string())),
+        Err(e) => Err((StatusCode::BAD_REQUEST, format!("{}\n", e))),
+    }
+}
+
+#[cfg(feature = "remote-blocklist")]
+async fn refresh_lists(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String, (StatusCode, String)> {
+    let webhook = state
+        .webhook
+        .as_deref()
+        .ok_or((StatusCode::NOT_FOUND, "webhook is disabled\n".to_string()))?;
+    let now = unix_time().as_secs();
+    if !webhook.verify(&headers, &name, &body, now) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "bad or replayed signature\n".to_string(),
+        ));
+    }
+    let source = webhook
+        .sources
+        .iter()
+        .find(|it| it.name == name)
+        .ok_or((StatusCode::NOT_FOUND, "no such lists\n".to_string()))?;
+
+    match source.reload().await {
+        Ok((blocked, allowed)) => {
+            tracing::info!(
+                "webhook reloaded {} blocked and {} allowed domains for {}",
+                blocked,
+                allowed,
+                name
+            );
+            Ok(format!("{} {}\n", blocked, allowed))
+        }
+        Err(e) => {
+            tracing::warn!("webhook could not reload the lists for {}: {}", name, e);
+            Err((StatusCode::BAD_GATEWAY, format!("{}\n", e)))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "remote-blocklist"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signatures_of_the_time_name_and_body() {
+        let webhook = Webhook::new(b"secret", Arc::default());
+        let now = 1_700_000_000;
+        let signed = |timestamp: u64, name: &str, body: &str| {
+            let message = format!("{}\n{}\n{}", timestamp, name, body);
+            let tag = hmac::sign(
+                &hmac::Key::new(hmac::HMAC_SHA256, b"secret"),
+                message.as_bytes(),
+            );
+            let hex: String = tag
+                .as_ref()
+                .iter()
+                .map(|it| format!("{:02x}", it))
+                .collect();
+            let mut headers = HeaderMap::new();
+            headers.insert("x-signature-timestamp", timestamp.into());
+            headers.insert(
+                "x-signature-256",
+                format!("sha256={}", hex).parse().unwrap(),
+            );
+            headers
+        };
+
+        let headers = signed(now, "default", "changed");
+        assert!(!webhook.verify(&headers, "default", b"tampered", now));
+        assert!(!webhook.verify(&headers, "kids", b"changed", now));
+        assert!(webhook.verify(&headers, "default", b"changed", now));
+        // Replayed, both within the window and after it.
+        assert!(!webhook.verify(&headers, "default", b"changed", now + 1));
+        let headers = signed(now, "default", "");
+        assert!(!webhook.verify(&headers, "default", b"", now + SIGNATURE_WINDOW + 1));
+        assert!(webhook.verify(&headers, "default", b"", now - 60));
+
+        assert!(!webhook.verify(&HeaderMap::new(), "default", b"changed", now));
+        let mut headers = signed(now, "default", "later");
+        headers.remove("x-signature-timestamp");
+        assert!(!webhook.verify(&headers, "default", b"later", now));
+        headers.insert("x-signature-timestamp", now.into());
+        headers.insert("x-signature-256", "sha256=zz".parse().unwrap());
+        assert!(!webhook.verify(&headers, "default", b"later", now));
+    }
+}
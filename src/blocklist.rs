@@ -1,24 +1,559 @@
+use crate::clock::unix_time;
+use crate::dns::Lists;
+#[cfg(feature = "remote-blocklist")]
+use crate::fs::write_atomically;
+use crate::gravity::{self, Gravity};
+use crate::suffix::{parse_types, TYPE_OPTION};
 use rustc_hash::FxHashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
-pub async fn get(url: String) -> anyhow::Result<FxHashSet<String>> {
+/// Which domains a list is read for. Only tells apart those of a Pi-hole gravity database,
+/// which holds both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListKind {
+    Block,
+    Allow,
+}
+
+/// Reads a list from a file or url. Downloads are kept in `cache_dir`, when given, and only
+/// fetched again once they changed; the kept copy is used if the download fails.
+pub async fn get(
+    url: String,
+    kind: ListKind,
+    cache_dir: Option<&Path>,
+) -> anyhow::Result<FxHashSet<String>> {
     let payload = if url.starts_with("http") {
-        reqwest::get(url).await?.text().await?
+        download(url, cache_dir).await?
     } else {
-        let mut f = File::open(url).await?;
-        let mut buf = String::new();
-        let _ = f.read_to_string(&mut buf).await;
-        buf
+        let mut f = File::open(&url).await?;
+        let mut buf = Vec::new();
+        let _ = f.read_to_end(&mut buf).await;
+        if gravity::is_database(&buf) {
+            return read_gravity(&url, kind);
+        }
+        String::from_utf8_lossy(&buf).into_owned()
+    };
+
+    Ok(parse(&payload))
+}
+
+/// Like `get`, for lists that may not be given.
+pub async fn get_optional(
+    url: Option<&str>,
+    kind: ListKind,
+    cache_dir: Option<&Path>,
+) -> anyhow::Result<Option<FxHashSet<String>>> {
+    match url {
+        Some(url) => Ok(Some(get(url.to_string(), kind, cache_dir).await?)),
+        None => Ok(None),
+    }
+}
+
+fn read_gravity(path: &str, kind: ListKind) -> anyhow::Result<FxHashSet<String>> {
+    let gravity = Gravity::read(Path::new(path)).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+    if !gravity.skipped.is_empty() {
+        tracing::warn!(
+            "{}: {} regexes other than domain matches are not used",
+            path,
+            gravity.skipped.len()
+        );
+    }
+    Ok(match kind {
+        ListKind::Block => gravity.block,
+        ListKind::Allow => gravity.allow,
+    })
+}
+
+/// Where the lists of a policy are loaded from, so they can be loaded again while serving.
+pub struct ListSource {
+    /// `default`, or the name of the client group.
+    pub name: String,
+    pub lists: Lists,
+    /// File or url of the block list, `None` where the policy shares the default one.
+    pub block: Option<String>,
+    /// File or url of the allow list, `None` where the policy shares the default one.
+    pub allow: Option<String>,
+    pub cache_dir: Option<PathBuf>,
+    /// When the lists were last loaded and why that failed, if it did.
+    last_refresh: Mutex<(u64, Option<String>)>,
+}
+
+/// The state of a list source as shown by the admin API.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ListStats {
+    pub name: String,
+    pub block: Option<String>,
+    pub allow: Option<String>,
+    /// Entries of the lists in use, counting those shared with the default policy.
+    pub blocked: usize,
+    pub allowed: usize,
+    /// Seconds since the Unix epoch.
+    pub refreshed: u64,
+    /// Why the last refresh failed, `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+impl ListSource {
+    /// A source for `lists`, which were loaded just now.
+    pub fn new(
+        name: String,
+        lists: Lists,
+        block: Option<String>,
+        allow: Option<String>,
+        cache_dir: Option<PathBuf>,
+    ) -> Self {
+        let source = ListSource {
+            name,
+            lists,
+            block,
+            allow,
+            cache_dir,
+            last_refresh: Mutex::new((unix_time().as_secs(), None)),
+        };
+        source.record_metrics();
+        source
+    }
+
+    /// Loads the lists again and swaps them in, returning how many blocked and allowed domains
+    /// were loaded. The current lists are kept if either can't be loaded.
+    pub async fn reload(&self) -> anyhow::Result<(usize, usize)> {
+        let result = self.load().await;
+        *self.last_refresh.lock().unwrap() = (
+            unix_time().as_secs(),
+            result.as_ref().err().map(|e| e.to_string()),
+        );
+        self.record_metrics();
+        result
+    }
+
+    pub fn stats(&self) -> ListStats {
+        let (blocked, allowed) = self.lists.counts();
+        let (refreshed, error) = self.last_refresh.lock().unwrap().clone();
+        ListStats {
+            name: self.name.clone(),
+            block: self.block.clone(),
+            allow: self.allow.clone(),
+            blocked,
+            allowed,
+            refreshed,
+            error,
+        }
+    }
+
+    fn record_metrics(&self) {
+        let stats = self.stats();
+        metrics::gauge!("blocklist_entries", "policy" => stats.name.clone(), "list" => "block")
+            .set(stats.blocked as f64);
+        metrics::gauge!("blocklist_entries", "policy" => stats.name.clone(), "list" => "allow")
+            .set(stats.allowed as f64);
+        metrics::gauge!("blocklist_last_refresh_timestamp_seconds", "policy" => stats.name.clone())
+            .set(stats.refreshed as f64);
+        metrics::gauge!("blocklist_last_refresh_success", "policy" => stats.name)
+            .set(if stats.error.is_none() { 1.0 } else { 0.0 });
+    }
+
+    async fn load(&self) -> anyhow::Result<(usize, usize)> {
+        let cache_dir = self.cache_dir.as_deref();
+        let (blocklist, allowlist) = tokio::try_join!(
+            get_optional(self.block.as_deref(), ListKind::Block, cache_dir),
+            get_optional(self.allow.as_deref(), ListKind::Allow, cache_dir)
+        )?;
+        let loaded = (
+            blocklist.as_ref().map_or(0, |it| it.len()),
+            allowlist.as_ref().map_or(0, |it| it.len()),
+        );
+        self.lists.replace(blocklist, allowlist).await;
+        Ok(loaded)
+    }
+}
+
+/// Domains listed one per line, skipping blank lines and comments.
+///
+/// Besides bare domains, hosts file lines (`0.0.0.0 example.com`) and AdBlock domain rules
+/// (`||example.com^`) are understood, so most public lists can be used as they are. Entries
+/// followed by `$dnstype=AAAA|TXT` only apply to those record types and keep the option.
+pub fn parse(payload: &str) -> FxHashSet<String> {
+    payload.lines().flat_map(parse_rule).collect()
+}
+
+/// The entries of a single line of a list.
+pub(crate) fn parse_rule(line: &str) -> Vec<String> {
+    let (line, types) = match line.trim().split_once(TYPE_OPTION) {
+        Some((line, option)) => {
+            let types = option.split_whitespace().next().unwrap_or_default();
+            match parse_types(types) {
+                Some(_) => (line, Some(types.to_ascii_uppercase())),
+                None => return Vec::new(),
+            }
+        }
+        None => (line, None),
+    };
+
+    parse_line(line)
+        .into_iter()
+        .map(|it| {
+            // Queries are matched in lowercase.
+            let domain = it.trim_end_matches('.').to_ascii_lowercase();
+            match &types {
+                Some(types) => format!("{}.{}{}", domain, TYPE_OPTION, types),
+                None => format!("{}.", domain),
+            }
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Vec<&str> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') || line.starts_with('[') {
+        return Vec::new();
+    }
+
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule).into_iter().collect();
+    }
+
+    // Inline comments start at a token beginning with `#`, unlike cosmetic `example.com##.ad` rules.
+    let mut tokens = line
+        .split_whitespace()
+        .take_while(|it| !it.starts_with('#'));
+    let domains: Vec<_> = match tokens.next() {
+        Some(first) if first.parse::<IpAddr>().is_ok() => tokens
+            .filter(|it| !HOSTS_LOCAL_NAMES.contains(it))
+            .collect(),
+        Some(domain) => vec![domain],
+        None => Vec::new(),
     };
+    domains.into_iter().filter(|it| is_domain(it)).collect()
+}
+
+/// The domain of a `||example.com^` rule. Rules with paths, wildcards or options block less
+/// than the whole domain and are skipped.
+fn adblock_domain(rule: &str) -> Option<&str> {
+    let domain = rule.strip_suffix('^').unwrap_or(rule);
+    is_domain(domain).then_some(domain)
+}
+
+/// Anything else, such as exception and cosmetic AdBlock rules, is not a domain to block.
+/// Neither are lines of dots, which would be the root and match every name.
+fn is_domain(it: &str) -> bool {
+    it.chars().any(|c| c != '.')
+        && it
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Names hosts files map to the loopback address for the system's own use.
+const HOSTS_LOCAL_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+#[cfg(feature = "remote-blocklist")]
+async fn download(url: String, cache_dir: Option<&Path>) -> anyhow::Result<String> {
+    use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+    use reqwest::StatusCode;
+
+    let Some(cache_dir) = cache_dir else {
+        return Ok(reqwest::get(url).await?.error_for_status()?.text().await?);
+    };
+    let cached = CachedList::new(cache_dir, &url);
+    let kept = cached.read().await;
+
+    let mut request = reqwest::Client::new().get(&url);
+    if let Some((validators, _)) = &kept {
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = match request.send().await.and_then(|it| it.error_for_status()) {
+        Ok(response) => response,
+        Err(e) => match kept {
+            Some((_, payload)) => {
+                tracing::warn!("could not download {}, using the kept copy: {}", url, e);
+                return Ok(payload);
+            }
+            None => return Err(e.into()),
+        },
+    };
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some((_, payload)) = kept {
+            tracing::debug!("{} has not changed", url);
+            return Ok(payload);
+        }
+    }
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|it: &reqwest::header::HeaderValue| it.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    let payload = response.text().await?;
+    if let Err(e) = cached.write(&validators, &payload).await {
+        tracing::warn!("could not keep a copy of {}: {}", url, e);
+    }
+    Ok(payload)
+}
+
+#[cfg(not(feature = "remote-blocklist"))]
+async fn download(url: String, _cache_dir: Option<&Path>) -> anyhow::Result<String> {
+    anyhow::bail!("{} needs advoid built with remote-blocklist", url)
+}
+
+/// What a server needs to tell whether a list changed since it was downloaded.
+#[cfg(feature = "remote-blocklist")]
+#[derive(Debug, Default, PartialEq)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+#[cfg(feature = "remote-blocklist")]
+impl Validators {
+    /// Reads the `.meta` file of a kept list, ignoring what it doesn't understand.
+    fn parse(meta: &str) -> Self {
+        let mut validators = Validators::default();
+        for line in meta.lines() {
+            match line.split_once(": ") {
+                Some(("etag", value)) => validators.etag = Some(value.to_string()),
+                Some(("last-modified", value)) => {
+                    validators.last_modified = Some(value.to_string())
+                }
+                _ => {}
+            }
+        }
+        validators
+    }
+
+    fn to_meta(&self) -> String {
+        let mut meta = String::new();
+        if let Some(etag) = &self.etag {
+            meta.push_str(&format!("etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            meta.push_str(&format!("last-modified: {}\n", last_modified));
+        }
+        meta
+    }
+}
+
+/// The last successful download of a list, kept as a `.txt` file with its validators in a
+/// `.meta` file next to it.
+#[cfg(feature = "remote-blocklist")]
+struct CachedList {
+    dir: std::path::PathBuf,
+    name: String,
+}
+
+#[cfg(feature = "remote-blocklist")]
+impl CachedList {
+    /// Named after the SHA-256 of `url`, which tells every url apart and is short enough for
+    /// any file system.
+    fn new(cache_dir: &Path, url: &str) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, url.as_bytes());
+        let name = digest
+            .as_ref()
+            .iter()
+            .map(|it| format!("{:02x}", it))
+            .collect();
+        CachedList {
+            dir: cache_dir.to_path_buf(),
+            name,
+        }
+    }
+
+    fn path(&self, extension: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{}.{}", self.name, extension))
+    }
+
+    async fn read(&self) -> Option<(Validators, String)> {
+        let payload = tokio::fs::read_to_string(self.path("txt")).await.ok()?;
+        let meta = tokio::fs::read_to_string(self.path("meta"))
+            .await
+            .unwrap_or_default();
+        Some((Validators::parse(&meta), payload))
+    }
+
+    async fn write(&self, validators: &Validators, payload: &str) -> std::io::Result<()> {
+        // The list goes first, so a crash leaves at worst a new list with stale validators,
+        // which only costs a full download next time.
+        tokio::fs::create_dir_all(&self.dir).await?;
+        write_atomically(&self.path("txt"), payload).await?;
+        tokio::fs::write(self.path("meta"), validators.to_meta()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(payload: &str) -> Vec<String> {
+        let mut domains: Vec<_> = parse(payload).into_iter().collect();
+        domains.sort();
+        domains
+    }
+
+    #[test]
+    fn parses_plain_domains() {
+        // Lines of dots would be the root, matching every name.
+        let payload = "# comment\n\nexample.com\n  ads.example  \ntracker.example # inline\n\
+                       .\n..\n0.0.0.0 .\n||.^\n";
+        assert_eq!(
+            parsed(payload),
+            ["ads.example.", "example.com.", "tracker.example."]
+        );
+    }
+
+    #[test]
+    fn parses_hosts_files() {
+        let payload = "127.0.0.1 localhost\n::1 localhost ip6-localhost\n0.0.0.0 0.0.0.0\n\
+                       0.0.0.0 ads.example\n127.0.0.1\ttracker.example pixel.example # ads\n";
+        assert_eq!(
+            parsed(payload),
+            ["ads.example.", "pixel.example.", "tracker.example."]
+        );
+    }
+
+    #[test]
+    fn parses_adblock_domain_rules() {
+        let payload = "[Adblock Plus 2.0]\n! Title: list\n||ads.example^\n||tracker.example\n\
+                       ||example.com/ads/*\n||cdn.example^$third-party\n@@||good.example^\n\
+                       example.org##.banner\n";
+        assert_eq!(parsed(payload), ["ads.example.", "tracker.example."]);
+    }
+
+    #[test]
+    fn lowercases_entries() {
+        let payload = "||Ads.Example.com^\n0.0.0.0 Tracker.EXAMPLE\n\
+                       CDN.example$dnstype=AAAA\n";
+        assert_eq!(
+            parsed(payload),
+            [
+                "ads.example.com.",
+                "cdn.example.$dnstype=AAAA",
+                "tracker.example."
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_record_type_options() {
+        let payload = "dualstack.example$dnstype=aaaa # broken v6\n\
+                       ||exfil.example^$dnstype=TXT|NULL\ntypo.example$dnstype=NOPE\n";
+        assert_eq!(
+            parsed(payload),
+            [
+                "dualstack.example.$dnstype=AAAA",
+                "exfil.example.$dnstype=TXT|NULL"
+            ]
+        );
+    }
+
+    #[cfg(feature = "remote-blocklist")]
+    #[test]
+    fn reads_and_writes_validators() {
+        let meta = "etag: \"abc\"\nlast-modified: Wed, 21 Oct 2015 07:28:00 GMT\nother: x\n";
+        let validators = Validators::parse(meta);
+        assert_eq!(
+            validators,
+            Validators {
+                etag: Some("\"abc\"".to_string()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            }
+        );
+        assert_eq!(Validators::parse(&validators.to_meta()), validators);
+        assert_eq!(Validators::parse(""), Validators::default());
+    }
+
+    #[cfg(feature = "remote-blocklist")]
+    #[tokio::test]
+    async fn keeps_a_copy_per_url() {
+        let dir = std::env::temp_dir().join(format!("advoid-kept-{}", std::process::id()));
+        let list = CachedList::new(&dir, "https://lists.example/a.txt");
+        let other = CachedList::new(&dir, "https://lists.example/a_txt");
+        assert_ne!(list.path("txt"), other.path("txt"));
+        let long = CachedList::new(&dir, &format!("https://lists.example/{}", "a".repeat(1000)));
+        assert_eq!(
+            long.path("meta").file_name().unwrap().len(),
+            64 + ".meta".len()
+        );
+
+        assert!(list.read().await.is_none());
+        let validators = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+        list.write(&validators, "ads.example\n").await.unwrap();
+        let (kept, payload) = list.read().await.unwrap();
+        assert_eq!(kept, validators);
+        assert_eq!(payload, "ads.example\n");
+        assert!(other.read().await.is_none());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[cfg(feature = "remote-blocklist")]
+    #[tokio::test]
+    async fn downloads_lists_again_only_once_they_changed() {
+        use axum::http::{header, HeaderMap, StatusCode};
+        use axum::response::IntoResponse;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Answers 304 to requests carrying the ETag of the list, and counts the other ones.
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counted = downloads.clone();
+        let router = axum::Router::new().route(
+            "/list.txt",
+            axum::routing::get(move |headers: HeaderMap| async move {
+                if headers
+                    .get(header::IF_NONE_MATCH)
+                    .is_some_and(|it| it == "\"v1\"")
+                {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                counted.fetch_add(1, Ordering::SeqCst);
+                ([(header::ETAG, "\"v1\"")], "ads.example\n").into_response()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, router).await });
+        let dir = std::env::temp_dir().join(format!("advoid-download-{}", std::process::id()));
 
-    let blocklist: FxHashSet<String> = payload
-        .lines()
-        .map(|it| it.trim().to_string())
-        .filter(|it| !it.is_empty())
-        .filter(|it| !it.starts_with('#'))
-        .map(|it| format!("{}.", it))
-        .collect();
+        for _ in 0..2 {
+            let payload = download(url.clone(), Some(&dir)).await.unwrap();
+            assert_eq!(payload, "ads.example\n");
+        }
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
 
-    Ok(blocklist)
+        // The kept copy is used once the server is gone.
+        server.abort();
+        let _ = server.await;
+        assert_eq!(download(url, Some(&dir)).await.unwrap(), "ads.example\n");
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
 }
@@ -0,0 +1,492 @@
+use hickory_client::op::{DnsResponse, Message, ResponseCode};
+use hickory_client::rr::{DNSClass, Name, RData, Record, RecordType};
+use lru::LruCache;
+use std::io::{self, Read, Write};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CacheKey {
+    name: Name,
+    query_class: DNSClass,
+    query_type: RecordType,
+}
+
+impl CacheKey {
+    pub fn new(name: &Name, query_class: DNSClass, query_type: RecordType) -> Self {
+        CacheKey {
+            name: name.to_lowercase(),
+            query_class,
+            query_type,
+        }
+    }
+
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+
+    pub fn query_class(&self) -> DNSClass {
+        self.query_class
+    }
+
+    pub fn query_type(&self) -> RecordType {
+        self.query_type
+    }
+}
+
+/// A cache entry as shown by the admin API.
+#[derive(Debug, serde::Serialize)]
+pub struct CacheEntry {
+    pub name: String,
+    #[serde(rename = "class")]
+    pub query_class: String,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    #[serde(rename = "rcode")]
+    pub response_code: String,
+    /// Seconds until the entry expires.
+    pub ttl: u64,
+    pub hits: u64,
+}
+
+struct Entry {
+    response: DnsResponse,
+    stored: Instant,
+    expires: Instant,
+    hits: u64,
+}
+
+/// Upstream answers kept until their TTL runs out.
+pub struct ResponseCache {
+    entries: Mutex<LruCache<CacheKey, Entry>>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        ResponseCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Returns the cached answer with its TTLs lowered by the time it has been cached.
+    pub fn get(&self, key: &CacheKey) -> Option<DnsResponse> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let now = Instant::now();
+        let entry = match entries.get_mut(key) {
+            Some(entry) if entry.expires > now => {
+                entry.hits += 1;
+                entry
+            }
+            Some(_) => {
+                entries.pop(key);
+                metrics::counter!("dns_cache_misses").increment(1);
+                return None;
+            }
+            None => {
+                metrics::counter!("dns_cache_misses").increment(1);
+                return None;
+            }
+        };
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut message = entry.response.clone().into_message();
+        drop(entries);
+        age(&mut message, elapsed);
+
+        metrics::counter!("dns_cache_hits").increment(1);
+        DnsResponse::from_message(message).ok()
+    }
+
+    pub fn insert(&self, key: CacheKey, response: &DnsResponse) {
+        let Some(ttl) = cache_ttl(response) else {
+            return;
+        };
+        let response = match response.answers().is_empty() {
+            true => match clamp_soa_ttl(response, ttl.as_secs() as u32) {
+                Some(response) => response,
+                None => return,
+            },
+            false => response.clone(),
+        };
+
+        let now = Instant::now();
+        self.entries.lock().unwrap().put(
+            key,
+            Entry {
+                response,
+                stored: now,
+                expires: now + ttl,
+                hits: 0,
+            },
+        );
+    }
+
+    /// A snapshot of the live entries, most recently used first.
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.expires > now)
+            .map(|(key, entry)| CacheEntry {
+                name: key.name.to_string(),
+                query_class: key.query_class.to_string(),
+                query_type: key.query_type.to_string(),
+                response_code: entry.response.response_code().to_string(),
+                ttl: (entry.expires - now).as_secs(),
+                hits: entry.hits,
+            })
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Removes every entry for `name`, whatever its type, and returns how many were removed.
+    pub fn remove_name(&self, name: &Name) -> usize {
+        let name = name.to_lowercase();
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<_> = entries
+            .iter()
+            .filter(|(key, _)| key.name == name)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            entries.pop(key);
+        }
+        keys.len()
+    }
+
+    /// Ages every entry by `elapsed`, time the monotonic clock missed (e.g. while the system
+    /// was suspended), and returns how many entries expired because of it.
+    pub fn age_by(&self, elapsed: Duration) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut expired = Vec::new();
+        for (key, entry) in entries.iter_mut() {
+            match (
+                entry.stored.checked_sub(elapsed),
+                entry.expires.checked_sub(elapsed),
+            ) {
+                (Some(stored), Some(expires)) if expires > now => {
+                    entry.stored = stored;
+                    entry.expires = expires;
+                }
+                _ => expired.push(key.clone()),
+            }
+        }
+        for key in &expired {
+            entries.pop(key);
+        }
+        expired.len()
+    }
+
+    /// Writes the live entries, least recently used first, with their TTLs as of now.
+    pub fn save(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let live: Vec<_> = entries
+            .iter()
+            .rev()
+            .filter(|(_, entry)| entry.expires > now)
+            .collect();
+
+        w.write_all(&(live.len() as u32).to_be_bytes())?;
+        for (_, entry) in live {
+            let mut message = entry.response.clone().into_message();
+            age(
+                &mut message,
+                now.duration_since(entry.stored).as_secs() as u32,
+            );
+            let bytes = message.to_vec()?;
+
+            w.write_all(&((entry.expires - now).as_secs() as u32).to_be_bytes())?;
+            w.write_all(&entry.hits.to_be_bytes())?;
+            w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+            w.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Reads entries written by `save` `elapsed` ago, dropping the ones that expired since.
+    pub fn load(&self, r: &mut impl Read, elapsed: Duration) -> anyhow::Result<usize> {
+        let now = Instant::now();
+        let elapsed = elapsed.as_secs() as u32;
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut loaded = 0;
+        for _ in 0..read_u32(r)? {
+            let remaining = read_u32(r)?;
+            let mut hits = [0; 8];
+            r.read_exact(&mut hits)?;
+            let len = read_u32(r)?;
+            let bytes = read_bytes(r, len)?;
+
+            let Some(remaining) = remaining.checked_sub(elapsed).filter(|it| *it > 0) else {
+                continue;
+            };
+            let mut message = Message::from_vec(&bytes)?;
+            age(&mut message, elapsed);
+            let Some(query) = message.queries().first() else {
+                continue;
+            };
+
+            let key = CacheKey::new(query.name(), query.query_class(), query.query_type());
+            entries.put(
+                key,
+                Entry {
+                    response: DnsResponse::from_message(message)?,
+                    stored: now,
+                    expires: now + Duration::from_secs(remaining.into()),
+                    hits: u64::from_be_bytes(hits),
+                },
+            );
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Keys hit at least `min_hits` times whose entries expire within `window` or the last
+    /// tenth of their TTL, whichever is longer.
+    pub fn expiring(&self, min_hits: u64, window: Duration) -> Vec<CacheKey> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.hits >= min_hits && entry.expires > now)
+            .filter(|(_, entry)| {
+                let window = window.max((entry.expires - entry.stored) / 10);
+                entry.expires - now <= window
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+fn age(message: &mut Message, elapsed: u32) {
+    let age = |records: &mut Vec<Record>| {
+        for record in records.iter_mut() {
+            record.set_ttl(record.ttl().saturating_sub(elapsed));
+        }
+    };
+    age(message.answers_mut());
+    age(message.name_servers_mut());
+    age(message.additionals_mut());
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Reads `len` bytes, without allocating more than there are when a corrupt length is read.
+pub(crate) fn read_bytes(r: &mut impl Read, len: u32) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(len.into()).read_to_end(&mut buf)?;
+    if buf.len() < len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+/// Positive answers are cached for the lowest TTL among their records, negative ones
+/// (NXDOMAIN and NODATA) for the SOA minimum TTL as in RFC 2308.
+fn cache_ttl(response: &DnsResponse) -> Option<Duration> {
+    if response.truncated() {
+        return None;
+    }
+
+    let ttl = match response.response_code() {
+        ResponseCode::NoError if !response.answers().is_empty() => response
+            .answers()
+            .iter()
+            .chain(response.name_servers())
+            .chain(response.additionals())
+            .map(|it| it.ttl())
+            .min(),
+        ResponseCode::NoError | ResponseCode::NXDomain => negative_ttl(response),
+        _ => None,
+    };
+
+    ttl.filter(|ttl| *ttl > 0)
+        .map(|ttl| Duration::from_secs(ttl.into()))
+}
+
+/// Negative answers without an SOA record in the authority section are not cached.
+fn negative_ttl(response: &DnsResponse) -> Option<u32> {
+    response
+        .name_servers()
+        .iter()
+        .find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+            _ => None,
+        })
+}
+
+/// Lowers the TTL of the SOA record in a negative answer to `ttl`, which clients would
+/// otherwise cache the answer for (RFC 2308 section 5).
+fn clamp_soa_ttl(response: &DnsResponse, ttl: u32) -> Option<DnsResponse> {
+    let mut message = response.clone().into_message();
+    for record in message.name_servers_mut() {
+        if record.record_type() == RecordType::SOA {
+            record.set_ttl(record.ttl().min(ttl));
+        }
+    }
+    DnsResponse::from_message(message).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_client::op::Query;
+    use hickory_client::rr::rdata::{A, SOA};
+    use std::str::FromStr;
+
+    fn name(name: &str) -> Name {
+        Name::from_str(name).unwrap()
+    }
+
+    fn response(code: ResponseCode, answers: &[u32], soa: Option<(u32, u32)>) -> DnsResponse {
+        let mut message = Message::new();
+        message.add_query(Query::query(name("example.com."), RecordType::A));
+        message.set_response_code(code);
+        for ttl in answers {
+            message.add_answer(Record::from_rdata(
+                name("example.com."),
+                *ttl,
+                RData::A(A::new(192, 0, 2, 1)),
+            ));
+        }
+        if let Some((ttl, minimum)) = soa {
+            message.add_name_server(Record::from_rdata(
+                name("com."),
+                ttl,
+                RData::SOA(SOA::new(
+                    name("ns.com."),
+                    name("admin.com."),
+                    1,
+                    3600,
+                    600,
+                    86400,
+                    minimum,
+                )),
+            ));
+        }
+        DnsResponse::from_message(message).unwrap()
+    }
+
+    #[test]
+    fn caches_negative_answers_for_the_soa_minimum() {
+        let ttl = |response: &DnsResponse| cache_ttl(response).map(|it| it.as_secs());
+        assert_eq!(
+            ttl(&response(ResponseCode::NoError, &[300, 60], None)),
+            Some(60)
+        );
+
+        // The lower of the SOA record's TTL and its minimum (RFC 2308 section 5).
+        let nxdomain = response(ResponseCode::NXDomain, &[], Some((3600, 300)));
+        assert_eq!(ttl(&nxdomain), Some(300));
+        let nodata = response(ResponseCode::NoError, &[], Some((60, 300)));
+        assert_eq!(ttl(&nodata), Some(60));
+        assert_eq!(ttl(&response(ResponseCode::NXDomain, &[], None)), None);
+        assert_eq!(
+            ttl(&response(ResponseCode::NXDomain, &[], Some((0, 300)))),
+            None
+        );
+        let servfail = response(ResponseCode::ServFail, &[], Some((3600, 300)));
+        assert_eq!(ttl(&servfail), None);
+
+        let cache = ResponseCache::new(NonZeroUsize::new(10).unwrap());
+        let key = CacheKey::new(&name("Example.COM."), DNSClass::IN, RecordType::A);
+        cache.insert(key.clone(), &nxdomain);
+        let cached = cache.get(&key).unwrap();
+        assert_eq!(cached.response_code(), ResponseCode::NXDomain);
+        // Served with the negative TTL rather than the SOA record's own.
+        assert_eq!(cached.name_servers()[0].ttl(), 300);
+
+        let other = CacheKey::new(&name("example.com."), DNSClass::IN, RecordType::AAAA);
+        cache.insert(other.clone(), &response(ResponseCode::NXDomain, &[], None));
+        assert!(cache.get(&other).is_none());
+    }
+
+    /// Sets when the entry for `key` was stored and expires, relative to now, and its hits.
+    fn set_entry(cache: &ResponseCache, key: &CacheKey, age: u64, ttl: u64, hits: u64) {
+        let now = Instant::now();
+        let mut entries = cache.entries.lock().unwrap();
+        let entry = entries.get_mut(key).unwrap();
+        entry.stored = now - Duration::from_secs(age);
+        entry.expires = entry.stored + Duration::from_secs(ttl);
+        entry.hits = hits;
+    }
+
+    #[test]
+    fn finds_popular_entries_about_to_expire() {
+        let cache = ResponseCache::new(NonZeroUsize::new(10).unwrap());
+        let key = |it| CacheKey::new(&name(it), DNSClass::IN, RecordType::A);
+        let keys = [
+            "soon.example.",
+            "later.example.",
+            "rare.example.",
+            "long.example.",
+        ]
+        .map(key);
+        for key in &keys {
+            cache.insert(key.clone(), &response(ResponseCode::NoError, &[3600], None));
+        }
+        // Expiring in 5 seconds, 60 seconds, 5 seconds with a single hit, and 18 seconds
+        // into the last tenth of a 200 second TTL.
+        set_entry(&cache, &keys[0], 95, 100, 3);
+        set_entry(&cache, &keys[1], 40, 100, 3);
+        set_entry(&cache, &keys[2], 95, 100, 1);
+        set_entry(&cache, &keys[3], 182, 200, 3);
+
+        let mut expiring = cache.expiring(2, Duration::from_secs(10));
+        expiring.sort_by_key(|it| it.name().to_string());
+        assert_eq!(expiring, [keys[3].clone(), keys[0].clone()]);
+        assert_eq!(cache.expiring(2, Duration::from_secs(60)).len(), 3);
+        assert_eq!(cache.expiring(5, Duration::from_secs(60)).len(), 0);
+    }
+
+    #[test]
+    fn loads_saved_entries_aged_by_the_time_since() {
+        let cache = ResponseCache::new(NonZeroUsize::new(10).unwrap());
+        let key = CacheKey::new(&name("example.com."), DNSClass::IN, RecordType::A);
+        cache.insert(key.clone(), &response(ResponseCode::NoError, &[300], None));
+        cache.get(&key);
+        let mut saved = Vec::new();
+        cache.save(&mut saved).unwrap();
+
+        let loaded = ResponseCache::new(NonZeroUsize::new(10).unwrap());
+        let read = loaded.load(&mut saved.as_slice(), Duration::from_secs(100));
+        assert_eq!(read.unwrap(), 1);
+        assert_eq!(loaded.get(&key).unwrap().answers()[0].ttl(), 200);
+        assert_eq!(loaded.entries()[0].hits, 2);
+
+        let expired = ResponseCache::new(NonZeroUsize::new(10).unwrap());
+        let read = expired.load(&mut saved.as_slice(), Duration::from_secs(300));
+        assert_eq!(read.unwrap(), 0);
+        let mut truncated = &saved[..saved.len() - 1];
+        assert!(expired.load(&mut truncated, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn ages_entries_by_time_missed_while_asleep() {
+        let cache = ResponseCache::new(NonZeroUsize::new(10).unwrap());
+        let key = |it| CacheKey::new(&name(it), DNSClass::IN, RecordType::A);
+        let (short, long) = (key("short.example."), key("long.example."));
+        cache.insert(short.clone(), &response(ResponseCode::NoError, &[60], None));
+        cache.insert(long.clone(), &response(ResponseCode::NoError, &[300], None));
+
+        assert_eq!(cache.age_by(Duration::from_secs(100)), 1);
+        assert!(cache.get(&short).is_none());
+        assert_eq!(cache.get(&long).unwrap().answers()[0].ttl(), 200);
+        assert_eq!(cache.age_by(Duration::from_secs(200)), 1);
+        assert!(cache.entries().is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+use hickory_client::op::{DnsResponse, Message};
+use hickory_client::rr::Name;
+use hickory_proto::error::ProtoError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Exchanges waiting to be written before new ones are dropped.
+const QUEUE: usize = 1024;
+
+/// Length of the pcap file header.
+const HEADER_LEN: u64 = 24;
+
+/// Raw IPv4/IPv6 packets.
+const LINKTYPE_RAW: u32 = 101;
+
+/// Port the queries appear to be sent from.
+const LOCAL_PORT: u16 = 49152;
+
+/// A query sent upstream and what came back.
+struct Exchange {
+    upstream: SocketAddr,
+    sent: SystemTime,
+    request: Vec<u8>,
+    received: SystemTime,
+    response: Option<Vec<u8>>,
+}
+
+/// Writes the wire format of sampled upstream queries and their answers to a pcap file, to debug
+/// interop issues with an upstream in Wireshark or tcpdump.
+///
+/// Messages are written as UDP packets between `0.0.0.0` (`::`) and port 53 of the upstream
+/// whatever the transport, since encrypted transports don't carry anything worth decoding
+/// besides the DNS messages. Queries that failed are written without an answer. Once the file
+/// reaches its maximum size it is moved to `<FILE>.1`, replacing the previous one.
+pub struct WireCapture {
+    sample_rate: f64,
+    zones: Vec<Name>,
+    sender: mpsc::Sender<Exchange>,
+}
+
+impl WireCapture {
+    pub async fn create(path: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+        let mut file = create_file(&path).await?;
+        let (sender, mut receiver) = mpsc::channel::<Exchange>(QUEUE);
+        tokio::spawn(async move {
+            let mut size = HEADER_LEN;
+            while let Some(exchange) = receiver.recv().await {
+                let records = exchange.records();
+                if size + records.len() as u64 > max_size && size > HEADER_LEN {
+                    let mut rotated = path.clone().into_os_string();
+                    rotated.push(".1");
+                    let reopened = match tokio::fs::rename(&path, rotated).await {
+                        Ok(()) => create_file(&path).await,
+                        Err(e) => Err(e.into()),
+                    };
+                    match reopened {
+                        Ok(reopened) => (file, size) = (reopened, HEADER_LEN),
+                        Err(e) => {
+                            warn!("could not rotate capture {}: {}", path.display(), e);
+                            return;
+                        }
+                    }
+                }
+                if let Err(e) = file.write_all(&records).await {
+                    warn!("could not write capture {}: {}", path.display(), e);
+                    return;
+                }
+                size += records.len() as u64;
+            }
+        });
+
+        Ok(WireCapture {
+            sample_rate: 0.0,
+            zones: Vec::new(),
+            sender,
+        })
+    }
+
+    /// Captures a `sample_rate` fraction (0 to 1) of all the queries.
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Captures every query for names under `zones`.
+    pub fn with_zones(mut self, zones: Vec<Name>) -> Self {
+        self.zones = zones;
+        self
+    }
+
+    pub(crate) fn samples(&self, name: &Name) -> bool {
+        self.zones.iter().any(|it| it.zone_of(name)) || rand::random::<f64>() < self.sample_rate
+    }
+
+    pub(crate) fn record(
+        &self,
+        upstream: SocketAddr,
+        mut request: Message,
+        sent: SystemTime,
+        response: &Result<DnsResponse, ProtoError>,
+    ) {
+        // The client picks the id of the message actually sent.
+        let response = response.as_ref().ok().map(|it| {
+            request.set_id(it.id());
+            it.as_buffer().to_vec()
+        });
+        let Ok(request) = request.to_vec() else {
+            return;
+        };
+        let exchange = Exchange {
+            upstream,
+            sent,
+            request,
+            received: SystemTime::now(),
+            response,
+        };
+        if self.sender.try_send(exchange).is_err() {
+            metrics::counter!("dns_capture_dropped").increment(1);
+        }
+    }
+}
+
+async fn create_file(path: &Path) -> anyhow::Result<tokio::fs::File> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend(0xa1b2c3d4u32.to_le_bytes());
+    header.extend(2u16.to_le_bytes());
+    header.extend(4u16.to_le_bytes());
+    header.extend([0; 8]);
+    header.extend(u32::from(u16::MAX).to_le_bytes());
+    header.extend(LINKTYPE_RAW.to_le_bytes());
+    file.write_all(&header).await?;
+    Ok(file)
+}
+
+impl Exchange {
+    /// The pcap records of the exchange.
+    fn records(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        record(
+            &mut records,
+            self.sent,
+            &packet(self.upstream, &self.request, true),
+        );
+        if let Some(response) = &self.response {
+            record(
+                &mut records,
+                self.received,
+                &packet(self.upstream, response, false),
+            );
+        }
+        records
+    }
+}
+
+fn record(records: &mut Vec<u8>, time: SystemTime, packet: &[u8]) {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    records.extend((time.as_secs() as u32).to_le_bytes());
+    records.extend(time.subsec_micros().to_le_bytes());
+    records.extend((packet.len() as u32).to_le_bytes());
+    records.extend((packet.len() as u32).to_le_bytes());
+    records.extend(packet);
+}
+
+/// `message` in a UDP packet sent to `upstream`, or received from it.
+fn packet(upstream: SocketAddr, message: &[u8], outgoing: bool) -> Vec<u8> {
+    // Messages over TCP may not fit in a UDP packet.
+    let message = &message[..message.len().min(u16::MAX as usize - 48)];
+    let udp_len = (8 + message.len()) as u16;
+    let (local_port, upstream_port) = (LOCAL_PORT, 53);
+    let (source_port, destination_port) = match outgoing {
+        true => (local_port, upstream_port),
+        false => (upstream_port, local_port),
+    };
+
+    let mut packet = Vec::with_capacity(48 + message.len());
+    match upstream.ip() {
+        IpAddr::V4(ip) => {
+            let local = Ipv4Addr::UNSPECIFIED;
+            let (source, destination) = if outgoing { (local, ip) } else { (ip, local) };
+            packet.extend([0x45, 0]);
+            packet.extend((20 + udp_len).to_be_bytes());
+            packet.extend([0, 0, 0x40, 0, 64, 17, 0, 0]);
+            packet.extend(source.octets());
+            packet.extend(destination.octets());
+            let checksum = checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            let local = Ipv6Addr::UNSPECIFIED;
+            let (source, destination) = if outgoing { (local, ip) } else { (ip, local) };
+            packet.extend([0x60, 0, 0, 0]);
+            packet.extend(udp_len.to_be_bytes());
+            packet.extend([17, 64]);
+            packet.extend(source.octets());
+            packet.extend(destination.octets());
+        }
+    }
+    // A zero UDP checksum isn't verified.
+    packet.extend(source_port.to_be_bytes());
+    packet.extend(destination_port.to_be_bytes());
+    packet.extend(udp_len.to_be_bytes());
+    packet.extend([0, 0]);
+    packet.extend(message);
+    packet
+}
+
+/// The internet checksum (RFC 1071) of `bytes`.
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum = bytes
+        .chunks(2)
+        .map(|it| u32::from(u16::from_be_bytes([it[0], *it.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_messages_in_udp_packets() {
+        let upstream: SocketAddr = "192.0.2.53:853".parse().unwrap();
+        let query = packet(upstream, b"dns", true);
+        assert_eq!(query.len(), 20 + 8 + 3);
+        assert_eq!(&query[2..4], &31u16.to_be_bytes());
+        assert_eq!(checksum(&query[..20]), 0);
+        assert_eq!(&query[12..16], &[0, 0, 0, 0]);
+        assert_eq!(&query[16..20], &[192, 0, 2, 53]);
+        assert_eq!(&query[20..24], &[0xc0, 0x00, 0x00, 0x35]);
+
+        let answer = packet("[2001:db8::53]:53".parse().unwrap(), b"dns", false);
+        assert_eq!(answer.len(), 40 + 8 + 3);
+        assert_eq!(&answer[4..6], &11u16.to_be_bytes());
+        assert_eq!(&answer[40..44], &[0x00, 0x35, 0xc0, 0x00]);
+    }
+}
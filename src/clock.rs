@@ -0,0 +1,109 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// How often the clock is checked.
+const INTERVAL: Duration = Duration::from_secs(600);
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Offsets beyond this are logged as warnings.
+const MAX_SKEW: f64 = 2.0;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_TO_UNIX: f64 = 2_208_988_800.0;
+
+/// Compares the system clock with the NTP server at `server` every few minutes, exporting the
+/// offset as the `system_clock_skew_seconds` gauge and warning when it is off by more than a
+/// couple of seconds, since the timestamps of the history, metrics and logs would be too.
+pub fn spawn_clock_check(server: String) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(INTERVAL);
+        loop {
+            ticker.tick().await;
+            match tokio::time::timeout(TIMEOUT, probe(&server)).await {
+                Ok(Ok(offset)) => {
+                    metrics::gauge!("system_clock_skew_seconds").set(offset);
+                    if offset.abs() > MAX_SKEW {
+                        tracing::warn!(
+                            "system clock is {:.3}s {} {}",
+                            offset.abs(),
+                            if offset > 0.0 { "behind" } else { "ahead of" },
+                            server
+                        );
+                    }
+                }
+                Ok(Err(e)) => tracing::warn!("could not check the clock against {}: {}", server, e),
+                Err(_) => tracing::warn!("could not check the clock against {}: timed out", server),
+            }
+        }
+    });
+}
+
+/// How far the server's clock is ahead of ours, in seconds.
+async fn probe(server: &str) -> anyhow::Result<f64> {
+    let addr = tokio::net::lookup_host(server)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("could not resolve {}", server))?;
+    let socket = UdpSocket::bind(if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(addr).await?;
+
+    // Version 3, client mode.
+    let mut request = [0; 48];
+    request[0] = 0x1b;
+    let sent = unix_time().as_secs_f64();
+    socket.send(&request).await?;
+
+    let mut response = [0; 48];
+    let len = socket.recv(&mut response).await?;
+    let received = unix_time().as_secs_f64();
+    anyhow::ensure!(
+        len == response.len() && response[0] & 0x07 == 4 && response[1] != 0,
+        "not an NTP server response"
+    );
+
+    let server_received = ntp_time(&response[32..40]);
+    let server_sent = ntp_time(&response[40..48]);
+    Ok(offset(sent, server_received, server_sent, received))
+}
+
+/// The clock offset from the four timestamps of an exchange (RFC 5905), which cancels out the
+/// network delay as long as it is about the same both ways.
+fn offset(sent: f64, server_received: f64, server_sent: f64, received: f64) -> f64 {
+    ((server_received - sent) + (server_sent - received)) / 2.0
+}
+
+/// An NTP timestamp as seconds since the Unix epoch.
+fn ntp_time(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+    let fraction = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+    seconds as f64 + fraction as f64 / 2f64.powi(32) - NTP_TO_UNIX
+}
+
+/// The time since the Unix epoch, or zero for a clock set before it.
+pub(crate) fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_offset_without_the_network_delay() {
+        // 2026-01-01T00:00:00Z and a half.
+        let timestamp = [0xed, 0x00, 0x37, 0x80, 0x80, 0x00, 0x00, 0x00];
+        assert_eq!(ntp_time(&timestamp), 1_767_225_600.5);
+
+        // 125ms each way, with the server 3s ahead and behind.
+        assert_eq!(offset(10.0, 13.125, 13.25, 10.375), 3.0);
+        assert_eq!(offset(10.0, 7.125, 7.25, 10.375), -3.0);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::clock::unix_time;
+use hickory_client::op::{Edns, ResponseCode};
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+
+/// Length of the cookie the client makes up.
+const CLIENT_COOKIE_LEN: usize = 8;
+
+/// Longest server cookie clients may send back (RFC 7873 section 4).
+const MAX_SERVER_COOKIE_LEN: usize = 32;
+
+/// Version of the server cookie layout of RFC 9018.
+const VERSION: u8 = 1;
+
+/// Seconds a server cookie is accepted for, and how far ahead of this clock it may be.
+const LIFETIME: u32 = 60 * 60;
+const CLOCK_SKEW: u32 = 5 * 60;
+
+/// What to do with DNS Cookies (RFC 7873).
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum CookieMode {
+    /// Ignore them
+    #[default]
+    Off,
+    /// Answer client cookies with a server cookie
+    On,
+    /// Also answer UDP queries bearing a client cookie without a valid server cookie with
+    /// BADCOOKIE, so clients retry with the server cookie they got
+    Require,
+}
+
+/// Makes and checks server cookies in the layout of RFC 9018, with a secret made up at startup,
+/// so the server cookies of a client live as long as the process.
+pub struct Cookies {
+    mode: CookieMode,
+    secret: RandomState,
+}
+
+impl Cookies {
+    pub fn new(mode: CookieMode) -> Self {
+        Cookies {
+            mode,
+            secret: RandomState::new(),
+        }
+    }
+
+    /// The cookie to answer the query of `client` with and, if the query can't be answered, the
+    /// error to answer instead. Queries without a cookie are left alone.
+    pub(crate) fn check(
+        &self,
+        request: &Edns,
+        client: IpAddr,
+        udp: bool,
+    ) -> (Option<EdnsOption>, Option<ResponseCode>) {
+        let Some(EdnsOption::Unknown(_, cookie)) = request.option(EdnsCode::Cookie) else {
+            return (None, None);
+        };
+        if cookie.len() != CLIENT_COOKIE_LEN
+            && !(CLIENT_COOKIE_LEN + 8..=CLIENT_COOKIE_LEN + MAX_SERVER_COOKIE_LEN)
+                .contains(&cookie.len())
+        {
+            metrics::counter!("dns_cookies", "result" => "malformed").increment(1);
+            return (None, Some(ResponseCode::FormErr));
+        }
+
+        let (client_cookie, server_cookie) = cookie.split_at(CLIENT_COOKIE_LEN);
+        let now = now();
+        let result = match server_cookie {
+            [] => "new",
+            _ if self.is_valid(client_cookie, server_cookie, client, now) => "valid",
+            _ => "invalid",
+        };
+        metrics::counter!("dns_cookies", "result" => result).increment(1);
+
+        let mut answer = client_cookie.to_vec();
+        answer.extend(self.server_cookie(client_cookie, client, now));
+        let answer = EdnsOption::Unknown(EdnsCode::Cookie.into(), answer);
+        let refused = self.mode == CookieMode::Require && udp && result != "valid";
+        (Some(answer), refused.then_some(ResponseCode::BADCOOKIE))
+    }
+
+    fn is_valid(
+        &self,
+        client_cookie: &[u8],
+        server_cookie: &[u8],
+        client: IpAddr,
+        now: u32,
+    ) -> bool {
+        let Some(time) = server_cookie.get(4..8) else {
+            return false;
+        };
+        let time = u32::from_be_bytes(time.try_into().unwrap());
+        // Serial number arithmetic, as the timestamp wraps around (RFC 9018 section 4.3).
+        let age = now.wrapping_sub(time);
+        (age <= LIFETIME || age.wrapping_neg() <= CLOCK_SKEW)
+            && server_cookie == self.server_cookie(client_cookie, client, time)
+    }
+
+    /// Version, three reserved bytes, the timestamp and a hash of the rest, the client cookie
+    /// and the client address.
+    fn server_cookie(&self, client_cookie: &[u8], client: IpAddr, time: u32) -> Vec<u8> {
+        let mut cookie = vec![VERSION, 0, 0, 0];
+        cookie.extend(time.to_be_bytes());
+        let hash = self
+            .secret
+            .hash_one((client_cookie, &cookie, client.to_canonical()));
+        cookie.extend(hash.to_be_bytes());
+        cookie
+    }
+}
+
+fn now() -> u32 {
+    unix_time().as_secs() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edns(cookie: &[u8]) -> Edns {
+        let mut edns = Edns::new();
+        edns.options_mut().insert(EdnsOption::Unknown(
+            EdnsCode::Cookie.into(),
+            cookie.to_vec(),
+        ));
+        edns
+    }
+
+    #[test]
+    fn answers_client_cookies_with_server_cookies() {
+        let cookies = Cookies::new(CookieMode::Require);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let client_cookie = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        let (Some(EdnsOption::Unknown(10, answer)), Some(ResponseCode::BADCOOKIE)) =
+            cookies.check(&edns(&client_cookie), client, true)
+        else {
+            panic!("expected a server cookie along with BADCOOKIE");
+        };
+        assert_eq!(answer.len(), 24);
+        assert_eq!(answer[..8], client_cookie);
+
+        let (_, error) = cookies.check(&edns(&answer), client, true);
+        assert_eq!(error, None);
+        let (_, error) = cookies.check(&edns(&answer), "192.0.2.2".parse().unwrap(), true);
+        assert_eq!(error, Some(ResponseCode::BADCOOKIE));
+        let (_, error) = cookies.check(&edns(&client_cookie), client, false);
+        assert_eq!(error, None);
+
+        let (_, error) = cookies.check(&edns(&answer[..12]), client, true);
+        assert_eq!(error, Some(ResponseCode::FormErr));
+        assert_eq!(cookies.check(&Edns::new(), client, true), (None, None));
+
+        let stale = cookies.server_cookie(&client_cookie, client, now() - LIFETIME - 1);
+        assert!(!cookies.is_valid(&client_cookie, &stale, client, now()));
+    }
+}
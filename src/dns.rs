@@ -1,64 +1,849 @@
-use hickory_client::client::{AsyncClient, ClientHandle};
+use crate::acme::AcmeChallenges;
+use crate::cache::{CacheKey, ResponseCache};
+use crate::cookie::{CookieMode, Cookies};
+use crate::history::{History, Verdict};
+use crate::local::{is_private, LocalRecords};
+use crate::metrics::{record_stage_duration, stage_span};
+use crate::overrides::Overrides;
+use crate::plugin::{Decision, PluginQuery, QueryPlugin};
+use crate::schedule::ScheduledLists;
+#[cfg(feature = "policy-script")]
+use crate::script::PolicyScript;
+use crate::snapshot::Snapshot;
+use crate::suffix::{Matching, SuffixSet};
+use crate::upstream::{UpstreamClient, UpstreamDiff};
+use crate::zone::Zones;
+use arc_swap::ArcSwap;
+use futures::StreamExt;
 use hickory_client::op::{DnsResponse, Edns, Header, MessageType, OpCode, ResponseCode};
-use hickory_client::rr::{DNSClass, IntoName, Name, Record, RecordType};
+use hickory_client::rr::{DNSClass, IntoName, Name, RData, Record, RecordType};
+use hickory_proto::op::message::emit_message_parts;
+use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+use hickory_proto::rr::rdata::{A, AAAA, CNAME, HTTPS};
+use hickory_proto::serialize::binary::BinEncoder;
 use hickory_server::authority::{MessageResponse, MessageResponseBuilder};
-use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
-use rustc_hash::FxHashSet;
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use ipnet::IpNet;
+use lru::LruCache;
+use rustc_hash::{FxHashMap, FxHashSet};
 use std::io;
+use std::iter;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{debug, error, instrument, warn};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, error, instrument, warn, Instrument};
 
-struct CheckedDomain {
-    block: FxHashSet<String>,
-    allow: FxHashSet<String>,
+/// TTL of answers synthesized by advoid itself.
+const LOCAL_TTL: u32 = 60;
+
+/// Block decisions remembered by default.
+const CHECKED_CAPACITY: NonZeroUsize = NonZeroUsize::new(100_000).unwrap();
+
+/// How often the cache is scanned for popular entries about to expire.
+const PREFETCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Warm-up queries sent to the upstreams at once.
+const WARM_UP_CONCURRENCY: usize = 8;
+
+/// How long the warm-up may hold back the listener.
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest UDP answer to clients without EDNS, and the least any client gets (RFC 1035).
+const MIN_UDP_SIZE: u16 = 512;
+
+/// Largest UDP answer by default, small enough not to be fragmented (DNS Flag Day 2020).
+const DEFAULT_MAX_UDP_SIZE: u16 = 1232;
+
+enum Answer {
+    Upstream(DnsResponse),
+    Blocked,
+    Refused,
+    Local(Vec<Record>),
+    /// Records advoid is the authority for, with the SOA record of negative answers.
+    Authoritative {
+        response_code: ResponseCode,
+        answers: Vec<Record>,
+        authority: Vec<Record>,
+    },
+}
+
+impl Answer {
+    fn answer_count(&self) -> usize {
+        match self {
+            Answer::Upstream(response) => response.answers().len(),
+            Answer::Local(records) => records.len(),
+            Answer::Authoritative { answers, .. } => answers.len(),
+            Answer::Blocked | Answer::Refused => 0,
+        }
+    }
+}
+
+/// What to do with the EDNS Client Subnet option when forwarding a query upstream.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum EcsPolicy {
+    /// Never send a client subnet upstream.
+    #[default]
+    Strip,
+    /// Pass the client subnet sent by the client through unchanged.
+    Forward,
+    /// Always send the given subnet instead of whatever the client sent.
+    Override(ClientSubnet),
+}
+
+/// How blocked HTTPS and SVCB (TYPE65/TYPE64) queries are answered.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum SvcbBlockResponse {
+    /// NXDOMAIN, the same as every other blocked query
+    #[default]
+    Nxdomain,
+    /// An empty NOERROR answer, so clients fall back to the (blocked) A/AAAA lookup
+    Nodata,
+}
+
+/// What happens to upstream answers leading public names to private addresses, which a page
+/// on the Internet could use to reach devices on the LAN through the browser (DNS rebinding).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RebindProtection {
+    /// Answer them as they are
+    #[default]
+    Off,
+    /// Remove the private addresses from the answer
+    Strip,
+    /// Refuse the query
+    Refuse,
+}
+
+/// Block decisions already made, most recently used first, so each name is matched only once
+/// per policy. Policy 0 is the default one, the others belong to client groups.
+pub(crate) struct CheckedDomain {
+    blocked: LruCache<(usize, String), bool>,
 }
 
 impl CheckedDomain {
-    pub fn new() -> Self {
+    pub fn new(capacity: NonZeroUsize) -> Self {
         CheckedDomain {
-            block: FxHashSet::default(),
-            allow: FxHashSet::default(),
+            blocked: LruCache::new(capacity),
         }
     }
+
+    fn get(&mut self, policy: usize, domain: &str) -> Option<bool> {
+        self.blocked.get(&(policy, domain.to_string())).copied()
+    }
+
+    pub(crate) fn insert(&mut self, policy: usize, domain: String, blocked: bool) {
+        self.blocked.put((policy, domain), blocked);
+        metrics::gauge!("dns_checked_domains").set(self.blocked.len() as f64);
+    }
+
+    /// The names decided `blocked` by the default policy, least recently used first.
+    pub(crate) fn names(&self, blocked: bool) -> Vec<&str> {
+        self.blocked
+            .iter()
+            .rev()
+            .filter(|((policy, _), it)| *policy == 0 && **it == blocked)
+            .map(|((_, name), _)| name.as_str())
+            .collect()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocked.clear();
+        metrics::gauge!("dns_checked_domains").set(0.0);
+    }
 }
 
+/// Applies the entries limited to some record types to the decision made by those that aren't.
+/// They are rare enough not to be worth caching per type, and their allowlist entries take
+/// precedence over blocklist entries covering every type.
+fn match_typed(
+    policy: &Policy,
+    matching: Matching,
+    domain: &str,
+    query_type: RecordType,
+    blocked: bool,
+) -> bool {
+    let blacklist = policy.blacklist.load();
+    let allowlist = policy.allowlist.load();
+    if !blacklist.has_typed() && !allowlist.has_typed() {
+        return blocked;
+    }
+    !allowlist.matches_type(domain, query_type, matching)
+        && (blocked || blacklist.matches_type(domain, query_type, matching))
+}
+
+/// The block and allow lists applied to a client.
+#[derive(Clone, Default)]
+struct Policy {
+    blacklist: Arc<ArcSwap<SuffixSet>>,
+    allowlist: Arc<ArcSwap<SuffixSet>>,
+}
+
+/// Clients in any of `networks` get their own lists.
+struct ClientGroup {
+    name: String,
+    networks: Vec<IpNet>,
+    policy: Policy,
+}
+
+/// Resolution of a query still waiting for the upstream, shared with retransmissions of it.
+type Inflight = watch::Receiver<Option<Result<DnsResponse, String>>>;
+
+/// A client's query, as told apart by its source address and message id.
+type InflightKey = (SocketAddr, u16, CacheKey);
+
 pub struct StubRequestHandler {
-    upstream: Arc<Mutex<AsyncClient>>,
-    blacklist: FxHashSet<String>,
+    upstream: Arc<dyn UpstreamClient>,
+    forwarders: Vec<(Name, Arc<dyn UpstreamClient>)>,
+    policy: Policy,
+    client_groups: Vec<ClientGroup>,
+    allow_from: Vec<IpNet>,
+    deny_from: Vec<IpNet>,
+    cookies: Option<Cookies>,
+    matching: Matching,
     checked: Arc<Mutex<CheckedDomain>>,
+    #[cfg(feature = "policy-script")]
+    policy_script: Option<PolicyScript>,
+    ecs_policy: EcsPolicy,
+    plugins: Vec<Arc<dyn QueryPlugin>>,
+    cache: Option<Arc<ResponseCache>>,
+    scheduled: Option<Arc<ScheduledLists>>,
+    history: Option<Arc<History>>,
+    overrides: Option<Arc<Overrides>>,
+    local: Option<Arc<LocalRecords>>,
+    acme: Option<Arc<AcmeChallenges>>,
+    zones: Option<Arc<Zones>>,
+    candidate_allowlist: Option<SuffixSet>,
+    svcb_block_response: SvcbBlockResponse,
+    rebind_protection: RebindProtection,
+    rebind_allowed: Vec<Name>,
+    block_page: Vec<IpAddr>,
+    upstream_diff: Option<Arc<UpstreamDiff>>,
+    ttl_bounds: (u32, u32),
+    max_udp_size: u16,
+    inflight: std::sync::Mutex<FxHashMap<InflightKey, Inflight>>,
 }
 
 impl StubRequestHandler {
-    pub fn new(upstream: Arc<Mutex<AsyncClient>>, blacklist: FxHashSet<String>) -> Self {
+    pub fn new(upstream: Arc<dyn UpstreamClient>, blacklist: FxHashSet<String>) -> Self {
         StubRequestHandler {
             upstream,
-            blacklist,
-            checked: Arc::new(Mutex::new(CheckedDomain::new())),
+            forwarders: Vec::new(),
+            policy: Policy {
+                blacklist: Arc::new(ArcSwap::from_pointee(SuffixSet::new(blacklist))),
+                allowlist: Arc::default(),
+            },
+            client_groups: Vec::new(),
+            allow_from: Vec::new(),
+            deny_from: Vec::new(),
+            cookies: None,
+            matching: Matching::default(),
+            checked: Arc::new(Mutex::new(CheckedDomain::new(CHECKED_CAPACITY))),
+            #[cfg(feature = "policy-script")]
+            policy_script: None,
+            ecs_policy: EcsPolicy::default(),
+            plugins: Vec::new(),
+            cache: None,
+            scheduled: None,
+            history: None,
+            overrides: None,
+            local: None,
+            acme: None,
+            zones: None,
+            candidate_allowlist: None,
+            svcb_block_response: SvcbBlockResponse::default(),
+            rebind_protection: RebindProtection::default(),
+            rebind_allowed: Vec::new(),
+            block_page: Vec::new(),
+            upstream_diff: None,
+            ttl_bounds: (0, u32::MAX),
+            max_udp_size: DEFAULT_MAX_UDP_SIZE,
+            inflight: std::sync::Mutex::new(FxHashMap::default()),
         }
     }
 
-    #[instrument(skip(self))]
-    async fn is_blacklist_subdomain(&self, domain: &String) -> bool {
+    /// Remembers at most `capacity` block decisions instead of the default.
+    pub fn with_checked_capacity(mut self, capacity: NonZeroUsize) -> Self {
+        self.checked = Arc::new(Mutex::new(CheckedDomain::new(capacity)));
+        self
+    }
+
+    /// Raises TTLs of upstream answers to `min` and lowers them to `max`.
+    pub fn with_ttl_bounds(mut self, min: Option<u32>, max: Option<u32>) -> Self {
+        self.ttl_bounds = (min.unwrap_or(0), max.unwrap_or(u32::MAX));
+        self
+    }
+
+    /// Answers UDP queries with at most `max_udp_size` bytes, or the smaller payload size the
+    /// client asks for with EDNS, setting TC on larger answers so the client retries over TCP.
+    pub fn with_max_udp_size(mut self, max_udp_size: u16) -> Self {
+        self.max_udp_size = max_udp_size.max(MIN_UDP_SIZE);
+        self
+    }
+
+    /// The size UDP answers to `request` must fit into, if it came over UDP.
+    fn udp_size_limit(&self, request: &Request) -> Option<u16> {
+        match request.protocol() {
+            Protocol::Udp => Some(match request.edns() {
+                Some(edns) => edns.max_payload().clamp(MIN_UDP_SIZE, self.max_udp_size),
+                None => MIN_UDP_SIZE,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The answer, authority and additional records of a response cut down to the UDP payload
+    /// size of `request`: without the additional records if only they don't fit, or else without
+    /// any records and with TC set on `header`.
+    fn fit_to_udp_size<'r>(
+        &self,
+        request: &Request,
+        header: &mut Header,
+        edns: Option<&Edns>,
+        sections: [&'r [Record]; 3],
+    ) -> [&'r [Record]; 3] {
+        let Some(limit) = self.udp_size_limit(request) else {
+            return sections;
+        };
+        let fits = |[answers, authority, additionals]: [&[Record]; 3]| {
+            let mut buffer = Vec::with_capacity(usize::from(limit));
+            let mut encoder = BinEncoder::new(&mut buffer);
+            encoder.set_max_size(limit);
+            emit_message_parts(
+                header,
+                &mut iter::once(request.query().original()),
+                &mut answers.iter(),
+                &mut authority.iter(),
+                &mut additionals.iter(),
+                edns,
+                &[],
+                &mut encoder,
+            )
+            .is_ok_and(|it| !it.truncated())
+        };
+
+        let [answers, authority, _] = sections;
+        if fits(sections) {
+            sections
+        } else if fits([answers, authority, &[]]) {
+            [answers, authority, &[]]
+        } else {
+            debug!("Truncating answer to {} bytes", limit);
+            metrics::counter!("dns_responses_truncated").increment(1);
+            header.set_truncated(true);
+            [&[]; 3]
+        }
+    }
+
+    pub fn with_svcb_block_response(mut self, svcb_block_response: SvcbBlockResponse) -> Self {
+        self.svcb_block_response = svcb_block_response;
+        self
+    }
+
+    /// Protects clients from answers leading to private addresses, except for the names under
+    /// `allowed` and the forwarded domains.
+    pub fn with_rebind_protection(
+        mut self,
+        rebind_protection: RebindProtection,
+        allowed: Vec<Name>,
+    ) -> Self {
+        self.rebind_protection = rebind_protection;
+        self.rebind_allowed = allowed;
+        self
+    }
+
+    /// Answers blocked A/AAAA queries with the addresses of a server explaining the block, and
+    /// other blocked queries with NODATA so the name still resolves.
+    pub fn with_block_page(mut self, addresses: Vec<IpAddr>) -> Self {
+        self.block_page = addresses;
+        self
+    }
+
+    /// Answers repeated queries from `cache` instead of the upstream.
+    pub fn with_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Compares the answers of every upstream for the queries `diff` covers.
+    pub fn with_upstream_diff(mut self, diff: UpstreamDiff) -> Self {
+        self.upstream_diff = Some(Arc::new(diff));
+        self
+    }
+
+    /// Reports the blocked queries `allowlist` would let through, without letting them, so the
+    /// effect of an allowlist can be seen before it is rolled out.
+    pub fn with_candidate_allowlist(mut self, allowlist: FxHashSet<String>) -> Self {
+        self.candidate_allowlist = Some(SuffixSet::new(allowlist));
+        self
+    }
+
+    /// Answers the names in `local` itself, before anything else is consulted.
+    pub fn with_local_records(mut self, local: Arc<LocalRecords>) -> Self {
+        self.local = Some(local);
+        self
+    }
+
+    /// Answers the ACME challenges presented to `acme` itself.
+    pub fn with_acme_challenges(mut self, acme: Arc<AcmeChallenges>) -> Self {
+        self.acme = Some(acme);
+        self
+    }
+
+    /// Answers the names under `zones` authoritatively instead of forwarding them.
+    pub fn with_zones(mut self, zones: Arc<Zones>) -> Self {
+        self.zones = Some(zones);
+        self
+    }
+
+    /// Blocks and allows the domains overridden at runtime, whatever the lists say.
+    pub fn with_overrides(mut self, overrides: Arc<Overrides>) -> Self {
+        self.overrides = Some(overrides);
+        self
+    }
+
+    /// Records the verdict of every query in `history`.
+    pub fn with_history(mut self, history: Arc<History>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Runs `plugins`, in order, on every query.
+    pub fn with_plugins(mut self, plugins: Vec<Arc<dyn QueryPlugin>>) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    pub fn with_ecs_policy(mut self, ecs_policy: EcsPolicy) -> Self {
+        self.ecs_policy = ecs_policy;
+        self
+    }
+
+    /// Matches list entries as `matching` says instead of on label boundaries.
+    pub fn with_matching(mut self, matching: Matching) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    /// Never blocks domains under the allowlist, even when they are under a blocked domain.
+    pub fn with_allowlist(self, allowlist: FxHashSet<String>) -> Self {
+        self.policy
+            .allowlist
+            .store(Arc::new(SuffixSet::new(allowlist)));
+        self
+    }
+
+    /// Also blocks the domains of `scheduled` lists while their schedule applies, unless the
+    /// client's allow list has them.
+    pub fn with_scheduled_lists(mut self, scheduled: Arc<ScheduledLists>) -> Self {
+        self.scheduled = Some(scheduled);
+        self
+    }
+
+    /// Applies other lists to clients in `networks`, checked in the order groups were added.
+    /// A group without its own block or allow list uses the default one.
+    pub fn with_client_group(
+        mut self,
+        name: String,
+        networks: Vec<IpNet>,
+        blacklist: Option<FxHashSet<String>>,
+        allowlist: Option<FxHashSet<String>>,
+    ) -> Self {
+        let own = |list: Option<FxHashSet<String>>, default: &Arc<ArcSwap<SuffixSet>>| match list {
+            Some(list) => Arc::new(ArcSwap::from_pointee(SuffixSet::new(list))),
+            None => default.clone(),
+        };
+        let policy = Policy {
+            blacklist: own(blacklist, &self.policy.blacklist),
+            allowlist: own(allowlist, &self.policy.allowlist),
+        };
+        self.client_groups.push(ClientGroup {
+            name,
+            networks,
+            policy,
+        });
+        self
+    }
+
+    /// Refuses queries from clients outside `allow_from`, unless it is empty, and from those in
+    /// `deny_from`, so an instance exposed by accident isn't an open resolver.
+    pub fn with_client_acl(mut self, allow_from: Vec<IpNet>, deny_from: Vec<IpNet>) -> Self {
+        self.allow_from = allow_from;
+        self.deny_from = deny_from;
+        self
+    }
+
+    /// Answers DNS Cookies of clients, so those that keep sending the server cookie they got
+    /// can't be spoofed by an off-path attacker guessing the message id.
+    pub fn with_cookies(mut self, mode: CookieMode) -> Self {
+        self.cookies = (mode != CookieMode::Off).then(|| Cookies::new(mode));
+        self
+    }
+
+    fn is_client_allowed(&self, client: IpAddr) -> bool {
+        // Clients of dual-stack sockets show up as IPv4-mapped addresses.
+        let client = client.to_canonical();
+        (self.allow_from.is_empty() || self.allow_from.iter().any(|it| it.contains(&client)))
+            && !self.deny_from.iter().any(|it| it.contains(&client))
+    }
+
+    /// The policy applied to `client` and its index in the decision cache.
+    fn policy_for(&self, client: IpAddr) -> (usize, &Policy) {
+        let client = client.to_canonical();
+        self.client_groups
+            .iter()
+            .enumerate()
+            .find(|(_, group)| group.networks.iter().any(|it| it.contains(&client)))
+            .map(|(i, group)| (i + 1, &group.policy))
+            .unwrap_or((0, &self.policy))
+    }
+
+    #[cfg(feature = "policy-script")]
+    pub fn with_policy_script(mut self, policy_script: PolicyScript) -> Self {
+        self.policy_script = Some(policy_script);
+        self
+    }
+
+    /// Forwards queries for names under each zone to its own upstream instead of the default one.
+    pub fn with_forwarders(mut self, mut forwarders: Vec<(Name, Arc<dyn UpstreamClient>)>) -> Self {
+        // The most specific zone wins.
+        forwarders.sort_by_key(|(zone, _)| std::cmp::Reverse(zone.num_labels()));
+        self.forwarders = forwarders;
+        self
+    }
+
+    fn upstream_for(&self, name: &Name) -> &dyn UpstreamClient {
+        select_upstream(self.upstream.as_ref(), &self.forwarders, name)
+    }
+
+    /// A handle for saving and restoring the response and decision caches.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.cache.clone(),
+            self.checked.clone(),
+            self.policy.blacklist.clone(),
+            self.policy.allowlist.clone(),
+            self.matching,
+        )
+    }
+
+    /// A handle for replacing the default block and allow lists while serving.
+    pub fn lists(&self) -> Lists {
+        Lists {
+            policy: self.policy.clone(),
+            checked: self.checked.clone(),
+        }
+    }
+
+    /// A handle for replacing the lists of a client group while serving. Lists the group
+    /// shares with the default policy are replaced through `lists` instead.
+    pub fn group_lists(&self, name: &str) -> Option<Lists> {
+        let group = self.client_groups.iter().find(|it| it.name == name)?;
+        Some(Lists {
+            policy: group.policy.clone(),
+            checked: self.checked.clone(),
+        })
+    }
+
+    /// Resolves A and AAAA for each of `names` the default policy doesn't block, so their
+    /// answers are cached before clients ask.
+    pub async fn warm_up(&self, names: &[Name]) {
+        if self.cache.is_none() {
+            return;
+        }
+
+        let start = Instant::now();
+        let mut queries = Vec::new();
         let mut checked = self.checked.lock().await;
+        for name in names {
+            let domain = name.to_string();
+            for query_type in [RecordType::A, RecordType::AAAA] {
+                if !self.is_blocked_by(&mut checked, (0, &self.policy), &domain, query_type) {
+                    queries.push((name, query_type));
+                }
+            }
+        }
+        drop(checked);
 
-        if checked.block.contains(domain) {
-            return true;
+        let options = self.shared_options();
+        let warmed = futures::stream::iter(queries)
+            .map(|(name, query_type)| {
+                let options = &options;
+                async move {
+                    if let Err(e) = self
+                        .forward_to_upstream(name.clone(), DNSClass::IN, query_type, options)
+                        .await
+                    {
+                        warn!("warm-up query failed {} {}: {}", name, query_type, e);
+                    }
+                }
+            })
+            .buffer_unordered(WARM_UP_CONCURRENCY)
+            .count();
+        match tokio::time::timeout(WARM_UP_TIMEOUT, warmed).await {
+            Ok(queries) => debug!(
+                "warmed up with {} queries in {:?}",
+                queries,
+                start.elapsed()
+            ),
+            Err(_) => warn!("warm-up did not finish within {:?}", WARM_UP_TIMEOUT),
         }
+    }
 
-        if checked.allow.contains(domain) {
-            return false;
+    /// EDNS options for queries made on behalf of every client rather than a single one.
+    fn shared_options(&self) -> Vec<EdnsOption> {
+        // Only an overridden subnet applies to every client; forwarded ones are never cached.
+        match self.ecs_policy {
+            EcsPolicy::Override(subnet) => vec![EdnsOption::Subnet(subnet)],
+            _ => Vec::new(),
         }
+    }
+
+    /// Refreshes cache entries hit at least `min_hits` times shortly before they expire.
+    pub fn spawn_prefetch(&self, min_hits: u64) {
+        let Some(cache) = self.cache.clone() else {
+            return;
+        };
+        let upstream = self.upstream.clone();
+        let forwarders = self.forwarders.clone();
+        let options = self.shared_options();
+        let ttl_bounds = self.ttl_bounds;
 
-        for it in &self.blacklist {
-            if domain.ends_with(it) {
-                checked.block.insert(domain.to_string());
-                return true;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PREFETCH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for key in cache.expiring(min_hits, PREFETCH_INTERVAL * 2) {
+                    let upstream = select_upstream(upstream.as_ref(), &forwarders, key.name());
+                    let name = key.name().clone();
+                    match upstream
+                        .query(name, key.query_class(), key.query_type(), &options)
+                        .await
+                        .and_then(|it| clamp_ttls(it, ttl_bounds))
+                    {
+                        Ok(response) => {
+                            metrics::counter!("dns_cache_prefetch").increment(1);
+                            cache.insert(key, &response);
+                        }
+                        Err(e) => warn!("prefetch failed {}: {}", key.name(), e),
+                    }
+                }
             }
+        });
+    }
+
+    #[instrument(skip(self))]
+    async fn is_blacklist_subdomain(
+        &self,
+        client: IpAddr,
+        domain: &String,
+        query_type: RecordType,
+    ) -> bool {
+        let lock_start = Instant::now();
+        let mut checked = self
+            .checked
+            .lock()
+            .instrument(stage_span("checked_lock"))
+            .await;
+        record_stage_duration("checked_lock", lock_start);
+
+        let match_start = Instant::now();
+        let blocked = stage_span("blocklist").in_scope(|| {
+            self.is_blocked_by(&mut checked, self.policy_for(client), domain, query_type)
+        });
+        record_stage_duration("blocklist", match_start);
+
+        blocked
+    }
+
+    /// Whether `policy` blocks `domain` for `query_type` queries, overrides taking precedence.
+    fn is_blocked_by(
+        &self,
+        checked: &mut CheckedDomain,
+        policy: (usize, &Policy),
+        domain: &String,
+        query_type: RecordType,
+    ) -> bool {
+        // Not cached, so changing them takes effect right away.
+        let overridden = self
+            .overrides
+            .as_ref()
+            .and_then(|it| it.verdict(domain, self.matching));
+        if let Some(blocked) = overridden {
+            return blocked;
         }
+        let blocked = self.match_blacklist(checked, policy, domain)
+            || self.is_scheduled_block(policy.1, domain, query_type);
+        match_typed(policy.1, self.matching, domain, query_type, blocked)
+    }
 
-        checked.allow.insert(domain.to_string());
-        false
+    fn check_candidate_allowlist(&self, client: IpAddr, domain: &str, query_type: RecordType) {
+        let Some(allowlist) = &self.candidate_allowlist else {
+            return;
+        };
+        if allowlist.matches_type(domain, query_type, self.matching) {
+            metrics::counter!("dns_candidate_allowed").increment(1);
+            tracing::info!(
+                target: "advoid::candidate",
+                name = %domain,
+                %client,
+                query_type = %query_type,
+                "blocked query would be allowed by the candidate allowlist"
+            );
+        }
+    }
+
+    fn match_blacklist(
+        &self,
+        checked: &mut CheckedDomain,
+        (id, policy): (usize, &Policy),
+        domain: &String,
+    ) -> bool {
+        if let Some(blocked) = checked.get(id, domain) {
+            return blocked;
+        }
+
+        let blocked = !policy.allowlist.load().matches(domain, self.matching)
+            && policy.blacklist.load().matches(domain, self.matching);
+        checked.insert(id, domain.to_string(), blocked);
+        blocked
+    }
+
+    /// Not cached, as the scheduled lists change over the day.
+    fn is_scheduled_block(&self, policy: &Policy, domain: &str, query_type: RecordType) -> bool {
+        self.scheduled
+            .as_ref()
+            .is_some_and(|it| it.matches(domain, query_type, self.matching))
+            && !policy
+                .allowlist
+                .load()
+                .matches_type(domain, query_type, self.matching)
+    }
+
+    /// Whether an override or the client's allow list lets `domain` through whatever the block
+    /// lists say.
+    fn is_allowlisted(&self, client: IpAddr, domain: &str, query_type: RecordType) -> bool {
+        let overridden = self
+            .overrides
+            .as_ref()
+            .and_then(|it| it.verdict(domain, self.matching));
+        match overridden {
+            Some(blocked) => !blocked,
+            None => self.policy_for(client).1.allowlist.load().matches_type(
+                domain,
+                query_type,
+                self.matching,
+            ),
+        }
+    }
+
+    /// A blocked name the answer to a `query_type` query leads clients to, either through its
+    /// CNAME chain (trackers cloaked behind first-party names) or as an HTTPS/SVCB service name.
+    async fn blocked_target<'a>(
+        &self,
+        client: IpAddr,
+        query_type: RecordType,
+        response: &'a DnsResponse,
+    ) -> Option<&'a Name> {
+        for record in response.answers() {
+            let target = match record.data() {
+                Some(RData::CNAME(CNAME(target))) => target,
+                Some(RData::HTTPS(HTTPS(svcb))) | Some(RData::SVCB(svcb)) => svcb.target_name(),
+                _ => continue,
+            };
+
+            // The root name stands for the owner name, which has already been checked. Upstreams
+            // may answer with any case, and the lists are lowercase.
+            if !target.is_root()
+                && self
+                    .is_blacklist_subdomain(client, &target.to_lowercase().to_string(), query_type)
+                    .await
+            {
+                return Some(target);
+            }
+        }
+        None
+    }
+
+    /// `response` without private addresses for a public `name`, or `None` if it is refused.
+    fn protect_from_rebinding(
+        &self,
+        name: &Name,
+        response: DnsResponse,
+    ) -> anyhow::Result<Option<DnsResponse>> {
+        let private = |record: &Record| {
+            record
+                .data()
+                .and_then(RData::ip_addr)
+                .is_some_and(|it| is_private(it) || it.is_unspecified())
+        };
+        if self.rebind_protection == RebindProtection::Off
+            || !response.answers().iter().any(private)
+            || self.rebind_allowed.iter().any(|it| it.zone_of(name))
+            || self.forwarders.iter().any(|(zone, _)| zone.zone_of(name))
+        {
+            return Ok(Some(response));
+        }
+
+        debug!("Protecting from {} leading to private addresses", name);
+        match self.rebind_protection {
+            RebindProtection::Refuse => {
+                metrics::counter!("dns_rebinding_blocked", "action" => "refuse").increment(1);
+                Ok(None)
+            }
+            _ => {
+                metrics::counter!("dns_rebinding_blocked", "action" => "strip").increment(1);
+                let mut message = response.into_message();
+                message.answers_mut().retain(|it| !private(it));
+                Ok(Some(DnsResponse::from_message(message)?))
+            }
+        }
+    }
+
+    fn blocked_answer(&self, name: &Name, query_type: RecordType) -> Answer {
+        if !self.block_page.is_empty() {
+            return Answer::Local(
+                self.block_page
+                    .iter()
+                    .flat_map(|it| address_records(name, query_type, *it))
+                    .collect(),
+            );
+        }
+        match (query_type, self.svcb_block_response) {
+            (RecordType::HTTPS | RecordType::SVCB, SvcbBlockResponse::Nodata) => {
+                Answer::Local(Vec::new())
+            }
+            _ => Answer::Blocked,
+        }
+    }
+
+    /// EDNS options sent upstream along with the query.
+    fn upstream_options(&self, request: &Request) -> Vec<EdnsOption> {
+        let subnet = match &self.ecs_policy {
+            EcsPolicy::Strip => None,
+            EcsPolicy::Forward => request
+                .edns()
+                .and_then(|edns| edns.option(EdnsCode::Subnet))
+                .cloned(),
+            EcsPolicy::Override(subnet) => Some(EdnsOption::Subnet(*subnet)),
+        };
+
+        // Nothing else the client sent is forwarded, including the device and MAC address
+        // options some home routers add to identify clients.
+        let forwarded = matches!(self.ecs_policy, EcsPolicy::Forward).then_some(EdnsCode::Subnet);
+        if let Some(edns) = request.edns() {
+            for code in edns.options().as_ref().keys() {
+                if Some(*code) == forwarded {
+                    continue;
+                }
+                if let Some(option) = stripped_option_label(u16::from(*code)) {
+                    debug!("Stripping EDNS option {:?}", code);
+                    metrics::counter!("dns_edns_options_stripped", "option" => option).increment(1);
+                }
+            }
+        }
+
+        subnet.into_iter().collect()
     }
 
     #[instrument(skip(self))]
@@ -67,12 +852,231 @@ impl StubRequestHandler {
         name: Name,
         query_class: DNSClass,
         query_type: RecordType,
+        options: &[EdnsOption],
     ) -> anyhow::Result<DnsResponse> {
-        let mut upstream = { self.upstream.lock().await.clone() };
+        // Answers tailored to a forwarded client subnet must not be shared with other clients.
+        let cache = match self.ecs_policy {
+            EcsPolicy::Forward if !options.is_empty() => None,
+            _ => self.cache.as_ref(),
+        };
+
+        let key = CacheKey::new(&name, query_class, query_type);
+        if let Some(response) = cache.and_then(|it| stage_span("cache").in_scope(|| it.get(&key))) {
+            return Ok(response);
+        }
+
+        let start = Instant::now();
+        let upstream = self.upstream_for(&name);
         let response = upstream
-            .query(name.clone(), query_class, query_type)
-            .await?;
-        Ok(response)
+            .query(name, query_class, query_type, options)
+            .instrument(stage_span("upstream"))
+            .await;
+        record_stage_duration("upstream", start);
+        let response = response.and_then(|it| clamp_ttls(it, self.ttl_bounds));
+
+        if let (Some(cache), Ok(response)) = (cache, &response) {
+            cache.insert(key, response);
+        }
+        response
+    }
+
+    /// Forwards the query of `request`, unless it is a retransmission of one still in flight,
+    /// in which case it waits for the answer to the original instead.
+    async fn forward_request(
+        &self,
+        request: &Request,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        options: &[EdnsOption],
+    ) -> anyhow::Result<DnsResponse> {
+        let key = (
+            request.src(),
+            request.id(),
+            CacheKey::new(&name, query_class, query_type),
+        );
+        let pending = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(original) => Err(original.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    inflight.insert(key.clone(), receiver);
+                    Ok(sender)
+                }
+            }
+        };
+
+        let sender = match pending {
+            Ok(sender) => sender,
+            Err(mut original) => {
+                debug!("Attaching retransmitted query {} to the original", &name);
+                metrics::counter!("dns_requests_deduplicated").increment(1);
+                let result = match original.wait_for(Option::is_some).await {
+                    Ok(result) => result.clone(),
+                    Err(_) => None,
+                };
+                return match result {
+                    Some(result) => result.map_err(anyhow::Error::msg),
+                    None => anyhow::bail!("original query for {} was abandoned", name),
+                };
+            }
+        };
+
+        let _guard = InflightGuard {
+            inflight: &self.inflight,
+            key,
+        };
+        let response = self
+            .forward_to_upstream(name, query_class, query_type, options)
+            .await;
+        sender.send_replace(Some(match &response {
+            Ok(response) => Ok(response.clone()),
+            Err(e) => Err(e.to_string()),
+        }));
+        response
+    }
+
+    /// Decides a query through the plugins, the policy script and the lists, forwarding it
+    /// upstream unless it is blocked or rewritten.
+    async fn resolve(
+        &self,
+        request: &Request,
+        name: &Name,
+        class: DNSClass,
+        tpe: RecordType,
+    ) -> anyhow::Result<(Verdict, Answer)> {
+        let query = PluginQuery {
+            client: request.src().ip(),
+            name,
+            query_class: class,
+            query_type: tpe,
+            allowlisted: !self.plugins.is_empty()
+                && self.is_allowlisted(request.src().ip(), &name.to_string(), tpe),
+            upstream: self.upstream_for(name),
+        };
+
+        let mut decision = Decision::Default;
+        for plugin in &self.plugins {
+            decision = plugin.on_query(&query).await;
+            if decision != Decision::Default {
+                debug!(
+                    "Plugin {} decided query {}: {:?}",
+                    plugin.name(),
+                    name,
+                    decision
+                );
+                metrics::counter!("dns_plugin_decisions", "plugin" => plugin.name()).increment(1);
+                break;
+            }
+        }
+
+        #[cfg(feature = "policy-script")]
+        let decision = match &self.policy_script {
+            _ if decision != Decision::Default => decision,
+            Some(script) => {
+                let start = Instant::now();
+                let domain = name.to_string();
+                let decision = stage_span("policy_script").in_scope(|| {
+                    script.evaluate(request.src().ip(), domain.trim_end_matches('.'), tpe)
+                });
+                record_stage_duration("policy_script", start);
+                decision
+            }
+            None => Decision::Default,
+        };
+
+        let blocked = match decision {
+            Decision::Default => {
+                let domain = name.to_string();
+                let blocked = self
+                    .is_blacklist_subdomain(request.src().ip(), &domain, tpe)
+                    .await;
+                if blocked {
+                    self.check_candidate_allowlist(request.src().ip(), &domain, tpe);
+                }
+                blocked
+            }
+            Decision::Allow => false,
+            Decision::Block | Decision::Rewrite(_) => true,
+        };
+
+        let resolved = if let Decision::Rewrite(address) = decision {
+            debug!("Rewriting query {} to {}", name, address);
+            metrics::counter!("dns_requests_rewrite").increment(1);
+            (
+                Verdict::Rewrite,
+                Answer::Local(address_records(name, tpe, address)),
+            )
+        } else if blocked {
+            debug!("Bypassing upstream query {}", &name.to_string());
+            metrics::counter!("dns_requests_block").increment(1);
+            (Verdict::Block, self.blocked_answer(name, tpe))
+        } else {
+            let options = self.upstream_options(request);
+            let mut dns_response = self
+                .forward_request(request, name.clone(), class, tpe, &options)
+                .await?;
+
+            if let Some(diff) = self.upstream_diff.clone().filter(|it| it.covers(name)) {
+                let name = name.clone();
+                tokio::spawn(async move { diff.compare(name, class, tpe).await });
+            }
+
+            let target = match decision {
+                Decision::Default => {
+                    self.blocked_target(request.src().ip(), tpe, &dns_response)
+                        .await
+                }
+                _ => None,
+            };
+            let mut verdict = match target {
+                Some(target) => {
+                    debug!("Blocking {} for its target {}", name, target);
+                    Decision::Block
+                }
+                None => Decision::Default,
+            };
+            for plugin in &self.plugins {
+                if verdict != Decision::Default {
+                    break;
+                }
+                verdict = plugin.on_response(&query, &mut dns_response).await;
+                if verdict != Decision::Default {
+                    debug!(
+                        "Plugin {} decided answer {}: {:?}",
+                        plugin.name(),
+                        name,
+                        verdict
+                    );
+                    metrics::counter!("dns_plugin_decisions", "plugin" => plugin.name())
+                        .increment(1);
+                }
+            }
+
+            match verdict {
+                Decision::Block => {
+                    metrics::counter!("dns_requests_block").increment(1);
+                    (Verdict::Block, self.blocked_answer(name, tpe))
+                }
+                Decision::Rewrite(address) => {
+                    metrics::counter!("dns_requests_rewrite").increment(1);
+                    (
+                        Verdict::Rewrite,
+                        Answer::Local(address_records(name, tpe, address)),
+                    )
+                }
+                Decision::Default | Decision::Allow => {
+                    metrics::counter!("dns_requests_forward").increment(1);
+                    match self.protect_from_rebinding(name, dns_response)? {
+                        Some(response) => (Verdict::Forward, Answer::Upstream(response)),
+                        None => (Verdict::Block, Answer::Refused),
+                    }
+                }
+            }
+        };
+
+        Ok(resolved)
     }
 
     #[instrument(skip_all)]
@@ -82,41 +1086,113 @@ impl StubRequestHandler {
         request: &Request,
         response_handle: R,
     ) -> anyhow::Result<ResponseInfo> {
+        let start = Instant::now();
         let name = request.query().name().into_name()?;
         let class = request.query().query_class();
         let tpe = request.query().query_type();
 
-        let upstream_response = if self.is_blacklist_subdomain(&name.to_string()).await {
-            debug!("Bypassing upstream query {}", &name.to_string());
-            metrics::counter!("dns_requests_block").increment(1);
-            None
-        } else {
-            let dns_response = self.forward_to_upstream(name.clone(), class, tpe).await?;
-            metrics::counter!("dns_requests_forward").increment(1);
-            Some(dns_response)
+        let local = self
+            .acme
+            .as_ref()
+            .and_then(|it| it.lookup(&name, tpe))
+            .or_else(|| self.local.as_ref().and_then(|it| it.lookup(&name, tpe)));
+        let local = local
+            .map(|answers| Answer::Authoritative {
+                response_code: ResponseCode::NoError,
+                answers,
+                authority: Vec::new(),
+            })
+            .or_else(|| {
+                let zone = self.zones.as_ref()?.lookup(&name, tpe)?;
+                Some(Answer::Authoritative {
+                    response_code: zone.response_code,
+                    answers: zone.answers,
+                    authority: zone.authority,
+                })
+            });
+        let (verdict, answer) = match local {
+            Some(answer) => {
+                debug!("Answering query {} from local records", &name);
+                metrics::counter!("dns_requests_local").increment(1);
+                (Verdict::Local, answer)
+            }
+            None => self.resolve(request, &name, class, tpe).await?,
         };
 
+        if let Some(history) = &self.history {
+            history.record(
+                &name,
+                request.src().ip(),
+                tpe,
+                verdict,
+                answer.answer_count(),
+                start.elapsed(),
+            );
+        }
+
         let response_builder = MessageResponseBuilder::from_message_request(request);
 
-        let response_info = match upstream_response {
-            Some(response) => {
+        let response_info = match answer {
+            Answer::Upstream(response) => {
                 let mut response_header = Header::response_from_request(request.header());
                 response_header.set_recursion_available(response.recursion_available());
                 response_header.set_response_code(response.response_code());
 
-                let response = response_builder.build(
-                    response_header,
-                    response.answers(),
-                    response.name_servers(),
-                    &[],
-                    response.additionals(),
+                let [answers, authority, additionals] = self.fit_to_udp_size(
+                    request,
+                    &mut response_header,
+                    response_edns.as_ref(),
+                    [
+                        response.answers(),
+                        response.name_servers(),
+                        response.additionals(),
+                    ],
+                );
+                let response =
+                    response_builder.build(response_header, answers, authority, &[], additionals);
+                send_response(response_edns, response, response_handle).await?
+            }
+            Answer::Local(records) => {
+                let mut response_header = Header::response_from_request(request.header());
+                response_header.set_recursion_available(true);
+
+                let [answers, _, _] = self.fit_to_udp_size(
+                    request,
+                    &mut response_header,
+                    response_edns.as_ref(),
+                    [&records, &[], &[]],
                 );
+                let response = response_builder.build(response_header, answers, &[], &[], &[]);
                 send_response(response_edns, response, response_handle).await?
             }
-            None => {
+            Answer::Authoritative {
+                response_code,
+                answers,
+                authority,
+            } => {
+                let mut response_header = Header::response_from_request(request.header());
+                response_header.set_recursion_available(true);
+                response_header.set_authoritative(true);
+                response_header.set_response_code(response_code);
+
+                let [answers, authority, _] = self.fit_to_udp_size(
+                    request,
+                    &mut response_header,
+                    response_edns.as_ref(),
+                    [&answers, &authority, &[]],
+                );
+                let response =
+                    response_builder.build(response_header, answers, &[], authority, &[]);
+                send_response(response_edns, response, response_handle).await?
+            }
+            Answer::Blocked => {
                 let response = response_builder.error_msg(request.header(), ResponseCode::NXDomain);
                 send_response(response_edns, response, response_handle).await?
             }
+            Answer::Refused => {
+                let response = response_builder.error_msg(request.header(), ResponseCode::Refused);
+                send_response(response_edns, response, response_handle).await?
+            }
         };
 
         Ok(response_info)
@@ -151,6 +1227,22 @@ impl RequestHandler for StubRequestHandler {
     ) -> ResponseInfo {
         metrics::counter!("dns_requests_total").increment(1);
 
+        if !self.is_client_allowed(request.src().ip()) {
+            debug!("Refusing query from {}", request.src().ip());
+            metrics::counter!("dns_requests_refused_acl").increment(1);
+            let response = MessageResponseBuilder::from_message_request(request)
+                .error_msg(request.header(), ResponseCode::Refused);
+            return response_handle
+                .send_response(response)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("request error: {}", e);
+                    let mut header = Header::new();
+                    header.set_response_code(ResponseCode::ServFail);
+                    header.into()
+                });
+        }
+
         // check if it's edns
         let response_edns = if let Some(req_edns) = request.edns() {
             let mut response = MessageResponseBuilder::from_message_request(request);
@@ -162,7 +1254,8 @@ impl RequestHandler for StubRequestHandler {
             // TODO: what version are we?
             let our_version = 0;
             resp_edns.set_dnssec_ok(true);
-            resp_edns.set_max_payload(req_edns.max_payload().max(512));
+            // UDP answers are cut to the payload size of the response.
+            resp_edns.set_max_payload(self.udp_size_limit(request).unwrap_or(self.max_udp_size));
             resp_edns.set_version(our_version);
 
             if req_edns.version() > our_version {
@@ -189,6 +1282,31 @@ impl RequestHandler for StubRequestHandler {
                 });
             }
 
+            if let Some(cookies) = &self.cookies {
+                let udp = matches!(request.protocol(), Protocol::Udp);
+                let (cookie, error) = cookies.check(req_edns, request.src().ip(), udp);
+                if let Some(cookie) = cookie {
+                    resp_edns.options_mut().insert(cookie);
+                }
+                if let Some(error) = error {
+                    debug!("Answering query from {} with {}", request.src().ip(), error);
+                    response_header.set_response_code(error);
+                    resp_edns.set_rcode_high(error.high());
+                    response.edns(resp_edns);
+
+                    let result = response_handle
+                        .send_response(response.build_no_records(response_header))
+                        .await;
+
+                    return result.unwrap_or_else(|e| {
+                        error!("request error: {}", e);
+                        let mut header = Header::new();
+                        header.set_response_code(ResponseCode::ServFail);
+                        header.into()
+                    });
+                }
+            }
+
             Some(resp_edns)
         } else {
             None
@@ -221,6 +1339,97 @@ impl RequestHandler for StubRequestHandler {
     }
 }
 
+/// The block and allow lists of a running `StubRequestHandler`.
+pub struct Lists {
+    policy: Policy,
+    checked: Arc<Mutex<CheckedDomain>>,
+}
+
+impl Lists {
+    /// How many entries the block and allow lists have.
+    pub fn counts(&self) -> (usize, usize) {
+        (
+            self.policy.blacklist.load().len(),
+            self.policy.allowlist.load().len(),
+        )
+    }
+
+    /// Swaps in the given lists, keeping the others, and forgets every decision made so far.
+    pub async fn replace(
+        &self,
+        blacklist: Option<FxHashSet<String>>,
+        allowlist: Option<FxHashSet<String>>,
+    ) {
+        // Lists are only matched against with the lock held, so no decision made against the
+        // old lists can be recorded after this.
+        let mut checked = self.checked.lock().await;
+        if let Some(blacklist) = blacklist {
+            self.policy
+                .blacklist
+                .store(Arc::new(SuffixSet::new(blacklist)));
+        }
+        if let Some(allowlist) = allowlist {
+            self.policy
+                .allowlist
+                .store(Arc::new(SuffixSet::new(allowlist)));
+        }
+        checked.clear();
+    }
+}
+
+/// Removes an in-flight query once it is answered or its handler is dropped.
+struct InflightGuard<'a> {
+    inflight: &'a std::sync::Mutex<FxHashMap<InflightKey, Inflight>>,
+    key: InflightKey,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+fn clamp_ttls(response: DnsResponse, (min, max): (u32, u32)) -> anyhow::Result<DnsResponse> {
+    if (min, max) == (0, u32::MAX) {
+        return Ok(response);
+    }
+
+    let mut message = response.into_message();
+    let clamp = |records: &mut Vec<Record>| {
+        for record in records.iter_mut() {
+            record.set_ttl(record.ttl().clamp(min, max));
+        }
+    };
+    clamp(message.answers_mut());
+    clamp(message.name_servers_mut());
+    clamp(message.additionals_mut());
+
+    Ok(DnsResponse::from_message(message)?)
+}
+
+fn select_upstream<'a>(
+    upstream: &'a dyn UpstreamClient,
+    forwarders: &'a [(Name, Arc<dyn UpstreamClient>)],
+    name: &Name,
+) -> &'a dyn UpstreamClient {
+    forwarders
+        .iter()
+        .find(|(zone, _)| zone.zone_of(name))
+        .map(|(_, upstream)| upstream.as_ref())
+        .unwrap_or(upstream)
+}
+
+/// Answers `name` with `address` when it matches the query type, otherwise with no data.
+fn address_records(name: &Name, query_type: RecordType, address: IpAddr) -> Vec<Record> {
+    let rdata = match (query_type, address) {
+        (RecordType::A, IpAddr::V4(ip)) => RData::A(A(ip)),
+        (RecordType::AAAA, IpAddr::V6(ip)) => RData::AAAA(AAAA(ip)),
+        _ => return Vec::new(),
+    };
+
+    vec![Record::from_rdata(name.clone(), LOCAL_TTL, rdata)]
+}
+
 #[allow(unused_mut, unused_variables)]
 #[instrument(skip_all)]
 async fn send_response<'a, R: ResponseHandler>(
@@ -257,5 +1466,704 @@ async fn send_response<'a, R: ResponseHandler>(
         response.set_edns(resp_edns);
     }
 
-    response_handle.send_response(response).await
+    // Serializing the response and writing it to the socket.
+    let start = Instant::now();
+    let info = response_handle
+        .send_response(response)
+        .instrument(stage_span("respond"))
+        .await;
+    record_stage_duration("respond", start);
+    info
+}
+
+/// The `dns_edns_options_stripped` label of an option the client sent, or `None` for hop-by-hop
+/// options (COOKIE, keepalive and padding) that never identify it. Codes besides ECS and the MAC
+/// and device ID options of home routers are counted as `other`, so clients can't add series.
+fn stripped_option_label(code: u16) -> Option<&'static str> {
+    match code {
+        10..=12 => None,
+        8 => Some("8"),
+        65001 => Some("65001"),
+        65073 => Some("65073"),
+        65074 => Some("65074"),
+        _ => Some("other"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_client::op::{Message, Query};
+    use hickory_proto::error::{ProtoError, ProtoErrorKind};
+    use hickory_proto::serialize::binary::{BinDecodable, BinDecoder, BinEncoder};
+    use hickory_server::authority::MessageRequest;
+    use std::collections::VecDeque;
+    use std::net::Ipv4Addr;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    enum Scripted {
+        Answer(Ipv4Addr),
+        Slow(Ipv4Addr, Duration),
+        Cname(&'static str, Ipv4Addr),
+        Truncated(Ipv4Addr),
+        Error,
+        Timeout,
+    }
+
+    /// Answers queries from a script instead of the network.
+    #[derive(Default)]
+    struct FakeUpstream {
+        script: std::sync::Mutex<VecDeque<Scripted>>,
+        calls: AtomicUsize,
+    }
+
+    impl FakeUpstream {
+        fn new(script: impl IntoIterator<Item = Scripted>) -> Arc<Self> {
+            Arc::new(FakeUpstream {
+                script: std::sync::Mutex::new(script.into_iter().collect()),
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl UpstreamClient for FakeUpstream {
+        async fn query(
+            &self,
+            name: Name,
+            query_class: DNSClass,
+            query_type: RecordType,
+            _options: &[EdnsOption],
+        ) -> anyhow::Result<DnsResponse> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let next = self.script.lock().unwrap().pop_front();
+
+            let mut query = Query::query(name.clone(), query_type);
+            query.set_query_class(query_class);
+            let mut message = Message::new();
+            message
+                .add_query(query)
+                .set_message_type(MessageType::Response)
+                .set_recursion_available(true);
+
+            match next.expect("unscripted upstream query") {
+                Scripted::Answer(ip) => {
+                    message.add_answer(Record::from_rdata(name, 300, RData::A(A(ip))));
+                }
+                Scripted::Cname(target, ip) => {
+                    let target = Name::from_ascii(target).unwrap();
+                    message
+                        .add_answer(Record::from_rdata(
+                            name,
+                            300,
+                            RData::CNAME(CNAME(target.clone())),
+                        ))
+                        .add_answer(Record::from_rdata(target, 300, RData::A(A(ip))));
+                }
+                Scripted::Slow(ip, delay) => {
+                    tokio::time::sleep(delay).await;
+                    message.add_answer(Record::from_rdata(name, 300, RData::A(A(ip))));
+                }
+                Scripted::Truncated(ip) => {
+                    message
+                        .add_answer(Record::from_rdata(name, 300, RData::A(A(ip))))
+                        .set_truncated(true);
+                }
+                Scripted::Error => anyhow::bail!("connection refused"),
+                Scripted::Timeout => return Err(ProtoError::from(ProtoErrorKind::Timeout).into()),
+            }
+            Ok(DnsResponse::from_message(message)?)
+        }
+    }
+
+    /// Keeps every response sent to the client.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<std::sync::Mutex<Vec<Message>>>);
+
+    impl Capture {
+        fn single(&self) -> Message {
+            let responses = self.0.lock().unwrap();
+            assert_eq!(responses.len(), 1, "expected exactly one response");
+            responses[0].clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ResponseHandler for Capture {
+        async fn send_response<'a>(
+            &mut self,
+            response: MessageResponse<
+                '_,
+                'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+                impl Iterator<Item = &'a Record> + Send + 'a,
+            >,
+        ) -> io::Result<ResponseInfo> {
+            let mut bytes = Vec::new();
+            let info = response.destructive_emit(&mut BinEncoder::new(&mut bytes))?;
+            self.0.lock().unwrap().push(Message::from_vec(&bytes)?);
+            Ok(info)
+        }
+    }
+
+    fn request(name: &str, query_type: RecordType, edns_version: Option<u8>) -> Request {
+        request_from("192.0.2.1:53000", name, query_type, edns_version)
+    }
+
+    fn request_from(
+        client: &str,
+        name: &str,
+        query_type: RecordType,
+        edns_version: Option<u8>,
+    ) -> Request {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+        if let Some(version) = edns_version {
+            let mut edns = Edns::new();
+            edns.set_version(version);
+            message.set_edns(edns);
+        }
+
+        let bytes = message.to_vec().unwrap();
+        let message = MessageRequest::read(&mut BinDecoder::new(&bytes)).unwrap();
+        Request::new(message, client.parse().unwrap(), Protocol::Udp)
+    }
+
+    fn handler(upstream: &Arc<FakeUpstream>, blocked: &[&str]) -> StubRequestHandler {
+        let blacklist = blocked.iter().map(|it| format!("{}.", it)).collect();
+        StubRequestHandler::new(upstream.clone(), blacklist)
+    }
+
+    async fn resolve(handler: &StubRequestHandler, request: &Request) -> (ResponseInfo, Message) {
+        let capture = Capture::default();
+        let info = handler.handle_request(request, capture.clone()).await;
+        (info, capture.single())
+    }
+
+    fn answer_addresses(message: &Message) -> Vec<IpAddr> {
+        message
+            .answers()
+            .iter()
+            .filter_map(|it| it.data().and_then(RData::ip_addr))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn forwards_allowed_queries() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 10))]);
+        let handler = handler(&upstream, &["ads.example"]);
+
+        let (_, response) =
+            resolve(&handler, &request("www.example.com.", RecordType::A, None)).await;
+
+        assert_eq!(response.id(), 1234);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 10])]);
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn blocks_subdomains_without_asking_upstream() {
+        let upstream = FakeUpstream::new([]);
+        let handler = handler(&upstream, &["ads.example"]);
+
+        for name in ["ads.example.", "tracker.ads.example."] {
+            let (_, response) = resolve(&handler, &request(name, RecordType::A, None)).await;
+            assert_eq!(response.response_code(), ResponseCode::NXDomain, "{}", name);
+        }
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn allowlist_takes_precedence() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 20))]);
+        let handler = handler(&upstream, &["example"])
+            .with_allowlist(["cdn.example.".to_string()].into_iter().collect());
+
+        let (_, response) =
+            resolve(&handler, &request("img.cdn.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(upstream.calls(), 1);
+
+        let (_, response) = resolve(&handler, &request("ads.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn client_groups_get_their_own_lists() {
+        let upstream = FakeUpstream::new([
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 30)),
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 31)),
+        ]);
+        let games: Option<FxHashSet<String>> =
+            Some(["games.example.".to_string()].into_iter().collect());
+        let kids = handler(&upstream, &["ads.example"])
+            .with_client_group(
+                "office".to_string(),
+                vec!["198.51.100.0/24".parse().unwrap()],
+                games.clone(),
+                None,
+            )
+            .with_client_group(
+                "kids".to_string(),
+                vec!["192.0.2.0/25".parse().unwrap()],
+                games,
+                None,
+            );
+
+        // Requests come from 192.0.2.1, which only the kids group covers.
+        let (_, response) = resolve(&kids, &request("games.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        let (_, response) = resolve(&kids, &request("ads.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(upstream.calls(), 1);
+        // The same client on a dual-stack socket.
+        let mapped = request_from(
+            "[::ffff:192.0.2.1]:53000",
+            "games.example.",
+            RecordType::A,
+            None,
+        );
+        let (_, response) = resolve(&kids, &mapped).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+        let other = handler(&upstream, &["ads.example"]).with_client_group(
+            "office".to_string(),
+            vec!["198.51.100.0/24".parse().unwrap()],
+            Some(["games.example.".to_string()].into_iter().collect()),
+            None,
+        );
+        let (_, response) = resolve(&other, &request("games.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_outside_the_acl() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 60))]);
+        let net = |it: &str| it.parse::<IpNet>().unwrap();
+
+        // Requests come from 192.0.2.1.
+        let outside = handler(&upstream, &[]).with_client_acl(vec![net("10.0.0.0/8")], vec![]);
+        let denied = handler(&upstream, &[])
+            .with_client_acl(vec![net("192.0.2.0/24")], vec![net("192.0.2.1/32")]);
+        for handler in [outside, denied] {
+            let (_, response) =
+                resolve(&handler, &request("www.example.", RecordType::A, None)).await;
+            assert_eq!(response.response_code(), ResponseCode::Refused);
+        }
+        assert_eq!(upstream.calls(), 0);
+
+        let allowed = handler(&upstream, &[]).with_client_acl(vec![net("192.0.2.0/24")], vec![]);
+        let (_, response) = resolve(&allowed, &request("www.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn answers_blocked_queries_with_the_block_page() {
+        let upstream = FakeUpstream::new([]);
+        let handler = handler(&upstream, &["ads.example"])
+            .with_block_page(vec![IpAddr::from([192, 0, 2, 99])]);
+
+        let (_, response) = resolve(&handler, &request("ads.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 99])]);
+
+        // Types without a block page address still resolve, so the A answer isn't discarded.
+        for query_type in [RecordType::AAAA, RecordType::MX] {
+            let (_, response) = resolve(&handler, &request("ads.example.", query_type, None)).await;
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert!(response.answers().is_empty());
+        }
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_local_records_before_the_lists() {
+        let upstream = FakeUpstream::new([]);
+        let local = LocalRecords::parse("192.168.1.10 nas.home\nads.example A 192.0.2.1").unwrap();
+        let handler = handler(&upstream, &["ads.example"]).with_local_records(Arc::new(local));
+
+        let (_, response) = resolve(&handler, &request("nas.home.", RecordType::A, None)).await;
+        assert!(response.authoritative());
+        assert_eq!(
+            answer_addresses(&response),
+            [IpAddr::from([192, 168, 1, 10])]
+        );
+
+        let (_, response) = resolve(&handler, &request("ads.example.", RecordType::A, None)).await;
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 1])]);
+
+        let (_, response) = resolve(&handler, &request("nas.home.", RecordType::AAAA, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn truncates_answers_larger_than_the_udp_payload_size() {
+        let upstream = FakeUpstream::new([]);
+        let lines = |name: &str, count: u8| {
+            (1..=count)
+                .map(|it| format!("192.168.1.{} {}", it, name))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let local = format!("{}\n{}", lines("some.home", 40), lines("many.home", 100));
+        let local = LocalRecords::parse(&local).unwrap();
+        let handler = handler(&upstream, &[])
+            .with_local_records(Arc::new(local))
+            .with_max_udp_size(1232);
+        let edns_request = |name: &str, max_payload: u16| {
+            let mut message = Message::new();
+            message.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+            let mut edns = Edns::new();
+            edns.set_max_payload(max_payload);
+            message.set_edns(edns);
+            let bytes = message.to_vec().unwrap();
+            let message = MessageRequest::read(&mut BinDecoder::new(&bytes)).unwrap();
+            Request::new(message, "192.0.2.1:53000".parse().unwrap(), Protocol::Udp)
+        };
+
+        // 40 addresses don't fit in 512 bytes, but do in what the client negotiates.
+        let (_, response) = resolve(&handler, &request("some.home.", RecordType::A, None)).await;
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        let (_, response) = resolve(&handler, &edns_request("some.home.", 4096)).await;
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 40);
+        assert_eq!(response.extensions().as_ref().unwrap().max_payload(), 1232);
+
+        let (_, response) = resolve(&handler, &edns_request("many.home.", 4096)).await;
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn protects_from_answers_leading_to_private_addresses() {
+        let upstream = FakeUpstream::new([
+            Scripted::Answer(Ipv4Addr::new(192, 168, 1, 1)),
+            Scripted::Answer(Ipv4Addr::new(192, 168, 1, 1)),
+            Scripted::Answer(Ipv4Addr::new(192, 168, 1, 1)),
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 50)),
+        ]);
+        let allowed = vec![Name::from_ascii("router.example.").unwrap()];
+        let strip = handler(&upstream, &[])
+            .with_rebind_protection(RebindProtection::Strip, allowed.clone());
+        let refuse =
+            handler(&upstream, &[]).with_rebind_protection(RebindProtection::Refuse, allowed);
+
+        let (_, response) = resolve(&strip, &request("evil.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        let (_, response) = resolve(&refuse, &request("evil.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        let (_, response) = resolve(
+            &refuse,
+            &request("www.router.example.", RecordType::A, None),
+        )
+        .await;
+        assert_eq!(
+            answer_addresses(&response),
+            [IpAddr::from([192, 168, 1, 1])]
+        );
+        let (_, response) = resolve(&refuse, &request("www.example.", RecordType::A, None)).await;
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 50])]);
+    }
+
+    #[tokio::test]
+    async fn records_verdicts_in_history() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 40))]);
+        let history = Arc::new(History::new(NonZeroUsize::new(2).unwrap()));
+        let handler = handler(&upstream, &["ads.example"]).with_history(history.clone());
+
+        for name in ["ads.example.", "www.example.com.", "ads.example."] {
+            resolve(&handler, &request(name, RecordType::A, None)).await;
+        }
+
+        // The first query fell out of the history.
+        let entries = history.find("ADS.example.");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].verdict, Verdict::Block);
+        assert_eq!(entries[0].answer_count, 0);
+        let forwarded = &history.find("www.example.com")[0];
+        assert_eq!(forwarded.verdict, Verdict::Forward);
+        assert_eq!(forwarded.answer_count, 1);
+    }
+
+    #[tokio::test]
+    async fn blocks_answers_cloaked_behind_cnames() {
+        let upstream = FakeUpstream::new([
+            Scripted::Cname("metrics.tracker.example.", Ipv4Addr::new(192, 0, 2, 80)),
+            Scripted::Cname("cdn.example.net.", Ipv4Addr::new(192, 0, 2, 81)),
+            Scripted::Cname("Pixel.TRACKER.example.", Ipv4Addr::new(192, 0, 2, 82)),
+        ]);
+        let handler = handler(&upstream, &["tracker.example"]);
+
+        let (_, response) = resolve(
+            &handler,
+            &request("metrics.shop.example.", RecordType::A, None),
+        )
+        .await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(response.answers().is_empty());
+
+        let (_, response) = resolve(
+            &handler,
+            &request("static.shop.example.", RecordType::A, None),
+        )
+        .await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 2);
+
+        let (_, response) = resolve(
+            &handler,
+            &request("pixel.shop.example.", RecordType::A, None),
+        )
+        .await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn blocked_https_queries_can_answer_nodata() {
+        let upstream = FakeUpstream::new([]);
+        let handler = handler(&upstream, &["ads.example"])
+            .with_svcb_block_response(SvcbBlockResponse::Nodata);
+
+        let (_, response) =
+            resolve(&handler, &request("ads.example.", RecordType::HTTPS, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+
+        let (_, response) = resolve(&handler, &request("ads.example.", RecordType::A, None)).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_edns_versions() {
+        let upstream = FakeUpstream::new([]);
+        let handler = handler(&upstream, &[]);
+
+        let (_, response) = resolve(
+            &handler,
+            &request("www.example.com.", RecordType::A, Some(1)),
+        )
+        .await;
+
+        // BADVERS shares its code with BADSIG, which is what it decodes as.
+        assert_eq!(
+            u16::from(response.response_code()),
+            u16::from(ResponseCode::BADVERS)
+        );
+        assert_eq!(response.extensions().as_ref().map(Edns::version), Some(0));
+        assert_eq!(upstream.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn answers_edns_queries_with_edns() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 30))]);
+        let handler = handler(&upstream, &[]);
+
+        let (_, response) = resolve(
+            &handler,
+            &request("www.example.com.", RecordType::A, Some(0)),
+        )
+        .await;
+
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.extensions().is_some());
+    }
+
+    #[tokio::test]
+    async fn upstream_failures_are_reported_as_servfail() {
+        let upstream = FakeUpstream::new([Scripted::Error, Scripted::Timeout]);
+        let handler = handler(&upstream, &[]);
+
+        for _ in 0..2 {
+            let capture = Capture::default();
+            let info = handler
+                .handle_request(
+                    &request("www.example.com.", RecordType::A, None),
+                    capture.clone(),
+                )
+                .await;
+
+            assert_eq!(info.response_code(), ResponseCode::ServFail);
+            assert!(capture.0.lock().unwrap().is_empty());
+        }
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn attaches_retransmissions_to_the_original_query() {
+        let upstream = FakeUpstream::new([
+            Scripted::Slow(Ipv4Addr::new(192, 0, 2, 70), Duration::from_millis(50)),
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 71)),
+        ]);
+        let handler = handler(&upstream, &[]);
+        let request = request("www.example.com.", RecordType::A, None);
+
+        let (original, retransmission) =
+            tokio::join!(resolve(&handler, &request), resolve(&handler, &request));
+        assert_eq!(
+            answer_addresses(&original.1),
+            [IpAddr::from([192, 0, 2, 70])]
+        );
+        assert_eq!(
+            answer_addresses(&retransmission.1),
+            [IpAddr::from([192, 0, 2, 70])]
+        );
+        assert_eq!(upstream.calls(), 1);
+
+        // Once answered, the same query is forwarded again.
+        let (_, response) = resolve(&handler, &request).await;
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 71])]);
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn caches_answers() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 40))]);
+        let cache = Arc::new(ResponseCache::new(NonZeroUsize::new(16).unwrap()));
+        let handler = handler(&upstream, &[]).with_cache(cache);
+
+        for _ in 0..2 {
+            let (_, response) =
+                resolve(&handler, &request("www.example.com.", RecordType::A, None)).await;
+            assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 40])]);
+        }
+        assert_eq!(upstream.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn warms_up_names_not_blocked() {
+        let upstream = FakeUpstream::new([
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 42)),
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 42)),
+        ]);
+        let cache = Arc::new(ResponseCache::new(NonZeroUsize::new(16).unwrap()));
+        let handler = handler(&upstream, &["ads.example.com"]).with_cache(cache);
+
+        let names =
+            ["www.example.com.", "ads.example.com."].map(|it| Name::from_ascii(it).unwrap());
+        handler.warm_up(&names).await;
+        assert_eq!(upstream.calls(), 2);
+
+        let (_, response) =
+            resolve(&handler, &request("www.example.com.", RecordType::A, None)).await;
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 42])]);
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_truncated_answers() {
+        let upstream = FakeUpstream::new([
+            Scripted::Truncated(Ipv4Addr::new(192, 0, 2, 50)),
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 51)),
+        ]);
+        let cache = Arc::new(ResponseCache::new(NonZeroUsize::new(16).unwrap()));
+        let handler = handler(&upstream, &[]).with_cache(cache);
+
+        let (_, response) =
+            resolve(&handler, &request("www.example.com.", RecordType::A, None)).await;
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 50])]);
+
+        let (_, response) =
+            resolve(&handler, &request("www.example.com.", RecordType::A, None)).await;
+        assert_eq!(answer_addresses(&response), [IpAddr::from([192, 0, 2, 51])]);
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn applies_ttl_bounds() {
+        let upstream = FakeUpstream::new([Scripted::Answer(Ipv4Addr::new(192, 0, 2, 60))]);
+        let handler = handler(&upstream, &[]).with_ttl_bounds(None, Some(120));
+
+        let (_, response) =
+            resolve(&handler, &request("www.example.com.", RecordType::A, None)).await;
+        assert_eq!(response.answers()[0].ttl(), 120);
+    }
+
+    /// A request carrying the client subnet 198.51.100.0/24 and a device identifier, as some
+    /// home routers add.
+    fn request_with_subnet(name: &str) -> Request {
+        let mut edns = Edns::new();
+        edns.options_mut()
+            .insert(EdnsOption::Subnet(ClientSubnet::new(
+                IpAddr::from([198, 51, 100, 0]),
+                24,
+                0,
+            )));
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(65074, b"laptop".to_vec()));
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A))
+            .set_edns(edns);
+
+        let bytes = message.to_vec().unwrap();
+        let message = MessageRequest::read(&mut BinDecoder::new(&bytes)).unwrap();
+        Request::new(message, "192.0.2.1:53000".parse().unwrap(), Protocol::Udp)
+    }
+
+    #[tokio::test]
+    async fn strips_forwards_or_overrides_the_client_subnet() {
+        let upstream = FakeUpstream::new([
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 50)),
+            Scripted::Answer(Ipv4Addr::new(192, 0, 2, 51)),
+        ]);
+        let request = request_with_subnet("www.example.com.");
+        let subnet = |address: [u8; 4], prefix| {
+            EdnsOption::Subnet(ClientSubnet::new(IpAddr::from(address), prefix, 0))
+        };
+
+        let stripped = handler(&upstream, &[]);
+        assert!(stripped.upstream_options(&request).is_empty());
+        let overridden = handler(&upstream, &[]).with_ecs_policy(EcsPolicy::Override(
+            ClientSubnet::new(IpAddr::from([203, 0, 113, 0]), 24, 0),
+        ));
+        assert_eq!(
+            overridden.upstream_options(&request),
+            [subnet([203, 0, 113, 0], 24)]
+        );
+        assert_eq!(overridden.shared_options(), [subnet([203, 0, 113, 0], 24)]);
+
+        // Device identifiers are stripped even when the subnet is forwarded.
+        let cache = Arc::new(ResponseCache::new(NonZeroUsize::new(16).unwrap()));
+        let forwarded = handler(&upstream, &[])
+            .with_ecs_policy(EcsPolicy::Forward)
+            .with_cache(cache);
+        assert_eq!(
+            forwarded.upstream_options(&request),
+            [subnet([198, 51, 100, 0], 24)]
+        );
+        assert!(forwarded.shared_options().is_empty());
+        // Answers for a forwarded subnet are not cached for other clients.
+        for _ in 0..2 {
+            resolve(&forwarded, &request).await;
+        }
+        assert_eq!(upstream.calls(), 2);
+    }
+
+    #[test]
+    fn labels_only_known_identifying_options() {
+        assert_eq!(stripped_option_label(8), Some("8"));
+        assert_eq!(stripped_option_label(65074), Some("65074"));
+        assert_eq!(stripped_option_label(10), None);
+        assert_eq!(stripped_option_label(12), None);
+        assert_eq!(stripped_option_label(65000), Some("other"));
+    }
 }
@@ -0,0 +1,12 @@
+use std::path::Path;
+
+/// Writes `contents` to `path` through a temporary file next to it, so a crash never leaves a
+/// half written file.
+pub(crate) async fn write_atomically(
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
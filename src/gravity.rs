@@ -0,0 +1,167 @@
+#[cfg(feature = "gravity-db")]
+use crate::blocklist;
+#[cfg(feature = "gravity-db")]
+use rusqlite::{Connection, OpenFlags};
+use rustc_hash::FxHashSet;
+use std::path::Path;
+
+/// The domains of a Pi-hole (v5 or later) gravity database, usually /etc/pihole/gravity.db.
+///
+/// Block entries are those of the enabled adlists (gravity), the denied domains and the deny
+/// regexes matching a domain (`(^|\.)example\.com$`). Allow entries are the allowed domains,
+/// the allow regexes matching a domain and, since Pi-hole v6, those of allow lists
+/// (antigravity). Disabled entries are left out, and group assignments are ignored.
+#[derive(Debug, Default)]
+pub struct Gravity {
+    pub block: FxHashSet<String>,
+    pub allow: FxHashSet<String>,
+    /// Regexes that can't be taken over.
+    pub skipped: Vec<String>,
+}
+
+/// `type` values of the domainlist table.
+#[cfg(feature = "gravity-db")]
+const ALLOW: i64 = 0;
+#[cfg(feature = "gravity-db")]
+const DENY: i64 = 1;
+#[cfg(feature = "gravity-db")]
+const ALLOW_REGEX: i64 = 2;
+#[cfg(feature = "gravity-db")]
+const DENY_REGEX: i64 = 3;
+
+/// Whether `data` starts like an SQLite database file.
+pub fn is_database(data: &[u8]) -> bool {
+    data.starts_with(b"SQLite format 3\0")
+}
+
+impl Gravity {
+    /// Reads the database at `path` read-only, along with the changes still in its write-ahead
+    /// log.
+    #[cfg(feature = "gravity-db")]
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        Self::query(&connection)
+    }
+
+    #[cfg(not(feature = "gravity-db"))]
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        anyhow::bail!("{} needs advoid built with gravity-db", path.display())
+    }
+
+    #[cfg(feature = "gravity-db")]
+    fn query(connection: &Connection) -> anyhow::Result<Self> {
+        let has_table = |name: &str| -> rusqlite::Result<bool> {
+            connection
+                .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?
+                .exists([name])
+        };
+        anyhow::ensure!(has_table("domainlist")?, "not a Pi-hole gravity database");
+        let mut gravity = Gravity::default();
+
+        // Domains of adlists disabled since gravity was last updated stay in the table.
+        let enabled = match has_table("adlist")? {
+            true => "adlist_id NOT IN (SELECT id FROM adlist WHERE enabled = 0)",
+            false => "1",
+        };
+        for (name, block) in [("gravity", true), ("antigravity", false)] {
+            if !has_table(name)? {
+                continue;
+            }
+            let mut statement =
+                connection.prepare(&format!("SELECT domain FROM {} WHERE {}", name, enabled))?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                // Pi-hole v6 keeps AdBlock rules of lists as they are.
+                let entries = blocklist::parse_rule(row.get_ref(0)?.as_str()?);
+                match block {
+                    true => gravity.block.extend(entries),
+                    false => gravity.allow.extend(entries),
+                }
+            }
+        }
+
+        let mut statement =
+            connection.prepare("SELECT type, domain FROM domainlist WHERE enabled != 0")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let (kind, domain): (i64, String) = (row.get(0)?, row.get(1)?);
+            match kind {
+                ALLOW => gravity.allow.extend(blocklist::parse_rule(&domain)),
+                DENY => gravity.block.extend(blocklist::parse_rule(&domain)),
+                ALLOW_REGEX | DENY_REGEX => match regex_domain(&domain) {
+                    Some(it) if kind == ALLOW_REGEX => {
+                        gravity.allow.insert(format!("{}.", it));
+                    }
+                    Some(it) => {
+                        gravity.block.insert(format!("{}.", it));
+                    }
+                    None => gravity.skipped.push(domain),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(gravity)
+    }
+}
+
+/// The domain a Pi-hole regex matching a domain and its subdomains (`(^|\.)example\.com$`)
+/// stands for.
+pub(crate) fn regex_domain(regex: &str) -> Option<String> {
+    let domain = regex.strip_prefix(r"(^|\.)")?.strip_suffix('$')?;
+    let domain = domain.replace(r"\.", ".");
+    domain
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+        .then_some(domain)
+}
+
+#[cfg(all(test, feature = "gravity-db"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_lists_of_a_gravity_database() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                r#"
+                CREATE TABLE adlist (id INTEGER PRIMARY KEY AUTOINCREMENT, address TEXT,
+                    enabled BOOLEAN NOT NULL DEFAULT 1);
+                CREATE TABLE gravity (domain TEXT NOT NULL, adlist_id INTEGER NOT NULL);
+                CREATE TABLE domainlist (id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    type INTEGER NOT NULL DEFAULT 0, domain TEXT NOT NULL,
+                    enabled BOOLEAN NOT NULL DEFAULT 1, UNIQUE(domain, type));
+                INSERT INTO adlist (address, enabled)
+                    VALUES ('https://a.example/', 1), ('https://b.example/', 0);
+                INSERT INTO gravity VALUES
+                    ('ads.example', 1), ('||tracker.example^', 1), ('disabled.example', 2);
+                INSERT INTO domainlist (type, domain, enabled) VALUES
+                    (0, 'good.ads.example', 1),
+                    (1, 'denied.example', 1),
+                    (1, 'off.example', 0),
+                    (2, '(^|\.)cdn\.example$', 1),
+                    (3, '^ad[0-9]+\.example$', 1);
+                "#,
+            )
+            .unwrap();
+
+        let gravity = Gravity::query(&connection).unwrap();
+        let set = |domains: &[&str]| domains.iter().map(|it| it.to_string()).collect();
+        assert_eq!(
+            gravity.block,
+            set(&["ads.example.", "tracker.example.", "denied.example."])
+        );
+        assert_eq!(gravity.allow, set(&["good.ads.example.", "cdn.example."]));
+        assert_eq!(gravity.skipped, [r"^ad[0-9]+\.example$"]);
+
+        let not_pihole = Connection::open_in_memory().unwrap();
+        not_pihole
+            .execute_batch("CREATE TABLE other (domain TEXT)")
+            .unwrap();
+        assert!(Gravity::query(&not_pihole).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::clock::unix_time;
+use crate::querylog::QueryLog;
+use hickory_client::rr::{Name, RecordType};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Seconds the salt of hashed client addresses is kept for.
+const SALT_LIFETIME: u64 = 24 * 60 * 60;
+
+/// How much of the client address is kept with a past query.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum ClientPrivacy {
+    /// The whole address
+    #[default]
+    Off,
+    /// The /24 (IPv4) or /64 (IPv6) network of the address
+    Truncate,
+    /// A hash of the address, salted with a secret replaced every day
+    Hash,
+    /// Nothing
+    Omit,
+}
+
+/// What became of a query.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Forward,
+    Block,
+    Rewrite,
+    /// Answered from the local records.
+    Local,
+}
+
+impl Verdict {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Forward => "forward",
+            Verdict::Block => "block",
+            Verdict::Rewrite => "rewrite",
+            Verdict::Local => "local",
+        }
+    }
+}
+
+/// A past query as shown by the admin API.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    /// Lowercase, without the trailing dot.
+    pub name: String,
+    /// As kept by the client privacy option.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(rename = "type")]
+    pub query_type: String,
+    pub verdict: Verdict,
+    /// Records in the answer section.
+    pub answer_count: usize,
+    /// Milliseconds taken to come up with the answer.
+    pub duration_ms: u64,
+}
+
+/// The verdicts of the most recent queries, kept in memory.
+pub struct History {
+    capacity: usize,
+    privacy: ClientPrivacy,
+    /// The salt of hashed client addresses and when it was made.
+    salt: Mutex<(u64, RandomState)>,
+    entries: Mutex<VecDeque<HistoryEntry>>,
+    query_log: Option<QueryLog>,
+}
+
+impl History {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        History {
+            capacity: capacity.get(),
+            privacy: ClientPrivacy::Off,
+            salt: Mutex::new((0, RandomState::new())),
+            entries: Mutex::new(VecDeque::with_capacity(capacity.get())),
+            query_log: None,
+        }
+    }
+
+    pub fn with_client_privacy(mut self, privacy: ClientPrivacy) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
+    /// Writes every entry to `query_log` as well, with the client as kept in memory.
+    pub fn with_query_log(mut self, query_log: QueryLog) -> Self {
+        self.query_log = Some(query_log);
+        self
+    }
+
+    pub(crate) fn record(
+        &self,
+        name: &Name,
+        client: IpAddr,
+        query_type: RecordType,
+        verdict: Verdict,
+        answer_count: usize,
+        duration: Duration,
+    ) {
+        let time = unix_time().as_secs();
+        let entry = HistoryEntry {
+            time,
+            name: normalize(&name.to_string()),
+            client: self.anonymize(client, time),
+            query_type: query_type.to_string(),
+            verdict,
+            answer_count,
+            duration_ms: duration.as_millis() as u64,
+        };
+        if let Some(query_log) = &self.query_log {
+            query_log.log(&entry);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The entries for `name`, newest first.
+    pub fn find(&self, name: &str) -> Vec<HistoryEntry> {
+        let name = normalize(name);
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .filter(|it| it.name == name)
+            .cloned()
+            .collect()
+    }
+
+    fn anonymize(&self, client: IpAddr, time: u64) -> Option<String> {
+        match self.privacy {
+            ClientPrivacy::Off => Some(client.to_string()),
+            ClientPrivacy::Truncate => Some(truncate(client).to_string()),
+            ClientPrivacy::Hash => {
+                let mut salt = self.salt.lock().unwrap();
+                if time.saturating_sub(salt.0) >= SALT_LIFETIME {
+                    *salt = (time, RandomState::new());
+                }
+                Some(format!("{:016x}", salt.1.hash_one(client.to_canonical())))
+            }
+            ClientPrivacy::Omit => None,
+        }
+    }
+}
+
+/// The /24 or /64 network of `client`.
+fn truncate(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & 0xffff_ff00).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & !u128::from(u64::MAX)).into(),
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymizes_clients() {
+        let history = |privacy| History::new(NonZeroUsize::MIN).with_client_privacy(privacy);
+        let client = |it: &str| it.parse::<IpAddr>().unwrap();
+
+        let truncated = history(ClientPrivacy::Truncate);
+        assert_eq!(
+            truncated.anonymize(client("192.168.1.23"), 0).as_deref(),
+            Some("192.168.1.0")
+        );
+        assert_eq!(
+            truncated
+                .anonymize(client("2001:db8:1:2:3:4:5:6"), 0)
+                .as_deref(),
+            Some("2001:db8:1:2::")
+        );
+
+        let hashed = history(ClientPrivacy::Hash);
+        let first = hashed.anonymize(client("192.168.1.23"), 1);
+        assert_eq!(hashed.anonymize(client("192.168.1.23"), 2), first);
+        assert_ne!(hashed.anonymize(client("192.168.1.24"), 2), first);
+        assert_ne!(
+            hashed.anonymize(client("192.168.1.23"), 1 + SALT_LIFETIME),
+            first
+        );
+
+        assert_eq!(
+            history(ClientPrivacy::Omit).anonymize(client("192.168.1.23"), 0),
+            None
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, Request, Response, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::IntoResponse;
+use axum::Router;
+use lru::LruCache;
+use std::net::{IpAddr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tower_http::trace::TraceLayer;
+use tracing::Span;
+
+/// Clients tracked by `RateLimit` before the least recently seen one is forgotten.
+const MAX_TRACKED_CLIENTS: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// Guards `router` with a bearer token and a per-client limit of requests per second, when
+/// given, and adds access logs under the `advoid::access` target and
+/// `http_requests`/`http_request_duration_seconds` metrics labelled with `server`. The routes of
+/// `unauthenticated` check requests on their own and skip the token, but not the rate limit.
+/// Needs to be served with `ConnectInfo<SocketAddr>`.
+pub(crate) fn with_access_control(
+    router: Router,
+    unauthenticated: Router,
+    server: &'static str,
+    token: Option<&str>,
+    rate_limit: Option<NonZeroU32>,
+) -> Router {
+    let router = match token {
+        Some(token) => router.layer(middleware::from_fn_with_state(
+            Arc::<str>::from(format!("Bearer {}", token)),
+            require_token,
+        )),
+        None => router,
+    };
+    let router = router.merge(unauthenticated);
+    // Outside the token check, so guessing tokens is limited as well.
+    let router = match rate_limit {
+        Some(rate) => router.layer(middleware::from_fn_with_state(
+            Arc::new(RateLimit::new(rate)),
+            limit_rate,
+        )),
+        None => router,
+    };
+
+    let trace = TraceLayer::new_for_http()
+        .make_span_with(move |request: &Request<Body>| {
+            let span = tracing::info_span!(
+                target: "advoid::access",
+                "http",
+                server,
+                method = %request.method(),
+                uri = %request.uri(),
+                client = tracing::field::Empty,
+            );
+            if let Some(ConnectInfo(client)) = request.extensions().get::<ConnectInfo<SocketAddr>>()
+            {
+                span.record("client", tracing::field::display(client));
+            }
+            span
+        })
+        .on_request(())
+        .on_response(move |response: &Response<Body>, latency: Duration, _: &Span| {
+            let status = response.status();
+            metrics::counter!(
+                "http_requests",
+                "server" => server,
+                "status" => status.as_str().to_string()
+            )
+            .increment(1);
+            metrics::histogram!("http_request_duration_seconds", "server" => server)
+                .record(latency.as_secs_f64());
+
+            let status = status.as_u16();
+            match response.status() {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                    tracing::warn!(target: "advoid::access", status, ?latency, "unauthorized request")
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    tracing::warn!(target: "advoid::access", status, ?latency, "rate limited request")
+                }
+                _ => tracing::info!(target: "advoid::access", status, ?latency, "request"),
+            }
+        });
+    router.layer(trace)
+}
+
+/// A token bucket per client, refilled at `rate` requests per second and holding up to a
+/// second's worth.
+struct RateLimit {
+    rate: f64,
+    buckets: Mutex<LruCache<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimit {
+    fn new(rate: NonZeroU32) -> Self {
+        RateLimit {
+            rate: rate.get() as f64,
+            buckets: Mutex::new(LruCache::new(MAX_TRACKED_CLIENTS)),
+        }
+    }
+
+    fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let refill = |tokens: f64, last: Instant| {
+            (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.rate)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let (tokens, last) = buckets.get_or_insert_mut(client, || (self.rate, now));
+        *tokens = refill(*tokens, *last);
+        *last = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+async fn limit_rate(
+    State(limit): State<Arc<RateLimit>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next,
+) -> axum::response::Response {
+    if !limit.allow(client.ip(), Instant::now()) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "1")],
+            "too many requests\n",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+async fn require_token(
+    State(expected): State<Arc<str>>,
+    request: Request<Body>,
+    next: Next,
+) -> axum::response::Response {
+    let authorization = request.headers().get(header::AUTHORIZATION);
+    if !authorization.is_some_and(|it| constant_time_eq(it.as_bytes(), expected.as_bytes())) {
+        return (StatusCode::UNAUTHORIZED, "unauthorized\n").into_response();
+    }
+    next.run(request).await
+}
+
+/// Compares without returning early, so the time taken doesn't tell how much of a guessed token
+/// is right. Only the length may leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && std::hint::black_box(a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y))) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens_in_full() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secreT", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secre", b"Bearer secret"));
+        assert!(!constant_time_eq(b"", b"Bearer secret"));
+    }
+
+    #[test]
+    fn limits_each_client_to_its_rate() {
+        let limit = RateLimit::new(NonZeroU32::new(2).unwrap());
+        let (a, b) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let now = Instant::now();
+
+        assert!(limit.allow(a, now));
+        assert!(limit.allow(a, now));
+        assert!(!limit.allow(a, now));
+        assert!(limit.allow(b, now));
+        assert!(limit.allow(a, now + Duration::from_millis(500)));
+        assert!(!limit.allow(a, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn forgets_the_least_recently_seen_clients() {
+        let limit = RateLimit::new(NonZeroU32::new(1).unwrap());
+        let now = Instant::now();
+        let first = "10.0.0.0".parse().unwrap();
+        assert!(limit.allow(first, now));
+        assert!(!limit.allow(first, now));
+
+        for i in 1..=MAX_TRACKED_CLIENTS.get() as u32 {
+            assert!(limit.allow(IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + i)), now));
+        }
+        assert_eq!(
+            limit.buckets.lock().unwrap().len(),
+            MAX_TRACKED_CLIENTS.get()
+        );
+        // Evicted, so it starts over with a full bucket.
+        assert!(limit.allow(first, now));
+    }
+}
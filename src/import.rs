@@ -0,0 +1,347 @@
+use crate::blocklist::{self, ListKind};
+use crate::gravity::{regex_domain, Gravity};
+use rustc_hash::FxHashMap;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::warn;
+
+/// What `advoid import` reads the configuration of.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ImportSource {
+    /// A Pi-hole directory such as /etc/pihole
+    Pihole,
+    /// An AdGuard Home configuration file (AdGuardHome.yaml)
+    Adguard,
+}
+
+/// The lists and local records taken over from another blocker.
+///
+/// Remote lists are downloaded and merged into the block and allow lists, so they are only as
+/// fresh as the last import. Entries block and allow names along with their subdomains, as
+/// every advoid list entry does, and regular expressions other than domain matches
+/// (`(^|\.)example\.com$`) can't be taken over.
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub block: BTreeSet<String>,
+    pub allow: BTreeSet<String>,
+    /// Lines of a `--local-records` file.
+    pub local: Vec<String>,
+    /// What couldn't be taken over, and why.
+    pub skipped: Vec<String>,
+}
+
+impl Imported {
+    pub async fn read(source: ImportSource, path: &Path) -> anyhow::Result<Self> {
+        let mut imported = Imported::default();
+        let (block_lists, allow_lists) = match source {
+            ImportSource::Pihole => imported.read_pihole(path).await?,
+            ImportSource::Adguard => imported.read_adguard(&tokio::fs::read_to_string(path).await?),
+        };
+
+        for (url, kind) in block_lists
+            .into_iter()
+            .map(|it| (it, ListKind::Block))
+            .chain(allow_lists.into_iter().map(|it| (it, ListKind::Allow)))
+        {
+            match blocklist::get(url.clone(), kind, None).await {
+                Ok(entries) if kind == ListKind::Block => imported.block.extend(entries),
+                Ok(entries) => imported.allow.extend(entries),
+                Err(e) => {
+                    warn!("could not download {}: {}", url, e);
+                    imported.skipped.push(format!("{}: {}", url, e));
+                }
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Reads the gravity database of Pi-hole v5 and later, the plain files it kept its lists in
+    /// before (v4) and its local records (custom.list). Returns the urls of the block lists.
+    async fn read_pihole(&mut self, dir: &Path) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            async move { tokio::fs::read_to_string(path).await.ok() }
+        };
+        anyhow::ensure!(dir.is_dir(), "{} is not a Pi-hole directory", dir.display());
+
+        let urls: Vec<_> = read("adlists.list")
+            .await
+            .iter()
+            .flat_map(|it| it.lines())
+            .map(str::trim)
+            .filter(|it| !it.is_empty() && !it.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        // Holds the domains of the adlists as of the last gravity update, which are not
+        // downloaded again.
+        let path = dir.join("gravity.db");
+        if path.is_file() {
+            let gravity = Gravity::read(&path).map_err(|e| anyhow::anyhow!("gravity.db: {}", e))?;
+            self.block.extend(gravity.block);
+            self.allow.extend(gravity.allow);
+            self.skipped.extend(
+                gravity
+                    .skipped
+                    .into_iter()
+                    .map(|it| format!("gravity.db: {}", it)),
+            );
+        }
+
+        for name in ["blacklist.txt", "gravity.list"] {
+            if let Some(text) = read(name).await {
+                self.block.extend(blocklist::parse(&text));
+            }
+        }
+        if let Some(text) = read("whitelist.txt").await {
+            self.allow.extend(blocklist::parse(&text));
+        }
+        for (name, block) in [("regex.list", true), ("whitelist_regex.list", false)] {
+            for line in read(name).await.iter().flat_map(|it| it.lines()) {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match regex_domain(line) {
+                    Some(domain) if block => self.block.insert(format!("{}.", domain)),
+                    Some(domain) => self.allow.insert(format!("{}.", domain)),
+                    None => {
+                        self.skipped.push(format!("{}: {}", name, line));
+                        continue;
+                    }
+                };
+            }
+        }
+        if let Some(text) = read("custom.list").await {
+            self.local.extend(
+                text.lines()
+                    .map(str::trim)
+                    .filter(|it| !it.is_empty() && !it.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+
+        Ok((urls, Vec::new()))
+    }
+
+    /// Reads the filters, user rules and DNS rewrites of an AdGuard Home configuration.
+    /// Returns the urls of the enabled block and allow lists.
+    fn read_adguard(&mut self, yaml: &str) -> (Vec<String>, Vec<String>) {
+        let urls = |key| {
+            yaml_sequence(yaml, key)
+                .into_iter()
+                .filter(|it| it.get("enabled").is_some_and(|it| it == "true"))
+                .filter_map(|mut it| it.remove("url"))
+                .collect::<Vec<_>>()
+        };
+        let lists = (urls("filters"), urls("whitelist_filters"));
+
+        for rule in yaml_sequence(yaml, "user_rules")
+            .into_iter()
+            .filter_map(|mut it| it.remove(""))
+        {
+            let (allow, line) = match rule.strip_prefix("@@") {
+                Some(line) => (true, line),
+                None => (false, rule.as_str()),
+            };
+            let entries = blocklist::parse(line);
+            let skipped =
+                entries.is_empty() && !matches!(line.chars().next(), None | Some('!' | '#'));
+            match allow {
+                _ if skipped => self.skipped.push(format!("user_rules: {}", rule)),
+                true => self.allow.extend(entries),
+                false => self.block.extend(entries),
+            }
+        }
+
+        for rewrite in yaml_sequence(yaml, "rewrites") {
+            let (Some(domain), Some(answer)) = (rewrite.get("domain"), rewrite.get("answer"))
+            else {
+                continue;
+            };
+            match answer.as_str() {
+                // Answers kept from upstream for the type.
+                "A" | "AAAA" => continue,
+                answer if answer.parse::<IpAddr>().is_ok() => {
+                    self.local.push(format!("{} {}", answer, domain))
+                }
+                answer => self.local.push(format!("{} CNAME {}", domain, answer)),
+            }
+        }
+
+        lists
+    }
+
+    /// Writes the lists and local records to `dir`, returning the options using them, quoted for
+    /// the shell.
+    pub async fn write(&self, dir: &Path) -> anyhow::Result<Vec<String>> {
+        tokio::fs::create_dir_all(dir).await?;
+        let files = [
+            (
+                "block.txt",
+                "--block",
+                self.block.iter().cloned().collect::<Vec<_>>(),
+            ),
+            ("allow.txt", "--allow", self.allow.iter().cloned().collect()),
+            ("local-records.txt", "--local-records", self.local.clone()),
+        ];
+
+        let mut options = Vec::new();
+        for (name, option, lines) in files {
+            // advoid always needs a block list.
+            if lines.is_empty() && option != "--block" {
+                continue;
+            }
+            let path = dir.join(name);
+            let mut text = lines.join("\n");
+            text.push('\n');
+            tokio::fs::write(&path, text).await?;
+            options.push(format!(
+                "{} {}",
+                option,
+                shell_word(&path.display().to_string())
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// The items of the block sequence under `key` in `yaml`, as their `key: value` pairs, or
+/// under `""` for plain values. Only as much YAML as AdGuard Home writes is understood.
+fn yaml_sequence(yaml: &str, key: &str) -> Vec<FxHashMap<String, String>> {
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let header = format!("{}:", key);
+    let mut lines = yaml.lines().skip_while(|it| it.trim() != header);
+    let Some(first) = lines.next() else {
+        return Vec::new();
+    };
+    let key_indent = indent(first);
+
+    let mut items: Vec<FxHashMap<String, String>> = Vec::new();
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let item = trimmed
+            .strip_prefix("- ")
+            .or((trimmed == "-").then_some(""));
+        if indent(line) < key_indent || (indent(line) == key_indent && item.is_none()) {
+            break;
+        }
+
+        let pair = match item {
+            Some(item) => {
+                items.push(FxHashMap::default());
+                item
+            }
+            None => trimmed,
+        };
+        let Some(current) = items.last_mut() else {
+            break;
+        };
+        match pair
+            .split_once(": ")
+            .or(pair.strip_suffix(':').map(|it| (it, "")))
+        {
+            Some((name, value)) if !pair.starts_with(['\'', '"']) => {
+                current.insert(name.to_string(), unquote(value))
+            }
+            _ => current.insert(String::new(), unquote(pair)),
+        };
+    }
+    items
+}
+
+/// `word` quoted for a POSIX shell, unless it has nothing a shell would split or expand.
+fn shell_word(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    if let Some(value) = value
+        .strip_prefix('\'')
+        .and_then(|it| it.strip_suffix('\''))
+    {
+        return value.replace("''", "'");
+    }
+    if let Some(value) = value.strip_prefix('"').and_then(|it| it.strip_suffix('"')) {
+        return value.replace("\\\"", "\"").replace("\\\\", "\\");
+    }
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_over_adguard_home_rules_and_rewrites() {
+        let yaml = "\
+filters:
+  - enabled: true
+    url: https://adguardteam.github.io/HostlistsRegistry/assets/filter_1.txt
+    name: AdGuard DNS filter
+    id: 1
+  - enabled: false
+    url: https://example.com/disabled.txt
+whitelist_filters: []
+user_rules:
+  - '||ads.example^'
+  - '@@||good.example^'
+  - '! comment'
+  - /tracker[0-9]+\\.example/
+filtering:
+  rewrites:
+    - domain: nas.home
+      answer: 192.168.1.10
+    - domain: '*.lab.home'
+      answer: nas.home
+    - domain: ipv4.home
+      answer: A
+";
+        let mut imported = Imported::default();
+        let (block, allow) = imported.read_adguard(yaml);
+
+        assert_eq!(
+            block,
+            ["https://adguardteam.github.io/HostlistsRegistry/assets/filter_1.txt"]
+        );
+        assert!(allow.is_empty());
+        assert_eq!(imported.block, BTreeSet::from(["ads.example.".to_string()]));
+        assert_eq!(
+            imported.allow,
+            BTreeSet::from(["good.example.".to_string()])
+        );
+        assert_eq!(
+            imported.local,
+            ["192.168.1.10 nas.home", "*.lab.home CNAME nas.home"]
+        );
+        assert_eq!(imported.skipped.len(), 1);
+
+        assert_eq!(
+            regex_domain(r"(^|\.)doubleclick\.net$").as_deref(),
+            Some("doubleclick.net")
+        );
+        assert_eq!(regex_domain(r"^ad[0-9]+\.example\.com$"), None);
+    }
+
+    #[test]
+    fn quotes_paths_for_the_shell() {
+        assert_eq!(shell_word("/etc/advoid/block.txt"), "/etc/advoid/block.txt");
+        assert_eq!(
+            shell_word("/home/me/My Lists/block.txt"),
+            "'/home/me/My Lists/block.txt'"
+        );
+        assert_eq!(
+            shell_word("/tmp/it's/block.txt"),
+            r"'/tmp/it'\''s/block.txt'"
+        );
+        assert_eq!(shell_word(""), "''");
+    }
+}
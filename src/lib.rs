@@ -1,4 +1,28 @@
+pub mod acme;
+pub mod admin;
 pub mod blocklist;
+pub mod cache;
+pub mod capture;
+pub mod clock;
+pub mod cookie;
 pub mod dns;
+mod fs;
+pub mod gravity;
+pub mod history;
+mod http;
+pub mod import;
+pub mod limits;
+pub mod local;
 pub mod metrics;
+pub mod overrides;
+pub mod plugin;
+pub mod querylog;
+pub mod resume;
+pub mod schedule;
+#[cfg(feature = "policy-script")]
+pub mod script;
+pub mod snapshot;
+pub mod suffix;
 pub mod trace;
+pub mod upstream;
+pub mod zone;
@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+/// Limits this high mean there is none (cgroup v1 reports the largest page aligned i64).
+const UNLIMITED: u64 = 1 << 60;
+
+/// Rough memory taken by an answer in the response cache, in bytes.
+pub const CACHE_ENTRY_SIZE: u64 = 1024;
+
+/// Rough memory taken by a remembered block decision, in bytes.
+pub const CHECKED_ENTRY_SIZE: u64 = 128;
+
+/// The memory limit of the cgroup advoid runs in, as set for a container, if there is one.
+pub fn memory_limit() -> Option<u64> {
+    let cgroup = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let own = cgroup
+        .lines()
+        .find_map(|it| it.strip_prefix("0::"))
+        .map(|it| Path::new("/sys/fs/cgroup").join(it.trim_start_matches('/')));
+    let candidates = own.into_iter().map(|it| it.join("memory.max")).chain([
+        PathBuf::from("/sys/fs/cgroup/memory.max"),
+        PathBuf::from("/sys/fs/cgroup/memory/memory.limit_in_bytes"),
+    ]);
+    candidates
+        .filter_map(|it| std::fs::read_to_string(it).ok())
+        .find_map(|it| parse_limit(&it))
+}
+
+fn parse_limit(text: &str) -> Option<u64> {
+    text.trim().parse().ok().filter(|it| *it < UNLIMITED)
+}
+
+/// Entries of `entry_size` bytes fitting in `percent` of `limit`, at least one.
+pub fn entries(limit: u64, percent: u8, entry_size: u64) -> usize {
+    (limit / 100 * u64::from(percent) / entry_size).max(1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cgroup_memory_limits() {
+        assert_eq!(parse_limit("268435456\n"), Some(256 << 20));
+        assert_eq!(parse_limit("max\n"), None);
+        assert_eq!(parse_limit("9223372036854771712\n"), None);
+
+        assert_eq!(entries(256 << 20, 5, CACHE_ENTRY_SIZE), 13107);
+        assert_eq!(entries(1024, 1, CACHE_ENTRY_SIZE), 1);
+    }
+}
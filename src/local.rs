@@ -0,0 +1,216 @@
+use crate::zone::{follow_cnames, records_or_wildcard};
+use hickory_client::rr::{Name, RData, Record, RecordType};
+use hickory_proto::rr::rdata::{A, AAAA, CNAME, PTR, TXT};
+use rustc_hash::FxHashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// TTL of local records.
+const TTL: u32 = 60;
+
+/// Records answered by advoid itself, before the block lists or upstreams are consulted.
+///
+/// Read from a file with one record per line, either hosts file style
+/// (`192.168.1.10 nas.home nas`) or as `<NAME> <TYPE> <VALUE>` with a type of `A`, `AAAA`,
+/// `CNAME` or `TXT` (`files.home CNAME nas.home`). Blank lines and `#` comments are skipped.
+///
+/// A name starting with `*.` (`10.0.0.5 *.lab.home`) answers for every name under it without
+/// local records of its own.
+///
+/// Private addresses also get a PTR record pointing back at the first name given for them, so
+/// reverse lookups for the LAN are answered too.
+#[derive(Default)]
+pub struct LocalRecords {
+    records: FxHashMap<Name, Vec<Record>>,
+}
+
+impl LocalRecords {
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = tokio::fs::read_to_string(path).await?;
+        LocalRecords::parse(&text).map_err(|e| anyhow::anyhow!("{}:{}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut local = LocalRecords::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let records =
+                parse_line(line).map_err(|e| anyhow::anyhow!("{}: {}: {}", i + 1, e, line))?;
+            for record in records {
+                local.insert(record);
+            }
+        }
+        Ok(local)
+    }
+
+    fn insert(&mut self, record: Record) {
+        if let Some(address) = record.data().and_then(RData::ip_addr) {
+            let reverse = Name::from(address);
+            if is_private(address)
+                && !record.name().is_wildcard()
+                && !self.records.contains_key(&reverse)
+            {
+                let ptr = RData::PTR(PTR(record.name().clone()));
+                self.records
+                    .insert(reverse.clone(), vec![Record::from_rdata(reverse, TTL, ptr)]);
+            }
+        }
+
+        let records = self.records.entry(record.name().clone()).or_default();
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    /// Names with local records.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The answer to a `query_type` query for `name`, following CNAMEs to other local names,
+    /// or `None` if `name` has no local records. Names with records of other types only get
+    /// an empty answer, and CNAMEs to names that aren't local are answered as they are.
+    pub(crate) fn lookup(&self, name: &Name, query_type: RecordType) -> Option<Vec<Record>> {
+        let records_of = |it: &Name| records_or_wildcard(&self.records, it, |_| false);
+        let (answers, _) = follow_cnames(name.to_lowercase(), query_type, records_of)?;
+        Some(answers)
+    }
+}
+
+/// Addresses that only have a meaning within the LAN, whose reverse lookups upstreams can't
+/// answer anyway.
+pub(crate) fn is_private(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_loopback(),
+    }
+}
+
+fn parse_line(line: &str) -> anyhow::Result<Vec<Record>> {
+    let mut fields = line.split_whitespace();
+    let first = fields.next().unwrap_or_default();
+
+    if let Ok(address) = first.parse::<IpAddr>() {
+        let rdata = match address {
+            IpAddr::V4(ip) => RData::A(A(ip)),
+            IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+        };
+        let names: Vec<_> = fields.map(parse_name).collect::<anyhow::Result<_>>()?;
+        anyhow::ensure!(!names.is_empty(), "no names for the address");
+        return Ok(names
+            .into_iter()
+            .map(|name| Record::from_rdata(name, TTL, rdata.clone()))
+            .collect());
+    }
+
+    let name = parse_name(first)?;
+    let rest = line[first.len()..].trim_start();
+    let (record_type, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let (record_type, value) = (record_type.to_ascii_uppercase(), value.trim());
+    anyhow::ensure!(!value.is_empty(), "no value");
+    let rdata = match record_type.as_str() {
+        "A" => RData::A(A(value.parse()?)),
+        "AAAA" => RData::AAAA(AAAA(value.parse()?)),
+        "CNAME" => RData::CNAME(CNAME(parse_name(value)?)),
+        "TXT" => RData::TXT(TXT::new(vec![value
+            .strip_prefix('"')
+            .and_then(|it| it.strip_suffix('"'))
+            .unwrap_or(value)
+            .to_string()])),
+        _ => anyhow::bail!("unsupported record type {}", record_type),
+    };
+    Ok(vec![Record::from_rdata(name, TTL, rdata)])
+}
+
+fn parse_name(name: &str) -> anyhow::Result<Name> {
+    let mut name = Name::from_str(name)?.to_lowercase();
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_local_names_and_follows_cnames() {
+        let local = LocalRecords::parse(
+            "# homelab\n\
+             192.168.1.10 nas.home nas\n\
+             nas.home   AAAA  fd00::10\n\
+             files.home CNAME NAS.home.\n\
+             nas.home TXT \"backup target\"\n",
+        )
+        .unwrap();
+        let name = |it| Name::from_str(it).unwrap();
+        let types = |records: Vec<Record>| {
+            records
+                .iter()
+                .map(|it| it.record_type())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(local.len(), 5);
+        assert_eq!(
+            types(local.lookup(&name("NAS.home."), RecordType::A).unwrap()),
+            [RecordType::A]
+        );
+        assert_eq!(
+            types(
+                local
+                    .lookup(&name("files.home."), RecordType::AAAA)
+                    .unwrap()
+            ),
+            [RecordType::CNAME, RecordType::AAAA]
+        );
+        assert_eq!(
+            local.lookup(&name("nas."), RecordType::MX).unwrap(),
+            Vec::new()
+        );
+        assert!(local.lookup(&name("other.home."), RecordType::A).is_none());
+
+        let ptr = local
+            .lookup(&name("10.1.168.192.in-addr.arpa."), RecordType::PTR)
+            .unwrap();
+        assert_eq!(ptr[0].data(), Some(&RData::PTR(PTR(name("nas.home.")))));
+        let wildcard = LocalRecords::parse(
+            "10.0.0.5 *.lab.home\n\
+             10.0.0.6 nas.lab.home\n\
+             files.home CNAME files.lab.home\n",
+        )
+        .unwrap();
+        let answers = wildcard
+            .lookup(&name("files.home."), RecordType::A)
+            .unwrap();
+        assert_eq!(answers[1].name(), &name("files.lab.home."));
+        assert_eq!(
+            answers[1].data(),
+            Some(&RData::A(A("10.0.0.5".parse().unwrap())))
+        );
+        let answers = wildcard
+            .lookup(&name("NAS.lab.home."), RecordType::A)
+            .unwrap();
+        assert_eq!(
+            answers[0].data(),
+            Some(&RData::A(A("10.0.0.6".parse().unwrap())))
+        );
+        assert!(wildcard.lookup(&name("lab.home."), RecordType::A).is_none());
+        assert!(wildcard
+            .lookup(&name("5.0.0.10.in-addr.arpa."), RecordType::PTR)
+            .is_none());
+
+        let public = LocalRecords::parse("192.0.2.1 www.home").unwrap();
+        assert!(public
+            .lookup(&name("1.2.0.192.in-addr.arpa."), RecordType::PTR)
+            .is_none());
+        assert!(LocalRecords::parse("nas.home MX 10 mail.home").is_err());
+    }
+}
@@ -1,66 +1,1025 @@
-use advoid::dns::StubRequestHandler;
-use clap::Parser;
-use hickory_client::client::AsyncClient;
-use hickory_client::udp::UdpClientStream;
+use advoid::acme::AcmeChallenges;
+use advoid::admin::AdminServer;
+#[cfg(feature = "remote-blocklist")]
+use advoid::admin::Webhook;
+use advoid::blocklist::{ListKind, ListSource};
+use advoid::cache::ResponseCache;
+use advoid::capture::WireCapture;
+use advoid::clock::spawn_clock_check;
+use advoid::cookie::CookieMode;
+use advoid::dns::{EcsPolicy, RebindProtection, StubRequestHandler, SvcbBlockResponse};
+use advoid::history::{ClientPrivacy, History};
+use advoid::import::{ImportSource, Imported};
+use advoid::limits;
+use advoid::local::LocalRecords;
+use advoid::overrides::Overrides;
+use advoid::plugin::PluginRegistry;
+use advoid::querylog::QueryLog;
+use advoid::resume::spawn_resume_watch;
+use advoid::schedule::{Schedule, ScheduledLists};
+#[cfg(feature = "policy-script")]
+use advoid::script::PolicyScript;
+use advoid::suffix::{Matching, TYPE_OPTION};
+use advoid::upstream::{
+    spawn_health_checks, Transport, Upstream, UpstreamClient, UpstreamDiff, UpstreamGroup,
+};
+use advoid::zone::Zones;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use hickory_proto::rr::rdata::opt::ClientSubnet;
+use hickory_proto::rr::Name;
 use hickory_server::ServerFuture;
-use std::net::SocketAddr;
+use ipnet::IpNet;
+#[cfg(feature = "encrypted-upstream")]
+use reqwest::Url;
+use rustc_hash::FxHashSet;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{AddrParseError, SocketAddr};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Take over the lists and local records of Pi-hole or AdGuard Home
+    Import(ImportArgs),
+}
+
+#[derive(Args, Debug)]
+struct ImportArgs {
+    /// Blocker the configuration is read from
+    #[clap(long, value_enum)]
+    from: ImportSource,
+
+    /// Pi-hole directory (e.g. /etc/pihole) or AdGuard Home configuration file
+    path: PathBuf,
+
+    /// Directory the block list, allow list and local records are written to
+    #[clap(long, default_value = ".")]
+    out: PathBuf,
+}
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[cfg_attr(
+    feature = "encrypted-upstream",
+    clap(group(ArgGroup::new("upstreams").required(true).args(["upstream", "upstream_tls", "upstream_https"])))
+)]
+#[cfg_attr(
+    not(feature = "encrypted-upstream"),
+    clap(group(ArgGroup::new("upstreams").required(true).args(["upstream"])))
+)]
 struct Cli {
-    /// Bind address
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Bind address, listened on over UDP and TCP
+    #[clap(long, required = true)]
+    bind: Option<SocketAddr>,
+
+    /// Receive buffer size (SO_RCVBUF) of the listening UDP socket in bytes
+    #[clap(long)]
+    udp_recv_buffer: Option<usize>,
+
+    /// Send buffer size (SO_SNDBUF) of the listening UDP socket in bytes
+    #[clap(long)]
+    udp_send_buffer: Option<usize>,
+
+    /// Upstream address, tried in the given order when repeated
+    #[clap(long)]
+    upstream: Vec<SocketAddr>,
+
+    /// Protocol used to reach the upstream address
+    #[clap(long, value_enum, default_value = "udp", requires = "upstream")]
+    upstream_protocol: UpstreamProtocol,
+
+    /// DNS-over-TLS upstream address
+    #[cfg(feature = "encrypted-upstream")]
+    #[clap(long, requires = "upstream_tls_name")]
+    upstream_tls: Option<SocketAddr>,
+
+    /// Server name of the DNS-over-TLS or DNS-over-QUIC upstream
+    #[cfg(feature = "encrypted-upstream")]
+    #[clap(long, required_if_eq("upstream_protocol", "quic"))]
+    upstream_tls_name: Option<String>,
+
+    /// DNS-over-HTTPS upstream url
+    #[cfg(feature = "encrypted-upstream")]
+    #[clap(long)]
+    upstream_https: Option<Url>,
+
+    /// Bootstrap address used to connect to the DNS-over-HTTPS upstream
+    #[cfg(feature = "encrypted-upstream")]
+    #[clap(long, requires = "upstream_https")]
+    upstream_https_bootstrap: Option<std::net::IpAddr>,
+
+    /// Name used in metrics for each upstream (e.g. cloudflare), given in the order of the upstreams
+    #[clap(long)]
+    upstream_name: Vec<String>,
+
+    /// Forward queries under a domain to other upstreams (e.g. corp.example.com=10.0.0.53:53,10.0.0.54:53)
+    #[clap(long, value_parser = parse_forward)]
+    forward: Vec<(Name, Vec<SocketAddr>)>,
+
+    /// Also send queries under a domain to every upstream and report differing answers (repeatable)
+    #[clap(long)]
+    upstream_diff: Vec<Name>,
+
+    /// pcap file the wire format of sampled upstream queries and answers is written to
+    #[clap(long)]
+    capture_file: Option<PathBuf>,
+
+    /// Fraction (0 to 1) of the upstream queries written to --capture-file
+    #[clap(long, default_value_t = 0.01, value_parser = parse_fraction, requires = "capture_file")]
+    capture_sample_rate: f64,
+
+    /// Also write every upstream query under a domain to --capture-file (repeatable)
+    #[clap(long, requires = "capture_file")]
+    capture_zone: Vec<Name>,
+
+    /// Size in MiB --capture-file is rotated to <FILE>.1 at
+    #[clap(long, default_value_t = 16, requires = "capture_file")]
+    capture_max_size: u64,
+
+    /// Randomize the query name case sent to UDP upstreams (DNS 0x20)
+    #[clap(long)]
+    upstream_0x20: bool,
+
+    /// Number of connections opened to each upstream
+    #[clap(long, default_value_t = 1)]
+    upstream_connections: usize,
+
+    /// Interval in seconds between upstream health checks
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    health_check_interval: u64,
+
+    /// Seconds the clock may jump ahead, e.g. on resume from sleep, before upstream connections
+    /// are reset and cached answers aged, 0 to disable
+    #[clap(long, default_value_t = 30)]
+    resume_threshold: u64,
+
+    /// NTP server (host:port) the system clock is compared with every 10 minutes, warning when
+    /// it is off by more than 2 seconds
+    #[clap(long)]
+    ntp_server: Option<String>,
+
+    /// Seconds an unhealthy upstream is skipped for
+    #[clap(long, default_value_t = 30)]
+    upstream_cooldown: u64,
+
+    /// What to do with the EDNS Client Subnet option of queries sent upstream
+    #[clap(long, value_enum, default_value = "strip")]
+    ecs: EcsMode,
+
+    /// Client subnet sent upstream when --ecs is override (e.g. 203.0.113.0/24)
+    #[clap(long, required_if_eq("ecs", "override"))]
+    ecs_subnet: Option<ClientSubnet>,
+
+    /// Raise TTLs of upstream answers below this many seconds
+    #[clap(long)]
+    min_ttl: Option<u32>,
+
+    /// Lower TTLs of upstream answers above this many seconds
+    #[clap(long)]
+    max_ttl: Option<u32>,
+
+    /// Largest UDP answer in bytes, lowered to the payload size of EDNS clients; larger answers
+    /// set TC so the client retries over TCP
+    #[clap(long, default_value_t = 1232, value_parser = clap::value_parser!(u16).range(512..))]
+    max_udp_size: u16,
+
+    /// Maximum number of block decisions remembered, so each name is matched only once
+    /// [default: 100000, or --cache-memory-percent of a container's memory limit]
+    #[clap(long)]
+    checked_cache_size: Option<NonZeroUsize>,
+
+    /// Maximum number of answers kept in the response cache, 0 to disable it
+    /// [default: 10000, or --cache-memory-percent of a container's memory limit]
     #[clap(long)]
-    bind: SocketAddr,
+    cache_size: Option<usize>,
 
-    /// Upstream address
+    /// Percentage of the cgroup memory limit each of the caches is sized to when running in a
+    /// container and their size isn't given
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=100))]
+    cache_memory_percent: u8,
+
+    /// Number of recent query verdicts kept for the admin API, 0 to keep none
+    #[clap(long, default_value_t = 0)]
+    history_size: usize,
+
+    /// How much of the client addresses the history keeps
+    #[clap(long, value_enum, default_value = "off")]
+    client_privacy: ClientPrivacy,
+
+    /// SQLite database every query kept in the history is written to as well
+    #[clap(long)]
+    query_log: Option<PathBuf>,
+
+    /// Days entries are kept in the query log
+    #[clap(long, default_value_t = 7, value_parser = clap::value_parser!(u64).range(1..))]
+    query_log_retention: u64,
+
+    /// File the response and decision caches are saved to on shutdown and restored from on startup
     #[clap(long)]
-    upstream: SocketAddr,
+    snapshot: Option<PathBuf>,
+
+    /// File or url listing domains resolved into the cache before the listener starts
+    #[clap(long)]
+    warm_up: Option<String>,
+
+    /// Refresh cached answers hit at least this many times shortly before they expire
+    #[clap(long)]
+    cache_prefetch: Option<u64>,
 
     /// Prometheus exporter endpoint
+    #[clap(long, required = true)]
+    exporter: Option<SocketAddr>,
+
+    /// Add the scrape time to every sample served by the exporter
+    #[clap(long)]
+    metrics_timestamps: bool,
+
+    /// File holding the bearer token scrapers must send to the exporter
+    #[clap(long)]
+    exporter_token_file: Option<PathBuf>,
+
+    /// Admin API endpoint (cache inspection and flush)
     #[clap(long)]
-    exporter: SocketAddr,
+    admin: Option<SocketAddr>,
+
+    /// File holding the bearer token admin API requests must carry
+    #[clap(long, requires = "admin")]
+    admin_token_file: Option<PathBuf>,
+
+    /// Requests per second each client may make to the admin API, 0 for no limit
+    #[clap(long, default_value_t = 10, requires = "admin")]
+    admin_rate_limit: u32,
+
+    /// Zone whose ACME DNS-01 challenges (TXT records) can be presented through the admin API
+    /// (repeatable, needs --admin-token-file)
+    #[clap(long, requires = "admin_token_file")]
+    acme_zone: Vec<Name>,
+
+    /// File domains blocked or allowed through the admin API are saved to, overriding the lists
+    /// (needs --admin-token-file)
+    #[clap(long, requires = "admin_token_file")]
+    overrides_file: Option<PathBuf>,
+
+    /// File holding the secret webhooks refreshing the lists through the admin API are signed with
+    #[cfg(feature = "remote-blocklist")]
+    #[clap(long, requires = "admin")]
+    webhook_secret_file: Option<PathBuf>,
 
     /// Block file path or url
+    #[clap(long, required = true)]
+    block: Option<String>,
+
+    /// Allow file path or url, taking precedence over the block file
     #[clap(long)]
-    block: String,
+    allow: Option<String>,
+
+    /// Allow file path or url only logged (`advoid::candidate`) and counted for the blocked
+    /// queries it would let through, to try it out before using it with --allow
+    #[clap(long)]
+    candidate_allow: Option<String>,
+
+    /// Hosts style file of local A/AAAA/CNAME/TXT records answered before the lists and
+    /// upstreams are consulted
+    #[clap(long)]
+    local_records: Option<PathBuf>,
+
+    /// File or url of domains blocked only while a schedule applies (repeatable, e.g.
+    /// mon-fri@09:00-17:00=/etc/advoid/streaming.txt)
+    #[clap(long, value_parser = parse_scheduled_block)]
+    scheduled_block: Vec<(Schedule, String)>,
+
+    /// Time zone of the --scheduled-block schedules as an offset from UTC, e.g. +09:00
+    #[clap(long, default_value = "+00:00", value_parser = parse_utc_offset, allow_hyphen_values = true)]
+    schedule_utc_offset: i64,
+
+    /// Zone answered authoritatively from a zone file instead of being forwarded (repeatable,
+    /// e.g. home.arpa=/etc/advoid/home.arpa.zone)
+    #[clap(long, value_parser = parse_zone)]
+    zone: Vec<(Name, PathBuf)>,
+
+    /// Match list entries against the end of names regardless of label boundaries, so
+    /// `ads.example` also blocks `notads.example` as older versions did
+    #[clap(long)]
+    suffix_matching: bool,
+
+    /// Networks clients may query from, refusing the rest (comma separated or repeatable,
+    /// e.g. 192.168.0.0/16,fd00::/8)
+    #[clap(long, value_delimiter = ',')]
+    allow_from: Vec<IpNet>,
+
+    /// Networks whose clients are refused, even within --allow-from (comma separated or
+    /// repeatable)
+    #[clap(long, value_delimiter = ',')]
+    deny_from: Vec<IpNet>,
+
+    /// Clients whose queries get their own lists, first match wins (e.g. kids=192.168.1.0/25)
+    #[clap(long, value_parser = parse_client_group)]
+    client_group: Vec<(String, Vec<IpNet>)>,
+
+    /// Block file path or url of a client group, instead of --block (e.g. kids=kids-block.txt)
+    #[clap(long, value_parser = parse_group_list)]
+    group_block: Vec<(String, String)>,
+
+    /// Allow file path or url of a client group, instead of --allow
+    #[clap(long, value_parser = parse_group_list)]
+    group_allow: Vec<(String, String)>,
+
+    /// Directory the last download of each list url is kept in, used when it has not changed or
+    /// can't be downloaded
+    #[clap(long)]
+    list_cache: Option<PathBuf>,
+
+    /// Answer to blocked HTTPS/SVCB queries
+    #[clap(long, value_enum, default_value = "nxdomain")]
+    svcb_block_response: SvcbBlockResponse,
+
+    /// What to do with upstream answers leading names to private addresses (DNS rebinding)
+    #[clap(long, value_enum, default_value = "off")]
+    rebind_protection: RebindProtection,
+
+    /// Domain whose answers may lead to private addresses despite --rebind-protection
+    /// (repeatable); forwarded domains always may
+    #[clap(long)]
+    rebind_allow: Vec<Name>,
+
+    /// What to do with DNS Cookies (RFC 7873) of clients
+    #[clap(long, value_enum, default_value = "off")]
+    cookies: CookieMode,
+
+    /// Address of a server explaining blocks, answered to blocked A/AAAA queries (repeatable)
+    #[clap(long)]
+    block_page: Vec<std::net::IpAddr>,
+
+    /// Rhai script whose policy(client, name, type) function decides each query
+    #[cfg(feature = "policy-script")]
+    #[clap(long)]
+    policy_script: Option<PathBuf>,
+
+    /// Time limit in milliseconds for a single policy script call
+    #[cfg(feature = "policy-script")]
+    #[clap(long, default_value_t = 10)]
+    policy_script_timeout: u64,
+
+    /// Enable a compiled-in plugin (<NAME> or <NAME>=<ARG>), run in the given order when repeated
+    #[clap(long)]
+    plugin: Vec<String>,
 
     /// OTel endpoint
+    #[cfg(feature = "otel")]
     #[clap(long)]
     otel: Option<String>,
+
+    /// Serve task and resource events to tokio-console (on 127.0.0.1:6669, or TOKIO_CONSOLE_BIND)
+    #[cfg(feature = "tokio-console")]
+    #[clap(long)]
+    tokio_console: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum UpstreamProtocol {
+    /// Plain DNS over UDP, retried over TCP when truncated
+    Udp,
+    /// DNS-over-QUIC, falling back to UDP on port 53 when the handshake fails
+    #[cfg(feature = "encrypted-upstream")]
+    Quic,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum EcsMode {
+    /// Remove the client subnet before forwarding
+    Strip,
+    /// Pass the client subnet through unchanged
+    Forward,
+    /// Replace the client subnet with --ecs-subnet
+    Override,
+}
+
+fn parse_forward(s: &str) -> Result<(Name, Vec<SocketAddr>), String> {
+    let (zone, addrs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <DOMAIN>=<UPSTREAM>[,<UPSTREAM>...]: {}", s))?;
+    let zone = Name::from_utf8(zone).map_err(|e| e.to_string())?;
+    let addrs = addrs
+        .split(',')
+        .map(|it| it.trim().parse())
+        .collect::<Result<_, AddrParseError>>()
+        .map_err(|e| e.to_string())?;
+    Ok((zone, addrs))
+}
+
+fn parse_scheduled_block(s: &str) -> Result<(Schedule, String), String> {
+    let (schedule, list) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <SCHEDULE>=<FILE|URL>: {}", s))?;
+    Ok((schedule.parse()?, list.to_string()))
+}
+
+fn parse_utc_offset(s: &str) -> Result<i64, String> {
+    let (sign, offset) = match s.split_at_checked(1) {
+        Some(("+", offset)) => (1, offset),
+        Some(("-", offset)) => (-1, offset),
+        _ => return Err(format!("expected +HH:MM or -HH:MM: {}", s)),
+    };
+    let (hours, minutes) = offset
+        .split_once(':')
+        .and_then(|(hours, minutes)| {
+            Some((hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?))
+        })
+        .filter(|(hours, minutes)| *hours <= 14 && *minutes < 60)
+        .ok_or_else(|| format!("expected +HH:MM or -HH:MM: {}", s))?;
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("expected a number from 0 to 1: {}", s)),
+    }
+}
+
+fn parse_zone(s: &str) -> Result<(Name, PathBuf), String> {
+    let (zone, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <ZONE>=<FILE>: {}", s))?;
+    let mut zone = Name::from_utf8(zone).map_err(|e| e.to_string())?;
+    zone.set_fqdn(true);
+    Ok((zone, PathBuf::from(path)))
+}
+
+fn parse_client_group(s: &str) -> Result<(String, Vec<IpNet>), String> {
+    let (name, networks) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <NAME>=<CIDR>[,<CIDR>...]: {}", s))?;
+    let networks = networks
+        .split(',')
+        .map(|it| it.trim().parse())
+        .collect::<Result<_, ipnet::AddrParseError>>()
+        .map_err(|e| e.to_string())?;
+    Ok((name.to_string(), networks))
+}
+
+fn parse_group_list(s: &str) -> Result<(String, String), String> {
+    let (name, list) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <NAME>=<FILE>: {}", s))?;
+    Ok((name.to_string(), list.to_string()))
+}
+
+/// The list given for a client group, if any.
+fn group_list<'a>(lists: &'a [(String, String)], group: &str) -> Option<&'a str> {
+    lists
+        .iter()
+        .find(|(name, _)| name == group)
+        .map(|(_, list)| list.as_str())
+}
+
+/// Reads a token or secret kept in a file, without the trailing newline editors tend to add.
+fn read_secret(path: Option<&Path>) -> anyhow::Result<Option<String>> {
+    match path {
+        Some(path) => {
+            let secret = std::fs::read_to_string(path)?.trim().to_string();
+            anyhow::ensure!(!secret.is_empty(), "{} is empty", path.display());
+            Ok(Some(secret))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn import(opt: ImportArgs) -> anyhow::Result<()> {
+    advoid::trace::init_tracing_without_otel(false);
+    let imported = Imported::read(opt.from, &opt.path).await?;
+    for skipped in &imported.skipped {
+        eprintln!("skipped {}", skipped);
+    }
+    let options = imported.write(&opt.out).await?;
+    eprintln!(
+        "imported {} blocked and {} allowed domains and {} local records, run advoid with",
+        imported.block.len(),
+        imported.allow.len(),
+        imported.local.len()
+    );
+    println!("{}", options.join(" "));
+    Ok(())
+}
+
+async fn load_lists(
+    block: &str,
+    allow: Option<&str>,
+    cache_dir: Option<&Path>,
+) -> anyhow::Result<(FxHashSet<String>, FxHashSet<String>)> {
+    let blocklist = advoid::blocklist::get(block.to_string(), ListKind::Block, cache_dir).await?;
+    let allowlist = match allow {
+        Some(allow) => {
+            advoid::blocklist::get(allow.to_string(), ListKind::Allow, cache_dir).await?
+        }
+        None => Default::default(),
+    };
+    Ok((blocklist, allowlist))
+}
+
+/// Reloads the block and allow lists on SIGHUP, keeping the current ones if that fails.
+#[cfg(unix)]
+fn spawn_reload_on_hangup(sources: Arc<Vec<ListSource>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP handler");
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            for source in sources.iter() {
+                match source.reload().await {
+                    Ok((blocked, allowed)) => tracing::info!(
+                        "reloaded {} blocked and {} allowed domains for {}",
+                        blocked,
+                        allowed,
+                        source.name
+                    ),
+                    Err(e) => tracing::warn!(
+                        "could not reload the block and allow lists for {}: {}",
+                        source.name,
+                        e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+/// Binds the listening socket with the requested buffer sizes. The kernel may cap them, e.g.
+/// at `net.core.rmem_max` on Linux, so the effective sizes are logged.
+fn bind_udp(
+    addr: SocketAddr,
+    recv_buffer: Option<usize>,
+    send_buffer: Option<usize>,
+) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(size) = recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+        tracing::info!("UDP receive buffer: {} bytes", socket.recv_buffer_size()?);
+    }
+    if let Some(size) = send_buffer {
+        socket.set_send_buffer_size(size)?;
+        tracing::info!("UDP send buffer: {} bytes", socket.send_buffer_size()?);
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Cli::parse();
+    if let Some(Command::Import(args)) = opt.command {
+        return import(args).await;
+    }
+    // Required by clap unless a subcommand is given.
+    let (Some(bind), Some(exporter), Some(block)) = (opt.bind, opt.exporter, opt.block) else {
+        unreachable!("--bind, --exporter and --block are required");
+    };
+
+    #[cfg(feature = "tokio-console")]
+    let tokio_console = opt.tokio_console;
+    #[cfg(not(feature = "tokio-console"))]
+    let tokio_console = false;
 
+    #[cfg(feature = "otel")]
     let _guard = if let Some(otel) = opt.otel {
         let service = env!("CARGO_PKG_NAME");
         let version = env!("CARGO_PKG_VERSION");
-        Some(advoid::trace::init_tracing(service, version, otel))
+        Some(advoid::trace::init_tracing(
+            service,
+            version,
+            otel,
+            tokio_console,
+        ))
     } else {
-        advoid::trace::init_tracing_without_otel();
+        advoid::trace::init_tracing_without_otel(tokio_console);
         None
     };
+    #[cfg(not(feature = "otel"))]
+    advoid::trace::init_tracing_without_otel(tokio_console);
 
-    let blocklist = advoid::blocklist::get(opt.block).await?;
+    if let (Some(min), Some(max)) = (opt.min_ttl, opt.max_ttl) {
+        anyhow::ensure!(min <= max, "--min-ttl must not exceed --max-ttl");
+    }
 
-    let conn = UdpClientStream::<UdpSocket>::new(opt.upstream);
-    let (upstream, background) = AsyncClient::connect(conn).await?;
-    let _handle = tokio::spawn(background);
+    let recorder_handle = advoid::metrics::setup_metrics_recorder()?;
 
-    let handler = StubRequestHandler::new(Arc::new(Mutex::new(upstream)), blocklist);
+    let list_cache = opt.list_cache.as_deref();
+    let (blocklist, allowlist) = load_lists(&block, opt.allow.as_deref(), list_cache).await?;
+
+    let memory_limit = limits::memory_limit();
+    let sized = |size: Option<usize>, default: usize, entry_size: u64| match (size, memory_limit) {
+        (Some(size), _) => size,
+        (None, Some(limit)) => limits::entries(limit, opt.cache_memory_percent, entry_size),
+        (None, None) => default,
+    };
+    let checked_cache_size = NonZeroUsize::new(sized(
+        opt.checked_cache_size.map(NonZeroUsize::get),
+        100_000,
+        limits::CHECKED_ENTRY_SIZE,
+    ))
+    .expect("never sized to 0");
+    let cache_size = sized(opt.cache_size, 10_000, limits::CACHE_ENTRY_SIZE);
+    if let Some(limit) = memory_limit {
+        tracing::info!(
+            "memory limit {} MiB, caching {} answers and {} block decisions",
+            limit >> 20,
+            cache_size,
+            checked_cache_size
+        );
+    }
 
-    let socket = UdpSocket::bind(&opt.bind).await?;
+    let cooldown = Duration::from_secs(opt.upstream_cooldown);
+    let capture = match opt.capture_file {
+        Some(path) => Some(Arc::new(
+            WireCapture::create(path, opt.capture_max_size * 1024 * 1024)
+                .await?
+                .with_sample_rate(opt.capture_sample_rate)
+                .with_zones(opt.capture_zone),
+        )),
+        None => None,
+    };
+    let captured = |upstream: Upstream| match &capture {
+        Some(capture) => upstream.with_capture(capture.clone()),
+        None => upstream,
+    };
+    #[cfg(feature = "encrypted-upstream")]
+    let tls_name = opt.upstream_tls_name;
+    #[cfg(feature = "encrypted-upstream")]
+    let encrypted = match (opt.upstream_tls, opt.upstream_https) {
+        (_, Some(url)) => Some(Upstream::new(
+            Transport::https(&url, opt.upstream_https_bootstrap).await?,
+        )),
+        (Some(addr), _) => Some(Upstream::new(Transport::Tls {
+            addr,
+            name: tls_name.clone().expect("clap requires the tls name"),
+        })),
+        (None, None) => None,
+    };
+    #[cfg(not(feature = "encrypted-upstream"))]
+    let encrypted = None;
+
+    let upstreams = match encrypted {
+        Some(upstream) => vec![upstream],
+        None => opt
+            .upstream
+            .into_iter()
+            .map(|addr| match opt.upstream_protocol {
+                UpstreamProtocol::Udp => Upstream::new(Transport::Udp(addr)),
+                #[cfg(feature = "encrypted-upstream")]
+                UpstreamProtocol::Quic => Upstream::new(Transport::Quic {
+                    addr,
+                    name: tls_name.clone().expect("clap requires the tls name"),
+                })
+                .with_fallback(Transport::Udp(SocketAddr::new(addr.ip(), 53))),
+            })
+            .collect(),
+    };
+    anyhow::ensure!(
+        opt.upstream_name.len() <= upstreams.len(),
+        "more --upstream-name than upstreams"
+    );
+    let mut names = opt.upstream_name.into_iter();
+    let upstream = UpstreamGroup::new(
+        upstreams
+            .into_iter()
+            .map(|it| match names.next() {
+                Some(name) => it.with_name(name),
+                None => it,
+            })
+            .map(|it| {
+                Arc::new(captured(
+                    it.with_cooldown(cooldown)
+                        .with_connections(opt.upstream_connections)
+                        .with_case_randomization(opt.upstream_0x20),
+                ))
+            })
+            .collect(),
+    );
+
+    let forwarders: Vec<_> = opt
+        .forward
+        .into_iter()
+        .map(|(zone, addrs)| {
+            let upstreams = addrs
+                .into_iter()
+                .map(|addr| {
+                    Arc::new(captured(
+                        Upstream::new(Transport::Udp(addr))
+                            .with_cooldown(cooldown)
+                            .with_connections(opt.upstream_connections)
+                            .with_case_randomization(opt.upstream_0x20),
+                    ))
+                })
+                .collect();
+            (zone, Arc::new(UpstreamGroup::new(upstreams)))
+        })
+        .collect();
+
+    let checked: Vec<_> = upstream
+        .upstreams()
+        .iter()
+        .chain(forwarders.iter().flat_map(|(_, it)| it.upstreams()))
+        .cloned()
+        .collect();
+    spawn_health_checks(
+        checked.clone(),
+        Duration::from_secs(opt.health_check_interval),
+    );
+
+    let ecs_policy = match opt.ecs {
+        EcsMode::Strip => EcsPolicy::Strip,
+        EcsMode::Forward => EcsPolicy::Forward,
+        EcsMode::Override => EcsPolicy::Override(opt.ecs_subnet.expect("clap requires the subnet")),
+    };
+
+    for (name, _) in opt.group_block.iter().chain(&opt.group_allow) {
+        if !opt.client_group.iter().any(|(group, _)| group == name) {
+            anyhow::bail!("no --client-group named {}", name);
+        }
+    }
+
+    if !opt.upstream_diff.is_empty() && upstream.upstreams().len() < 2 {
+        tracing::warn!("--upstream-diff needs at least two upstreams to compare");
+    }
+    let upstream_diff = UpstreamDiff::new(upstream.upstreams().to_vec(), opt.upstream_diff);
+
+    let mut handler = StubRequestHandler::new(Arc::new(upstream), blocklist)
+        .with_forwarders(
+            forwarders
+                .into_iter()
+                .map(|(zone, group)| (zone, group as Arc<dyn UpstreamClient>))
+                .collect(),
+        )
+        .with_allowlist(allowlist)
+        .with_matching(if opt.suffix_matching {
+            Matching::Suffix
+        } else {
+            Matching::Labels
+        })
+        .with_ecs_policy(ecs_policy)
+        .with_svcb_block_response(opt.svcb_block_response)
+        .with_rebind_protection(opt.rebind_protection, opt.rebind_allow)
+        .with_client_acl(opt.allow_from, opt.deny_from)
+        .with_cookies(opt.cookies)
+        .with_block_page(opt.block_page)
+        .with_upstream_diff(upstream_diff)
+        .with_ttl_bounds(opt.min_ttl, opt.max_ttl)
+        .with_max_udp_size(opt.max_udp_size)
+        .with_checked_capacity(checked_cache_size)
+        .with_plugins(PluginRegistry::builtin().build(&opt.plugin)?);
+
+    for (name, networks) in &opt.client_group {
+        let block = advoid::blocklist::get_optional(
+            group_list(&opt.group_block, name),
+            ListKind::Block,
+            list_cache,
+        )
+        .await?;
+        let allow = advoid::blocklist::get_optional(
+            group_list(&opt.group_allow, name),
+            ListKind::Allow,
+            list_cache,
+        )
+        .await?;
+        handler = handler.with_client_group(name.clone(), networks.clone(), block, allow);
+    }
+
+    #[cfg(feature = "policy-script")]
+    let handler = match opt.policy_script {
+        Some(path) => {
+            let timeout = Duration::from_millis(opt.policy_script_timeout);
+            handler.with_policy_script(PolicyScript::load(path, timeout)?)
+        }
+        None => handler,
+    };
+
+    let handler = if opt.scheduled_block.is_empty() {
+        handler
+    } else {
+        let mut lists = Vec::new();
+        for (schedule, list) in &opt.scheduled_block {
+            lists.push((
+                *schedule,
+                advoid::blocklist::get(list.clone(), ListKind::Block, list_cache).await?,
+            ));
+        }
+        let scheduled = Arc::new(ScheduledLists::new(lists, opt.schedule_utc_offset));
+        scheduled.clone().spawn_updates();
+        handler.with_scheduled_lists(scheduled)
+    };
+
+    let cache = NonZeroUsize::new(cache_size).map(|size| Arc::new(ResponseCache::new(size)));
+    let handler = match &cache {
+        Some(cache) => handler.with_cache(cache.clone()),
+        None => handler,
+    };
+
+    anyhow::ensure!(
+        opt.query_log.is_none() || opt.history_size > 0,
+        "--query-log needs --history-size"
+    );
+    let query_log = match &opt.query_log {
+        Some(path) => Some(QueryLog::open(path, opt.query_log_retention)?),
+        None => None,
+    };
+    let history = NonZeroUsize::new(opt.history_size).map(|size| {
+        let history = History::new(size).with_client_privacy(opt.client_privacy);
+        Arc::new(match query_log {
+            Some(query_log) => history.with_query_log(query_log),
+            None => history,
+        })
+    });
+    let handler = match &history {
+        Some(history) => handler.with_history(history.clone()),
+        None => handler,
+    };
+
+    let handler = match &opt.candidate_allow {
+        Some(candidate) => handler.with_candidate_allowlist(
+            advoid::blocklist::get(candidate.to_string(), ListKind::Allow, list_cache).await?,
+        ),
+        None => handler,
+    };
+
+    let handler = match &opt.local_records {
+        Some(path) => {
+            let local = LocalRecords::load(path).await?;
+            tracing::info!("loaded local records for {} names", local.len());
+            handler.with_local_records(Arc::new(local))
+        }
+        None => handler,
+    };
+
+    let handler = if opt.zone.is_empty() {
+        handler
+    } else {
+        let mut zones = Zones::default();
+        for (origin, path) in &opt.zone {
+            let records = zones.load(origin.clone(), path).await?;
+            tracing::info!("loaded {} records for zone {}", records, origin);
+        }
+        handler.with_zones(Arc::new(zones))
+    };
+
+    let acme =
+        (!opt.acme_zone.is_empty()).then(|| Arc::new(AcmeChallenges::new(opt.acme_zone.clone())));
+    let handler = match &acme {
+        Some(acme) => handler.with_acme_challenges(acme.clone()),
+        None => handler,
+    };
+
+    let overrides = match opt.overrides_file {
+        Some(path) => Some(Arc::new(Overrides::load(path).await?)),
+        None => None,
+    };
+    let handler = match &overrides {
+        Some(overrides) => handler.with_overrides(overrides.clone()),
+        None => handler,
+    };
+
+    if let Some(server) = opt.ntp_server.clone() {
+        spawn_clock_check(server);
+    }
+    if opt.resume_threshold > 0 {
+        spawn_resume_watch(
+            checked,
+            cache.clone(),
+            Duration::from_secs(opt.resume_threshold),
+        );
+    }
+    if let Some(min_hits) = opt.cache_prefetch {
+        handler.spawn_prefetch(min_hits);
+    }
+
+    let snapshot = opt.snapshot.map(|path| (handler.snapshot(), path));
+    if let Some((snapshot, path)) = &snapshot {
+        if let Err(e) = snapshot.load(path).await {
+            tracing::warn!("could not load snapshot {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(warm_up) = opt.warm_up {
+        let mut names: Vec<_> =
+            advoid::blocklist::get(warm_up, ListKind::Block, opt.list_cache.as_deref())
+                .await?
+                .iter()
+                .filter_map(|entry| {
+                    // Both record types are resolved whatever types the entry applies to.
+                    let name = entry
+                        .split_once(TYPE_OPTION)
+                        .map_or(entry.as_str(), |it| it.0);
+                    Name::from_ascii(name)
+                        .inspect_err(|e| tracing::warn!("skipping warm-up name {}: {}", name, e))
+                        .ok()
+                })
+                .collect();
+        names.sort();
+        names.dedup();
+        handler.warm_up(&names).await;
+    }
+
+    let mut sources = vec![ListSource::new(
+        "default".to_string(),
+        handler.lists(),
+        Some(block),
+        opt.allow,
+        opt.list_cache.clone(),
+    )];
+    for (name, _) in &opt.client_group {
+        sources.push(ListSource::new(
+            name.clone(),
+            handler.group_lists(name).expect("added above"),
+            group_list(&opt.group_block, name).map(str::to_string),
+            group_list(&opt.group_allow, name).map(str::to_string),
+            opt.list_cache.clone(),
+        ));
+    }
+    let sources = Arc::new(sources);
+
+    #[cfg(unix)]
+    spawn_reload_on_hangup(sources.clone());
+
+    #[cfg(feature = "remote-blocklist")]
+    let webhook = read_secret(opt.webhook_secret_file.as_deref())?
+        .map(|secret| Webhook::new(secret.as_bytes(), sources.clone()));
+
+    let socket = bind_udp(bind, opt.udp_recv_buffer, opt.udp_send_buffer)?;
     let mut server = ServerFuture::new(handler);
     server.register_socket(socket);
+    // Clients retry over TCP when an answer is too large for UDP.
+    server.register_listener(TcpListener::bind(bind).await?, Duration::from_secs(10));
 
     tokio::spawn(async move {
         let _ = server.block_until_done().await;
     });
 
-    advoid::metrics::start_metrics_server(opt.exporter).await?;
+    let metrics = advoid::metrics::start_metrics_server(
+        exporter,
+        recorder_handle,
+        opt.metrics_timestamps,
+        read_secret(opt.exporter_token_file.as_deref())?,
+    );
+    let servers = async {
+        match opt.admin {
+            Some(admin) => {
+                let mut admin = AdminServer::new(admin, sources).with_access_control(
+                    read_secret(opt.admin_token_file.as_deref())?,
+                    NonZeroU32::new(opt.admin_rate_limit),
+                );
+                if let Some(cache) = cache {
+                    admin = admin.with_cache(cache);
+                }
+                if let Some(history) = history {
+                    admin = admin.with_history(history);
+                }
+                if let Some(overrides) = overrides {
+                    admin = admin.with_overrides(overrides);
+                }
+                if let Some(acme) = acme {
+                    admin = admin.with_acme_challenges(acme);
+                }
+                #[cfg(feature = "remote-blocklist")]
+                if let Some(webhook) = webhook {
+                    admin = admin.with_webhook(webhook);
+                }
+                let admin = admin.serve();
+                tokio::try_join!(metrics, admin)?;
+                Ok(())
+            }
+            None => metrics.await,
+        }
+    };
+
+    tokio::select! {
+        result = servers => result?,
+        _ = shutdown_signal() => tracing::info!("shutting down"),
+    }
+
+    if let Some((snapshot, path)) = &snapshot {
+        snapshot.save(path).await?;
+    }
 
     Ok(())
 }
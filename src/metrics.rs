@@ -1,27 +1,216 @@
+use crate::clock::unix_time;
+use crate::http::with_access_control;
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::Router;
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use std::future::ready;
+use rustc_hash::FxHashSet;
 use std::net::SocketAddr;
+use std::time::Instant;
 use tokio::net::TcpListener;
 
-pub async fn start_metrics_server(endpoint: SocketAddr) -> anyhow::Result<()> {
-    let app = metrics_app()?;
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_TEXT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Clone)]
+struct MetricsState {
+    recorder_handle: PrometheusHandle,
+    timestamps: bool,
+}
+
+/// Serves `/metrics` in the Prometheus text format or, when the scraper asks for it, in
+/// OpenMetrics. With `timestamps`, every sample carries the time it was scraped at. Scrapers
+/// must send `Authorization: Bearer <token>` when `token` is given.
+pub async fn start_metrics_server(
+    endpoint: SocketAddr,
+    recorder_handle: PrometheusHandle,
+    timestamps: bool,
+    token: Option<String>,
+) -> anyhow::Result<()> {
+    let app = metrics_app(MetricsState {
+        recorder_handle,
+        timestamps,
+    });
+    let app = with_access_control(app, Router::new(), "metrics", token.as_deref(), None);
     let listener = TcpListener::bind(endpoint).await?;
 
     tracing::debug!("listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
-fn metrics_app() -> anyhow::Result<Router> {
-    let recorder_handle = setup_metrics_recorder()?;
-    let router = Router::new().route("/metrics", get(move || ready(recorder_handle.render())));
-    Ok(router)
+fn metrics_app(state: MetricsState) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state(state)
+}
+
+async fn render(State(state): State<MetricsState>, headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|it| it.to_str().ok())
+        .is_some_and(prefers_openmetrics);
+    let timestamp = state.timestamps.then(unix_time);
+
+    let text = state.recorder_handle.render();
+    match openmetrics {
+        true => (
+            [(header::CONTENT_TYPE, OPENMETRICS_TEXT)],
+            to_openmetrics(
+                &text,
+                timestamp.map(|it| format!("{:.3}", it.as_secs_f64())),
+            ),
+        ),
+        false => (
+            [(header::CONTENT_TYPE, PROMETHEUS_TEXT)],
+            match timestamp {
+                Some(it) => with_timestamps(&text, &it.as_millis().to_string()),
+                None => text,
+            },
+        ),
+    }
+}
+
+/// Whether the Accept header ranks OpenMetrics at least as high as the classic text format.
+fn prefers_openmetrics(accept: &str) -> bool {
+    let (mut openmetrics, mut text) = (0f32, 0f32);
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default();
+        let quality = params
+            .find_map(|it| it.strip_prefix("q="))
+            .and_then(|it| it.parse().ok())
+            .unwrap_or(1.0);
+        match media_type {
+            "application/openmetrics-text" => openmetrics = openmetrics.max(quality),
+            "text/plain" => text = text.max(quality),
+            _ => {}
+        }
+    }
+    openmetrics > 0.0 && openmetrics >= text
+}
+
+fn is_sample(line: &str) -> bool {
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// Appends `timestamp` to every sample.
+fn with_timestamps(text: &str, timestamp: &str) -> String {
+    let mut out = String::with_capacity(text.len() * 2);
+    for line in text.lines() {
+        out.push_str(line);
+        if is_sample(line) {
+            out.push(' ');
+            out.push_str(timestamp);
+        }
+        out.push('\n');
+    }
+    out
 }
 
-fn setup_metrics_recorder() -> anyhow::Result<PrometheusHandle> {
+/// Rewrites the Prometheus text format as OpenMetrics: counter families drop the `_total`
+/// suffix their samples must carry, blank lines go and the exposition ends with `# EOF`.
+fn to_openmetrics<'a>(text: &'a str, timestamp: Option<String>) -> String {
+    // HELP comes before TYPE, so counters are found up front.
+    let counters: FxHashSet<&str> = text
+        .lines()
+        .filter_map(|it| it.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+        .collect();
+    let family = |name: &'a str| match counters.contains(name) {
+        true => name.strip_suffix("_total").unwrap_or(name),
+        false => name,
+    };
+
+    let mut out = String::with_capacity(text.len() * 2);
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').unwrap_or((rest, "unknown"));
+            let kind = if kind == "untyped" { "unknown" } else { kind };
+            out.push_str(&format!("# TYPE {} {}\n", family(name), kind));
+        } else if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            out.push_str(&format!("# HELP {} {}\n", family(name), help));
+        } else if is_sample(line) {
+            let end = line.find(['{', ' ']).unwrap_or(line.len());
+            let (name, rest) = line.split_at(end);
+            out.push_str(name);
+            if counters.contains(name) && !name.ends_with("_total") {
+                out.push_str("_total");
+            }
+            out.push_str(rest);
+            if let Some(timestamp) = &timestamp {
+                out.push(' ');
+                out.push_str(timestamp);
+            }
+            out.push('\n');
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+pub fn setup_metrics_recorder() -> anyhow::Result<PrometheusHandle> {
     let handle = PrometheusBuilder::new().install_recorder()?;
 
     Ok(handle)
 }
+
+pub(crate) fn record_stage_duration(stage: &'static str, start: Instant) {
+    metrics::histogram!("dns_stage_duration_seconds", "stage" => stage)
+        .record(start.elapsed().as_secs_f64());
+}
+
+/// A TRACE span under the `advoid::stage` target marking a hot-path stage, for span-based
+/// profilers such as tracing-flame or tokio-console. Disabled spans cost next to nothing.
+pub(crate) fn stage_span(stage: &'static str) -> tracing::Span {
+    tracing::trace_span!(target: "advoid::stage", "stage", stage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_openmetrics_by_quality() {
+        // What Prometheus sends when OpenMetrics scraping is enabled.
+        assert!(prefers_openmetrics(
+            "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+        ));
+        assert!(!prefers_openmetrics(
+            "text/plain;version=0.0.4;q=1,application/openmetrics-text;q=0.5"
+        ));
+        assert!(!prefers_openmetrics("*/*"));
+    }
+
+    #[test]
+    fn rewrites_counters_and_ends_with_eof() {
+        let text = "# HELP dns_requests_total Queries received\n\
+                    # TYPE dns_requests_total counter\n\
+                    dns_requests_total 3\n\
+                    \n\
+                    # HELP dns_upstream_errors Failed upstream queries\n\
+                    # TYPE dns_upstream_errors counter\n\
+                    dns_upstream_errors{upstream=\"a\"} 1\n\
+                    # TYPE dns_checked_domains gauge\n\
+                    dns_checked_domains 2\n";
+
+        assert_eq!(
+            to_openmetrics(text, Some("1.500".to_string())),
+            "# HELP dns_requests Queries received\n\
+             # TYPE dns_requests counter\n\
+             dns_requests_total 3 1.500\n\
+             # HELP dns_upstream_errors Failed upstream queries\n\
+             # TYPE dns_upstream_errors counter\n\
+             dns_upstream_errors_total{upstream=\"a\"} 1 1.500\n\
+             # TYPE dns_checked_domains gauge\n\
+             dns_checked_domains 2 1.500\n\
+             # EOF\n"
+        );
+    }
+}
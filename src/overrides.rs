@@ -0,0 +1,208 @@
+use crate::fs::write_atomically;
+use crate::suffix::{Matching, SuffixSet};
+use arc_swap::ArcSwap;
+use hickory_client::rr::Name;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::sync::Mutex;
+
+/// Which of the overrides a domain is added to.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverrideList {
+    Block,
+    Allow,
+}
+
+/// The overridden domains as shown by the admin API.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct OverrideEntries {
+    pub block: BTreeSet<String>,
+    pub allow: BTreeSet<String>,
+}
+
+/// Domains blocked or allowed at runtime, taking precedence over every block and allow list,
+/// and saved to a file so they survive restarts.
+///
+/// The file lists one `block <domain>` or `allow <domain>` per line.
+pub struct Overrides {
+    path: PathBuf,
+    entries: Mutex<OverrideEntries>,
+    block: ArcSwap<SuffixSet>,
+    allow: ArcSwap<SuffixSet>,
+}
+
+impl Overrides {
+    /// Reads the overrides saved to `path`. A missing file is not an error.
+    pub async fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let text = match tokio::fs::read_to_string(&path).await {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = OverrideEntries::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = match line.split_once(' ') {
+                Some(("block", domain)) => normalize(domain).map(|it| entries.block.insert(it)),
+                Some(("allow", domain)) => normalize(domain).map(|it| entries.allow.insert(it)),
+                _ => None,
+            };
+            if parsed.is_none() {
+                anyhow::bail!("{}:{}: not an override: {}", path.display(), i + 1, line);
+            }
+        }
+
+        let overrides = Overrides {
+            path,
+            entries: Mutex::default(),
+            block: ArcSwap::default(),
+            allow: ArcSwap::default(),
+        };
+        overrides.apply(&entries);
+        *overrides.entries.lock().await = entries;
+        Ok(overrides)
+    }
+
+    /// Whether `domain` is blocked (`Some(true)`) or allowed (`Some(false)`) by an override.
+    /// Allowed domains win over blocked ones.
+    pub(crate) fn verdict(&self, domain: &str, matching: Matching) -> Option<bool> {
+        if self.allow.load().matches(domain, matching) {
+            Some(false)
+        } else if self.block.load().matches(domain, matching) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    pub async fn entries(&self) -> OverrideEntries {
+        self.entries.lock().await.clone()
+    }
+
+    /// Adds `domain` to `list`, or removes it with `present` false, and saves the overrides.
+    /// Returns whether anything changed, or `None` if `domain` isn't a domain name.
+    pub async fn set(
+        &self,
+        list: OverrideList,
+        domain: &str,
+        present: bool,
+    ) -> anyhow::Result<Option<bool>> {
+        let Some(domain) = normalize(domain) else {
+            return Ok(None);
+        };
+
+        let mut entries = self.entries.lock().await;
+        let mut updated = entries.clone();
+        let set = match list {
+            OverrideList::Block => &mut updated.block,
+            OverrideList::Allow => &mut updated.allow,
+        };
+        let changed = match present {
+            true => set.insert(domain),
+            false => set.remove(&domain),
+        };
+        if changed {
+            // Saved first so the overrides in use never differ from the file.
+            self.save(&updated).await?;
+            self.apply(&updated);
+            *entries = updated;
+        }
+        Ok(Some(changed))
+    }
+
+    fn apply(&self, entries: &OverrideEntries) {
+        self.block.store(SuffixSet::new(&entries.block).into());
+        self.allow.store(SuffixSet::new(&entries.allow).into());
+    }
+
+    async fn save(&self, entries: &OverrideEntries) -> anyhow::Result<()> {
+        let mut text = String::new();
+        for (action, domains) in [("block", &entries.block), ("allow", &entries.allow)] {
+            for domain in domains {
+                text.push_str(&format!("{} {}\n", action, domain));
+            }
+        }
+
+        write_atomically(&self.path, text).await?;
+        Ok(())
+    }
+}
+
+/// `domain` lowercased with a trailing dot, as list entries are, if it is a domain name.
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    if domain.is_empty() || domain.contains(char::is_whitespace) {
+        return None;
+    }
+    Name::from_str(&domain).ok()?;
+    Some(format!("{}.", domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saves_overrides_and_lets_allowed_domains_win() {
+        let path = std::env::temp_dir().join(format!("advoid-overrides-{}", std::process::id()));
+        let overrides = Overrides::load(path.clone()).await.unwrap();
+
+        let set = |list, domain, present| overrides.set(list, domain, present);
+        assert_eq!(
+            set(OverrideList::Block, "Ads.Example", true).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            set(OverrideList::Block, "ads.example.", true)
+                .await
+                .unwrap(),
+            Some(false)
+        );
+        assert_eq!(
+            set(OverrideList::Allow, "cdn.ads.example", true)
+                .await
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            set(OverrideList::Allow, "not a name", true).await.unwrap(),
+            None
+        );
+
+        assert_eq!(
+            overrides.verdict("x.ads.example.", Matching::Labels),
+            Some(true)
+        );
+        assert_eq!(
+            overrides.verdict("cdn.ads.example.", Matching::Labels),
+            Some(false)
+        );
+        assert_eq!(overrides.verdict("example.", Matching::Labels), None);
+
+        let reloaded = Overrides::load(path.clone()).await.unwrap().entries().await;
+        assert_eq!(reloaded.block, BTreeSet::from(["ads.example.".to_string()]));
+        assert_eq!(
+            reloaded.allow,
+            BTreeSet::from(["cdn.ads.example.".to_string()])
+        );
+
+        assert_eq!(
+            set(OverrideList::Allow, "cdn.ads.example", false)
+                .await
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            overrides.verdict("cdn.ads.example.", Matching::Labels),
+            Some(true)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
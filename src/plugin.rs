@@ -0,0 +1,115 @@
+use crate::upstream::UpstreamClient;
+use anyhow::anyhow;
+use hickory_client::op::DnsResponse;
+use hickory_client::rr::{DNSClass, Name, RecordType};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+mod dns64;
+mod redis;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decision {
+    /// Fall through to the built-in rules.
+    Default,
+    Allow,
+    Block,
+    Rewrite(IpAddr),
+}
+
+/// The query a plugin is asked about.
+pub struct PluginQuery<'a> {
+    pub client: IpAddr,
+    pub name: &'a Name,
+    pub query_class: DNSClass,
+    pub query_type: RecordType,
+    /// Whether the client's allow list, or an override, lets the name through, so plugins
+    /// blocking names can leave it alone.
+    pub allowlisted: bool,
+    /// Upstream the query is forwarded to, for plugins that need to look up something else.
+    pub upstream: &'a dyn UpstreamClient,
+}
+
+/// An extension hooked into every query handled by `StubRequestHandler`.
+///
+/// Plugins run in the order they were enabled. The first one returning anything other than
+/// `Decision::Default` decides the query and the rest are skipped.
+#[async_trait::async_trait]
+pub trait QueryPlugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Called before the blocklist and the policy script are consulted.
+    async fn on_query(&self, _query: &PluginQuery<'_>) -> Decision {
+        Decision::Default
+    }
+
+    /// Called with the upstream answer before it is sent back. `Decision::Allow` keeps the
+    /// (possibly modified) answer and skips the remaining plugins.
+    async fn on_response(&self, _query: &PluginQuery<'_>, _response: &mut DnsResponse) -> Decision {
+        Decision::Default
+    }
+}
+
+/// Builds a plugin from the argument given after `=` in its spec, if any.
+pub type PluginFactory = fn(Option<&str>) -> anyhow::Result<Arc<dyn QueryPlugin>>;
+
+/// The plugins that can be enabled by name.
+pub struct PluginRegistry {
+    factories: Vec<(&'static str, PluginFactory)>,
+}
+
+impl PluginRegistry {
+    pub fn new() -> Self {
+        PluginRegistry {
+            factories: Vec::new(),
+        }
+    }
+
+    /// A registry holding every plugin compiled into this binary.
+    pub fn builtin() -> Self {
+        let mut registry = PluginRegistry::new();
+        registry.register("dns64", dns64::Dns64::factory);
+        registry.register("redis", redis::RedisBlocklist::factory);
+        registry
+    }
+
+    pub fn register(&mut self, name: &'static str, factory: PluginFactory) {
+        self.factories.push((name, factory));
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.factories.iter().map(|(name, _)| *name)
+    }
+
+    /// Builds the plugins for `specs` (`<NAME>` or `<NAME>=<ARG>`), keeping their order.
+    pub fn build(&self, specs: &[String]) -> anyhow::Result<Vec<Arc<dyn QueryPlugin>>> {
+        specs
+            .iter()
+            .map(|spec| {
+                let (name, arg) = match spec.split_once('=') {
+                    Some((name, arg)) => (name, Some(arg)),
+                    None => (spec.as_str(), None),
+                };
+                let (_, factory) = self
+                    .factories
+                    .iter()
+                    .find(|(it, _)| *it == name)
+                    .ok_or_else(|| {
+                        let available = self.names().collect::<Vec<_>>();
+                        let available = match available.is_empty() {
+                            true => "none".to_string(),
+                            false => available.join(", "),
+                        };
+                        anyhow!("unknown plugin {} (available: {})", name, available)
+                    })?;
+                factory(arg)
+            })
+            .collect()
+    }
+}
+
+impl Default for PluginRegistry {
+    fn default() -> Self {
+        PluginRegistry::new()
+    }
+}
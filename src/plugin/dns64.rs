@@ -0,0 +1,151 @@
+use crate::plugin::{Decision, PluginQuery, QueryPlugin};
+use hickory_client::op::{DnsResponse, ResponseCode};
+use hickory_client::rr::{RData, Record, RecordType};
+use hickory_proto::rr::rdata::AAAA;
+use ipnet::Ipv6Net;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// The Well-Known Prefix of RFC 6052.
+const WELL_KNOWN_PREFIX: Ipv6Net =
+    Ipv6Net::new_assert(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96);
+
+/// Synthesizes AAAA records from the A records of names without any for clients on IPv6-only
+/// networks behind NAT64 (DNS64, RFC 6147).
+///
+/// Enabled with `--plugin dns64` for the Well-Known Prefix `64:ff9b::/96` or
+/// `--plugin dns64=<PREFIX>` for a network-specific one, which must be 32, 40, 48, 56, 64 or 96
+/// bits long. Non-global IPv4 addresses aren't translated with the Well-Known Prefix.
+pub struct Dns64 {
+    prefix: Ipv6Net,
+}
+
+impl Dns64 {
+    pub fn factory(arg: Option<&str>) -> anyhow::Result<Arc<dyn QueryPlugin>> {
+        let prefix = match arg {
+            Some(arg) => arg.parse::<Ipv6Net>()?.trunc(),
+            None => WELL_KNOWN_PREFIX,
+        };
+        anyhow::ensure!(
+            matches!(prefix.prefix_len(), 32 | 40 | 48 | 56 | 64 | 96),
+            "dns64 prefix must be 32, 40, 48, 56, 64 or 96 bits long, got {}",
+            prefix
+        );
+        Ok(Arc::new(Dns64 { prefix }))
+    }
+
+    fn translates(&self, address: Ipv4Addr) -> bool {
+        self.prefix != WELL_KNOWN_PREFIX
+            || !(address.is_private()
+                || address.is_loopback()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast())
+    }
+}
+
+#[async_trait::async_trait]
+impl QueryPlugin for Dns64 {
+    fn name(&self) -> &'static str {
+        "dns64"
+    }
+
+    async fn on_response(&self, query: &PluginQuery<'_>, response: &mut DnsResponse) -> Decision {
+        if query.query_type != RecordType::AAAA
+            || response.response_code() != ResponseCode::NoError
+            || response
+                .answers()
+                .iter()
+                .any(|it| it.record_type() == RecordType::AAAA)
+        {
+            return Decision::Default;
+        }
+
+        let a = match query
+            .upstream
+            .query(query.name.clone(), query.query_class, RecordType::A, &[])
+            .await
+        {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("dns64 could not look up A records of {}: {}", query.name, e);
+                return Decision::Default;
+            }
+        };
+        // The synthesized records last no longer than the absence of AAAA records.
+        let max_ttl = response.negative_ttl().unwrap_or(u32::MAX);
+        let synthesized: Vec<_> = a
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::A(address)) if self.translates(address.0) => Some(Record::from_rdata(
+                    record.name().clone(),
+                    record.ttl().min(max_ttl),
+                    RData::AAAA(AAAA(synthesize(self.prefix, address.0))),
+                )),
+                _ => None,
+            })
+            .collect();
+        if synthesized.is_empty() {
+            return Decision::Default;
+        }
+
+        debug!("Synthesizing AAAA records for {}", query.name);
+        metrics::counter!("dns_dns64_synthesized").increment(1);
+        let mut message = response.clone().into_message();
+        // The CNAMEs leading to the A records replace those of the empty answer.
+        let cnames = a
+            .answers()
+            .iter()
+            .filter(|it| it.record_type() == RecordType::CNAME)
+            .cloned();
+        *message.answers_mut() = cnames.chain(synthesized).collect();
+        message.name_servers_mut().clear();
+        match DnsResponse::from_message(message) {
+            Ok(synthesized) => *response = synthesized,
+            Err(e) => warn!("dns64 could not answer {}: {}", query.name, e),
+        }
+        Decision::Default
+    }
+}
+
+/// `address` embedded in `prefix` as laid out in RFC 6052, skipping bits 64 to 71.
+fn synthesize(prefix: Ipv6Net, address: Ipv4Addr) -> Ipv6Addr {
+    let mut bytes = prefix.network().octets();
+    let start = usize::from(prefix.prefix_len() / 8);
+    let positions = (start..16).filter(|it| *it != 8);
+    for (position, byte) in positions.zip(address.octets()) {
+        bytes[position] = byte;
+    }
+    Ipv6Addr::from(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_addresses_in_the_prefix() {
+        let address = Ipv4Addr::new(192, 0, 2, 33);
+        let synthesized = |prefix: &str| synthesize(prefix.parse().unwrap(), address).to_string();
+
+        assert_eq!(synthesized("64:ff9b::/96"), "64:ff9b::c000:221");
+        assert_eq!(synthesized("2001:db8::/32"), "2001:db8:c000:221::");
+        assert_eq!(synthesized("2001:db8:100::/40"), "2001:db8:1c0:2:21::");
+        assert_eq!(
+            synthesized("2001:db8:122:300::/56"),
+            "2001:db8:122:3c0:0:221::"
+        );
+        assert_eq!(
+            synthesized("2001:db8:122:344::/64"),
+            "2001:db8:122:344:c0:2:2100:0"
+        );
+
+        let dns64 = Dns64 {
+            prefix: WELL_KNOWN_PREFIX,
+        };
+        assert!(dns64.translates(address));
+        assert!(!dns64.translates(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::plugin::{Decision, PluginQuery, QueryPlugin};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+/// How long a lookup result is reused before Redis is asked again.
+const CACHE_TTL: Duration = Duration::from_secs(10);
+
+const CACHE_CAPACITY: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// Redis is asked while the client waits, so a slow one must not hold up the answer for long.
+const LOOKUP_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Connections opened to Redis at most, each running one lookup at a time.
+const MAX_CONNECTIONS: usize = 8;
+
+/// Blocks names found, along with any of their parent domains, in a Redis set.
+///
+/// Enabled with `--plugin redis=<HOST>:<PORT>/<KEY>`, where `<KEY>` is a set of domains without
+/// the trailing dot. Needs Redis 6.2 or later for SMISMEMBER. Queries are let through when
+/// Redis can't be reached, and so are names on the client's allow list.
+pub struct RedisBlocklist {
+    addr: String,
+    key: String,
+    /// Connections not running a lookup right now.
+    idle: std::sync::Mutex<Vec<BufStream<TcpStream>>>,
+    connections: Semaphore,
+    cache: std::sync::Mutex<LruCache<String, (bool, Instant)>>,
+}
+
+impl RedisBlocklist {
+    pub fn factory(arg: Option<&str>) -> anyhow::Result<Arc<dyn QueryPlugin>> {
+        let arg = arg.ok_or_else(|| anyhow::anyhow!("redis plugin needs <HOST>:<PORT>/<KEY>"))?;
+        let arg = arg.strip_prefix("redis://").unwrap_or(arg);
+        let (addr, key) = arg
+            .split_once('/')
+            .filter(|(addr, key)| !addr.is_empty() && !key.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!("redis plugin needs <HOST>:<PORT>/<KEY>, got {}", arg)
+            })?;
+
+        Ok(Arc::new(RedisBlocklist::new(addr, key)))
+    }
+
+    fn new(addr: &str, key: &str) -> Self {
+        RedisBlocklist {
+            addr: addr.to_string(),
+            key: key.to_string(),
+            idle: std::sync::Mutex::new(Vec::new()),
+            connections: Semaphore::new(MAX_CONNECTIONS),
+            cache: std::sync::Mutex::new(LruCache::new(CACHE_CAPACITY)),
+        }
+    }
+
+    /// The result of an earlier lookup for `domain`, unless it is older than `CACHE_TTL`.
+    fn cached(&self, domain: &str, now: Instant) -> Option<bool> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(domain) {
+            Some((blocked, expires)) if *expires > now => Some(*blocked),
+            _ => None,
+        }
+    }
+
+    fn remember(&self, domain: &str, blocked: bool, now: Instant) {
+        self.cache
+            .lock()
+            .unwrap()
+            .put(domain.to_string(), (blocked, now + CACHE_TTL));
+    }
+
+    async fn is_blocked(&self, domain: &str) -> anyhow::Result<bool> {
+        // The name itself and each of its parent domains.
+        let candidates: Vec<&str> = std::iter::once(domain)
+            .chain(domain.match_indices('.').map(|(i, _)| &domain[i + 1..]))
+            .filter(|it| !it.is_empty())
+            .collect();
+
+        let idle = self.idle.lock().unwrap().pop();
+        let mut stream = match idle {
+            Some(stream) => stream,
+            None => BufStream::new(TcpStream::connect(&self.addr).await?),
+        };
+
+        stream
+            .write_all(smismember_command(&self.key, &candidates).as_bytes())
+            .await?;
+        stream.flush().await?;
+        // A connection whose reply is half read, after an error or a timeout, is dropped here
+        // rather than handed to the next lookup.
+        let found = read_smismember_reply(&mut stream).await?;
+        self.idle.lock().unwrap().push(stream);
+
+        Ok(found.into_iter().any(|it| it))
+    }
+}
+
+#[async_trait::async_trait]
+impl QueryPlugin for RedisBlocklist {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn on_query(&self, query: &PluginQuery<'_>) -> Decision {
+        if query.allowlisted {
+            return Decision::Default;
+        }
+        let domain = query.name.to_lowercase().to_string();
+        let domain = domain.trim_end_matches('.');
+
+        let blocked = match self.cached(domain, Instant::now()) {
+            Some(blocked) => blocked,
+            None => {
+                // Waiting for a free connection doesn't count against the lookup timeout.
+                let _permit = self.connections.acquire().await.expect("never closed");
+                match tokio::time::timeout(LOOKUP_TIMEOUT, self.is_blocked(domain)).await {
+                    Ok(Ok(blocked)) => {
+                        self.remember(domain, blocked, Instant::now());
+                        blocked
+                    }
+                    Ok(Err(e)) => {
+                        warn!("redis lookup for {} failed: {}", domain, e);
+                        metrics::counter!("dns_redis_errors").increment(1);
+                        false
+                    }
+                    Err(_) => {
+                        warn!("redis lookup for {} timed out", domain);
+                        metrics::counter!("dns_redis_errors").increment(1);
+                        false
+                    }
+                }
+            }
+        };
+
+        match blocked {
+            true => Decision::Block,
+            false => Decision::Default,
+        }
+    }
+}
+
+/// `SMISMEMBER key members...` in RESP, asking which of the members are in the set.
+fn smismember_command(key: &str, members: &[&str]) -> String {
+    let mut command = format!("*{}\r\n$10\r\nSMISMEMBER\r\n", members.len() + 2);
+    for arg in std::iter::once(key).chain(members.iter().copied()) {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    command
+}
+
+/// Reads back the reply to `SMISMEMBER`, one flag per member asked about.
+async fn read_smismember_reply(
+    stream: &mut (impl AsyncBufRead + Unpin),
+) -> anyhow::Result<Vec<bool>> {
+    let reply = read_line(stream).await?;
+    let count: usize = match (reply.strip_prefix('*'), reply.strip_prefix('-')) {
+        (Some(count), _) => count.parse()?,
+        (_, Some(error)) => anyhow::bail!("{}", error),
+        _ => anyhow::bail!("unexpected reply to SMISMEMBER: {}", reply),
+    };
+    let mut found = Vec::with_capacity(count);
+    for _ in 0..count {
+        found.push(read_line(stream).await? == ":1");
+    }
+    Ok(found)
+}
+
+async fn read_line(stream: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        anyhow::bail!("connection closed by redis");
+    }
+    let line = line.trim_end().to_string();
+    anyhow::ensure!(!line.is_empty(), "empty reply from redis");
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_smismember_in_resp() {
+        assert_eq!(
+            smismember_command("block", &["ads.example.com", "example.com"]),
+            "*4\r\n$10\r\nSMISMEMBER\r\n$5\r\nblock\r\n$15\r\nads.example.com\r\n\
+             $11\r\nexample.com\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn parses_smismember_replies() {
+        let mut reply: &[u8] = b"*3\r\n:0\r\n:1\r\n:0\r\n";
+        assert_eq!(
+            read_smismember_reply(&mut reply).await.unwrap(),
+            [false, true, false]
+        );
+
+        let mut error: &[u8] = b"-ERR unknown command 'SMISMEMBER'\r\n";
+        let error = read_smismember_reply(&mut error).await.unwrap_err();
+        assert_eq!(error.to_string(), "ERR unknown command 'SMISMEMBER'");
+
+        let mut truncated: &[u8] = b"*2\r\n:1\r\n";
+        assert!(read_smismember_reply(&mut truncated).await.is_err());
+        let mut unexpected: &[u8] = b"+OK\r\n";
+        assert!(read_smismember_reply(&mut unexpected).await.is_err());
+    }
+
+    #[test]
+    fn reuses_lookups_until_they_expire() {
+        let redis = RedisBlocklist::new("127.0.0.1:6379", "block");
+        let now = Instant::now();
+        redis.remember("ads.example.com", true, now);
+        redis.remember("example.com", false, now);
+
+        assert_eq!(redis.cached("ads.example.com", now), Some(true));
+        assert_eq!(
+            redis.cached("example.com", now + CACHE_TTL / 2),
+            Some(false)
+        );
+        assert_eq!(redis.cached("ads.example.com", now + CACHE_TTL), None);
+        assert_eq!(redis.cached("www.example.com", now), None);
+    }
+}
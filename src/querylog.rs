@@ -0,0 +1,189 @@
+#[cfg(feature = "query-log")]
+use crate::clock::unix_time;
+use crate::history::HistoryEntry;
+#[cfg(feature = "query-log")]
+use rusqlite::{params, Connection};
+use std::path::Path;
+#[cfg(feature = "query-log")]
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::mpsc::{SyncSender, TrySendError};
+#[cfg(feature = "query-log")]
+use std::time::{Duration, Instant};
+
+/// Entries waiting to be written before new ones are dropped.
+#[cfg(feature = "query-log")]
+const QUEUE_SIZE: usize = 10_000;
+
+/// Most entries written in one transaction.
+#[cfg(feature = "query-log")]
+const BATCH_SIZE: usize = 1_000;
+
+/// How often entries older than the retention are deleted.
+#[cfg(feature = "query-log")]
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[cfg(feature = "query-log")]
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS queries (
+    time INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    client TEXT,
+    type TEXT NOT NULL,
+    verdict TEXT NOT NULL,
+    answer_count INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS queries_name ON queries (name, time);
+CREATE INDEX IF NOT EXISTS queries_time ON queries (time);
+";
+
+/// Every query recorded in the history, written to an SQLite database so the query log of a
+/// single box can be searched without any other service.
+///
+/// Entries are written by a thread of their own, and dropped rather than holding up queries
+/// when the disk can't keep up.
+pub struct QueryLog {
+    sender: SyncSender<HistoryEntry>,
+}
+
+impl QueryLog {
+    /// Opens or creates the database at `path`, deleting entries older than `retention` days.
+    #[cfg(feature = "query-log")]
+    pub fn open(path: &Path, retention: u64) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let retention = Duration::from_secs(retention * 24 * 60 * 60);
+        std::thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || write_entries(connection, receiver, retention))?;
+        Ok(QueryLog { sender })
+    }
+
+    #[cfg(not(feature = "query-log"))]
+    pub fn open(path: &Path, _retention: u64) -> anyhow::Result<Self> {
+        anyhow::bail!("{} needs advoid built with query-log", path.display())
+    }
+
+    pub(crate) fn log(&self, entry: &HistoryEntry) {
+        match self.sender.try_send(entry.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => metrics::counter!("query_log_dropped").increment(1),
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(feature = "query-log")]
+fn write_entries(
+    mut connection: Connection,
+    receiver: Receiver<HistoryEntry>,
+    retention: Duration,
+) {
+    let mut pruned: Option<Instant> = None;
+    loop {
+        let first = match receiver.recv_timeout(PRUNE_INTERVAL) {
+            Ok(entry) => Some(entry),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let batch: Vec<_> = first
+            .into_iter()
+            .chain(receiver.try_iter().take(BATCH_SIZE - 1))
+            .collect();
+        if let Err(e) = insert(&mut connection, &batch) {
+            tracing::warn!(
+                "could not write {} entries to the query log: {}",
+                batch.len(),
+                e
+            );
+        }
+
+        if pruned.is_none_or(|it| it.elapsed() >= PRUNE_INTERVAL) {
+            if let Err(e) = prune(&connection, retention) {
+                tracing::warn!("could not delete old entries of the query log: {}", e);
+            }
+            pruned = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(feature = "query-log")]
+fn insert(connection: &mut Connection, entries: &[HistoryEntry]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO queries (time, name, client, type, verdict, answer_count, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for entry in entries {
+            statement.execute(params![
+                entry.time as i64,
+                entry.name,
+                entry.client,
+                entry.query_type,
+                entry.verdict.as_str(),
+                entry.answer_count as i64,
+                entry.duration_ms as i64,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+#[cfg(feature = "query-log")]
+fn prune(connection: &Connection, retention: Duration) -> rusqlite::Result<usize> {
+    let oldest = unix_time().saturating_sub(retention).as_secs();
+    connection.execute("DELETE FROM queries WHERE time < ?1", [oldest as i64])
+}
+
+#[cfg(all(test, feature = "query-log"))]
+mod tests {
+    use super::*;
+    use crate::history::Verdict;
+
+    fn entry(time: u64, name: &str) -> HistoryEntry {
+        HistoryEntry {
+            time,
+            name: name.to_string(),
+            client: Some("192.0.2.1".to_string()),
+            query_type: "A".to_string(),
+            verdict: Verdict::Block,
+            answer_count: 0,
+            duration_ms: 2,
+        }
+    }
+
+    #[test]
+    fn writes_entries_and_deletes_old_ones() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        let now = unix_time().as_secs();
+        let entries = [
+            entry(now - 3 * 24 * 60 * 60, "old.example"),
+            entry(now, "ads.example"),
+        ];
+        insert(&mut connection, &entries).unwrap();
+
+        let retention = Duration::from_secs(2 * 24 * 60 * 60);
+        assert_eq!(prune(&connection, retention).unwrap(), 1);
+        let row: (String, Option<String>, String, String) = connection
+            .query_row(
+                "SELECT name, client, type, verdict FROM queries WHERE name = ?1",
+                ["ads.example"],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            row,
+            (
+                "ads.example".to_string(),
+                Some("192.0.2.1".to_string()),
+                "A".to_string(),
+                "block".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use crate::cache::ResponseCache;
+use crate::upstream::Upstream;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::MissedTickBehavior;
+
+/// How often the clocks are compared.
+const TICK: Duration = Duration::from_secs(5);
+
+/// Watches for the system waking up from sleep: the monotonic clock stops while suspended on
+/// Linux, so a wall clock running ahead of it (or a tick arriving much too late) by at least
+/// `threshold` means time was missed. The upstream connections are then dropped, since they
+/// are most likely dead, and the cached answers aged by the missed time.
+pub fn spawn_resume_watch(
+    upstreams: Vec<Arc<Upstream>>,
+    cache: Option<Arc<ResponseCache>>,
+    threshold: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+
+        let (mut wall, mut monotonic) = (SystemTime::now(), Instant::now());
+        loop {
+            ticker.tick().await;
+            let (now_wall, now_monotonic) = (SystemTime::now(), Instant::now());
+            let missed = missed_time(
+                now_wall.duration_since(wall).unwrap_or_default(),
+                now_monotonic.duration_since(monotonic),
+            );
+            (wall, monotonic) = (now_wall, now_monotonic);
+            if missed < threshold {
+                continue;
+            }
+
+            metrics::counter!("system_resumes").increment(1);
+            for upstream in &upstreams {
+                upstream.reset();
+            }
+            let expired = cache.as_ref().map_or(0, |it| it.age_by(missed));
+            tracing::info!(
+                "woke up after about {:?}, reconnecting upstreams and expired {} cached answers",
+                missed,
+                expired
+            );
+        }
+    });
+}
+
+/// The time missed between two ticks given how much the wall and monotonic clocks advanced.
+fn missed_time(wall: Duration, monotonic: Duration) -> Duration {
+    wall.max(monotonic).saturating_sub(TICK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_time_missed_between_ticks() {
+        let secs = Duration::from_secs;
+        assert_eq!(missed_time(secs(5), secs(5)), Duration::ZERO);
+        // Suspended: the monotonic clock stood still.
+        assert_eq!(missed_time(secs(3605), secs(5)), secs(3600));
+        // Elsewhere the monotonic clock keeps counting and the tick is late instead.
+        assert_eq!(missed_time(secs(605), secs(605)), secs(600));
+        // The wall clock was set back.
+        assert_eq!(missed_time(Duration::ZERO, secs(5)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::clock::unix_time;
+use crate::suffix::{Matching, SuffixSet};
+use arc_swap::ArcSwap;
+use hickory_client::rr::RecordType;
+use rustc_hash::FxHashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// When a scheduled list applies: `[<DAYS>@]<HH:MM>-<HH:MM>`, e.g. `mon-fri@09:00-17:00`.
+///
+/// Days are ranges or single days separated by commas, every day if left out. Times ending
+/// before they start run past midnight, into the next day.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    /// Bit 0 for Monday through bit 6 for Sunday.
+    days: u8,
+    /// Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+impl Schedule {
+    /// Whether the schedule applies at `minute` of the week, counted from Monday midnight.
+    fn is_active(&self, minute: u32) -> bool {
+        let (day, minute) = (minute / MINUTES_PER_DAY, minute % MINUTES_PER_DAY);
+        let on = |day: u32| self.days & (1 << (day % 7)) != 0;
+        if self.start < self.end {
+            on(day) && (self.start..self.end).contains(&minute)
+        } else {
+            (on(day) && minute >= self.start) || (on(day + 6) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = match s.split_once('@') {
+            Some((days, times)) => (parse_days(days)?, times),
+            None => (0x7f, s),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("expected <HH:MM>-<HH:MM>: {}", times))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end || start == MINUTES_PER_DAY {
+            return Err(format!("empty schedule: {}", s));
+        }
+        Ok(Schedule { days, start, end })
+    }
+}
+
+fn parse_days(s: &str) -> Result<u8, String> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|it| it.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("not a day: {}", name))
+    };
+    let mut days = 0;
+    for range in s.split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(range)?, day(range)?),
+        };
+        // Ranges like sat-mon wrap around the week.
+        let mut current = first;
+        loop {
+            days |= 1 << current;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .and_then(|(hours, minutes)| {
+            Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+        })
+        .filter(|(hours, minutes)| {
+            *minutes < 60 && (*hours < 24 || (*hours == 24 && *minutes == 0))
+        })
+        .ok_or_else(|| format!("not a time: {}", s))?;
+    Ok(hours * 60 + minutes)
+}
+
+/// Block lists applying only while their schedule does, in the time zone `utc_offset` seconds
+/// ahead of UTC.
+///
+/// The domains of the lists active right now are merged into a single set, swapped each time a
+/// schedule starts or ends, so queries are matched against one set whatever the number of
+/// lists. Lists are read once at startup.
+pub struct ScheduledLists {
+    lists: Vec<(Schedule, FxHashSet<String>)>,
+    utc_offset: i64,
+    active: ArcSwap<(Vec<bool>, SuffixSet)>,
+}
+
+impl ScheduledLists {
+    pub fn new(lists: Vec<(Schedule, FxHashSet<String>)>, utc_offset: i64) -> Self {
+        let scheduled = ScheduledLists {
+            lists,
+            utc_offset,
+            active: ArcSwap::from_pointee((Vec::new(), SuffixSet::new(Vec::<String>::new()))),
+        };
+        scheduled.update(unix_time().as_secs());
+        scheduled
+    }
+
+    /// Swaps the active set every minute a schedule starts or ends.
+    pub fn spawn_updates(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                let seconds = 60 - unix_time().as_secs() % 60;
+                tokio::time::sleep(Duration::from_secs(seconds)).await;
+                self.update(unix_time().as_secs());
+            }
+        });
+    }
+
+    fn update(&self, now: u64) {
+        let minute = self.minute_of_week(now);
+        let active: Vec<_> = self
+            .lists
+            .iter()
+            .map(|(schedule, _)| schedule.is_active(minute))
+            .collect();
+        if self.active.load().0 == active {
+            return;
+        }
+
+        let domains = self
+            .lists
+            .iter()
+            .zip(&active)
+            .filter(|(_, active)| **active)
+            .flat_map(|((_, domains), _)| domains);
+        let set = SuffixSet::new(domains);
+        tracing::info!("{} scheduled domains are blocked now", set.len());
+        metrics::gauge!("dns_scheduled_domains").set(set.len() as f64);
+        self.active.store(Arc::new((active, set)));
+    }
+
+    fn minute_of_week(&self, now: u64) -> u32 {
+        let minutes = (now as i64 + self.utc_offset).div_euclid(60);
+        // The Unix epoch was a Thursday.
+        (minutes + 3 * i64::from(MINUTES_PER_DAY)).rem_euclid(i64::from(MINUTES_PER_WEEK)) as u32
+    }
+
+    /// Whether an active list blocks `query_type` queries for `domain`, `$dnstype` entries
+    /// included.
+    pub(crate) fn matches(&self, domain: &str, query_type: RecordType, matching: Matching) -> bool {
+        self.active
+            .load()
+            .1
+            .matches_type(domain, query_type, matching)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_lists_while_their_schedule_applies() {
+        let schedule = |s: &str| s.parse::<Schedule>().unwrap();
+        let work_hours = schedule("mon-fri@09:00-17:00");
+        let night = schedule("sat,sun@22:00-06:00");
+        let at = |day: u32, hour: u32| day * MINUTES_PER_DAY + hour * 60;
+
+        assert!(work_hours.is_active(at(0, 9)));
+        assert!(!work_hours.is_active(at(4, 17)));
+        assert!(!work_hours.is_active(at(5, 10)));
+        assert!(night.is_active(at(6, 23)));
+        assert!(night.is_active(at(0, 5)));
+        assert!(!night.is_active(at(4, 23)));
+        assert_eq!(schedule("sat-mon@00:00-24:00").days, 0b1100001);
+        assert!("mon@09:00-09:00".parse::<Schedule>().is_err());
+        assert!("mon@25:00-26:00".parse::<Schedule>().is_err());
+
+        let domains = FxHashSet::from_iter([
+            "video.example.".to_string(),
+            "chat.example.$dnstype=AAAA".to_string(),
+        ]);
+        let lists = ScheduledLists::new(vec![(work_hours, domains)], 9 * 60 * 60);
+        let matches = |domain, query_type| lists.matches(domain, query_type, Matching::Labels);
+        // Monday 2024-01-01 09:30 at UTC+9.
+        lists.update(1_704_069_000);
+        assert!(matches("cdn.video.example.", RecordType::A));
+        assert!(matches("chat.example.", RecordType::AAAA));
+        assert!(!matches("chat.example.", RecordType::A));
+        lists.update(1_704_069_000 + 8 * 60 * 60);
+        assert!(!matches("cdn.video.example.", RecordType::A));
+        assert!(!matches("chat.example.", RecordType::AAAA));
+    }
+}
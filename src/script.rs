@@ -0,0 +1,157 @@
+use crate::plugin::Decision;
+use hickory_proto::rr::RecordType;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use std::cell::Cell;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// A rhai script whose `policy(client, name, type)` function is called for every query.
+pub struct PolicyScript {
+    engine: Engine,
+    ast: AST,
+    timeout: Duration,
+}
+
+impl PolicyScript {
+    pub fn load(path: PathBuf, timeout: Duration) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.on_progress(|_| {
+            let expired = DEADLINE.with(|it| it.get().is_some_and(|it| Instant::now() >= it));
+            expired.then(|| Dynamic::from("policy script timed out"))
+        });
+
+        let ast = engine.compile_file(path)?;
+
+        Ok(PolicyScript {
+            engine,
+            ast,
+            timeout,
+        })
+    }
+
+    pub fn evaluate(&self, client: IpAddr, name: &str, query_type: RecordType) -> Decision {
+        DEADLINE.with(|it| it.set(Some(Instant::now() + self.timeout)));
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().eval_ast(false),
+            &mut Scope::new(),
+            &self.ast,
+            "policy",
+            (client.to_string(), name.to_string(), query_type.to_string()),
+        );
+        DEADLINE.with(|it| it.set(None));
+
+        let decision = result
+            .map_err(|e| e.to_string())
+            .and_then(|it| parse_decision(&it));
+
+        decision.unwrap_or_else(|e| {
+            warn!("policy script failed for {}: {}", name, e);
+            metrics::counter!("dns_policy_script_errors").increment(1);
+            Decision::Default
+        })
+    }
+}
+
+fn parse_decision(value: &Dynamic) -> Result<Decision, String> {
+    if value.is_unit() {
+        return Ok(Decision::Default);
+    }
+
+    if let Some(map) = value.read_lock::<Map>() {
+        let rewrite = map
+            .get("rewrite")
+            .ok_or("map decisions must have a rewrite key")?;
+        let address = rewrite.to_string();
+        return address
+            .parse()
+            .map(Decision::Rewrite)
+            .map_err(|_| format!("invalid rewrite address: {}", address));
+    }
+
+    match value.to_string().as_str() {
+        "default" => Ok(Decision::Default),
+        "allow" => Ok(Decision::Allow),
+        "block" => Ok(Decision::Block),
+        other => Err(format!("unknown decision: {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(name: &str, source: &str) -> PolicyScript {
+        let path =
+            std::env::temp_dir().join(format!("advoid-{}-{}.rhai", name, std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let script = PolicyScript::load(path.clone(), Duration::from_millis(50)).unwrap();
+        std::fs::remove_file(path).unwrap();
+        script
+    }
+
+    #[test]
+    fn parses_decisions() {
+        let script = script(
+            "decisions",
+            r#"
+fn policy(client, name, type) {
+    switch name {
+        "allow.example" => "allow",
+        "block.example" => "block",
+        "nas.home" => #{ rewrite: "192.168.1.10" },
+        "bad.home" => #{ rewrite: "not an address" },
+        "missing.home" => #{ address: "192.168.1.10" },
+        "typo.example" => "blokc",
+        "aaaa.example" => if type == "AAAA" { "block" },
+        _ => "default",
+    }
+}
+"#,
+        );
+        let client = IpAddr::from([192, 168, 1, 2]);
+        let decide = |name, query_type| script.evaluate(client, name, query_type);
+
+        assert_eq!(decide("allow.example", RecordType::A), Decision::Allow);
+        assert_eq!(decide("block.example", RecordType::A), Decision::Block);
+        assert_eq!(
+            decide("nas.home", RecordType::A),
+            Decision::Rewrite(IpAddr::from([192, 168, 1, 10]))
+        );
+        assert_eq!(decide("aaaa.example", RecordType::AAAA), Decision::Block);
+        // Nothing returned, an unknown decision or a bad rewrite fall through as well.
+        assert_eq!(decide("aaaa.example", RecordType::A), Decision::Default);
+        for name in ["other.example", "typo.example", "bad.home", "missing.home"] {
+            assert_eq!(decide(name, RecordType::A), Decision::Default, "{}", name);
+        }
+    }
+
+    #[test]
+    fn stops_scripts_running_past_the_timeout() {
+        let script = script(
+            "timeout",
+            r#"
+fn policy(client, name, type) {
+    if name == "loop.example" {
+        loop {}
+    }
+    "block"
+}
+"#,
+        );
+        let client = IpAddr::from([192, 168, 1, 2]);
+
+        let start = Instant::now();
+        let decision = script.evaluate(client, "loop.example", RecordType::A);
+        assert_eq!(decision, Decision::Default);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // The deadline of the stopped call doesn't carry over to the next one.
+        let decision = script.evaluate(client, "ads.example", RecordType::A);
+        assert_eq!(decision, Decision::Block);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::cache::{read_bytes, read_u32, ResponseCache};
+use crate::clock::unix_time;
+use crate::dns::CheckedDomain;
+use crate::fs::write_atomically;
+use crate::suffix::{Matching, SuffixSet};
+use arc_swap::ArcSwap;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+const MAGIC: &[u8; 8] = b"ADVOID\x00\x01";
+
+/// The response cache and the block/allow decisions, kept across restarts.
+pub struct Snapshot {
+    cache: Option<Arc<ResponseCache>>,
+    checked: Arc<Mutex<CheckedDomain>>,
+    blacklist: Arc<ArcSwap<SuffixSet>>,
+    allowlist: Arc<ArcSwap<SuffixSet>>,
+    matching: Matching,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        cache: Option<Arc<ResponseCache>>,
+        checked: Arc<Mutex<CheckedDomain>>,
+        blacklist: Arc<ArcSwap<SuffixSet>>,
+        allowlist: Arc<ArcSwap<SuffixSet>>,
+        matching: Matching,
+    ) -> Self {
+        Snapshot {
+            cache,
+            checked,
+            blacklist,
+            allowlist,
+            matching,
+        }
+    }
+
+    /// Identifies the lists the block/allow decisions were made against, and how. Snapshots
+    /// saved before label-aware matching keep the fingerprint they had under suffix matching.
+    fn fingerprint(&self) -> u64 {
+        self.blacklist.load().fingerprint()
+            ^ self.allowlist.load().fingerprint().rotate_left(1)
+            ^ (self.matching == Matching::Labels) as u64
+    }
+
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        buf.write_all(MAGIC)?;
+        buf.write_all(&unix_time().as_secs().to_be_bytes())?;
+        let checked = self.checked.lock().await;
+        buf.write_all(&self.fingerprint().to_be_bytes())?;
+        write_names(&mut buf, &checked.names(true))?;
+        write_names(&mut buf, &checked.names(false))?;
+        drop(checked);
+
+        if let Some(cache) = &self.cache {
+            cache.save(&mut buf)?;
+        }
+
+        write_atomically(path, buf).await?;
+        debug!("saved snapshot to {}", path.display());
+        Ok(())
+    }
+
+    /// Restores a snapshot saved by `save`. A missing file is not an error.
+    pub async fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let buf = match tokio::fs::read(path).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut r = buf.as_slice();
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        anyhow::ensure!(
+            &magic == MAGIC,
+            "{} is not an advoid snapshot",
+            path.display()
+        );
+
+        let saved_at = Duration::from_secs(read_u64(&mut r)?);
+        let elapsed = unix_time().saturating_sub(saved_at);
+        let fingerprint = read_u64(&mut r)?;
+
+        let block = read_names(&mut r)?;
+        let allow = read_names(&mut r)?;
+        // Decisions made against another blocklist or allowlist would be wrong now.
+        let mut checked = self.checked.lock().await;
+        if fingerprint == self.fingerprint() {
+            checked.clear();
+            for name in block {
+                checked.insert(0, name, true);
+            }
+            for name in allow {
+                checked.insert(0, name, false);
+            }
+        }
+        drop(checked);
+
+        // The cache section is missing when the snapshot was saved with the cache disabled.
+        let loaded = match &self.cache {
+            Some(cache) if !r.is_empty() => cache.load(&mut r, elapsed)?,
+            _ => 0,
+        };
+
+        debug!(
+            "loaded {} cache entries from snapshot {}",
+            loaded,
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn write_names(w: &mut impl Write, names: &[&str]) -> io::Result<()> {
+    w.write_all(&(names.len() as u32).to_be_bytes())?;
+    for name in names {
+        w.write_all(&(name.len() as u32).to_be_bytes())?;
+        w.write_all(name.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_names(r: &mut impl Read) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for _ in 0..read_u32(r)? {
+        let len = read_u32(r)?;
+        names.push(String::from_utf8(read_bytes(r, len)?)?);
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_client::op::{DnsResponse, Message, Query};
+    use hickory_client::rr::rdata::A;
+    use hickory_client::rr::{DNSClass, Name, RData, Record, RecordType};
+    use std::num::NonZeroUsize;
+    use std::str::FromStr;
+
+    fn snapshot(blocked: &[&str]) -> Snapshot {
+        let lists = |entries: &[&str]| Arc::new(ArcSwap::from_pointee(SuffixSet::new(entries)));
+        Snapshot::new(
+            Some(Arc::new(ResponseCache::new(NonZeroUsize::new(10).unwrap()))),
+            Arc::new(Mutex::new(CheckedDomain::new(
+                NonZeroUsize::new(10).unwrap(),
+            ))),
+            lists(blocked),
+            lists(&[]),
+            Matching::Labels,
+        )
+    }
+
+    #[tokio::test]
+    async fn restores_the_caches_it_saved() {
+        let path = std::env::temp_dir().join(format!("advoid-snapshot-{}", std::process::id()));
+        let saved = snapshot(&["ads.example."]);
+        let name = Name::from_str("www.example.com.").unwrap();
+        let mut message = Message::new();
+        message.add_query(Query::query(name.clone(), RecordType::A));
+        message.add_answer(Record::from_rdata(
+            name.clone(),
+            300,
+            RData::A(A::new(192, 0, 2, 1)),
+        ));
+        let cache = saved.cache.as_ref().unwrap();
+        let key = crate::cache::CacheKey::new(&name, DNSClass::IN, RecordType::A);
+        cache.insert(key.clone(), &DnsResponse::from_message(message).unwrap());
+        let mut checked = saved.checked.lock().await;
+        checked.insert(0, "ads.example.".to_string(), true);
+        checked.insert(0, "www.example.com.".to_string(), false);
+        drop(checked);
+        saved.save(&path).await.unwrap();
+
+        let loaded = snapshot(&["ads.example."]);
+        loaded.load(&path).await.unwrap();
+        let checked = loaded.checked.lock().await;
+        assert_eq!(checked.names(true), ["ads.example."]);
+        assert_eq!(checked.names(false), ["www.example.com."]);
+        drop(checked);
+        let cache = loaded.cache.as_ref().unwrap();
+        assert_eq!(cache.get(&key).unwrap().answers()[0].ttl(), 300);
+
+        // Decisions made against other lists are dropped, cached answers are kept.
+        let other = snapshot(&["tracker.example."]);
+        other.load(&path).await.unwrap();
+        assert!(other.checked.lock().await.names(true).is_empty());
+        assert!(other.cache.as_ref().unwrap().get(&key).is_some());
+
+        let data = std::fs::read(&path).unwrap();
+        let mut newer = data.clone();
+        newer[7] = 2;
+        std::fs::write(&path, newer).unwrap();
+        assert!(snapshot(&[]).load(&path).await.is_err());
+        for len in [4, 20, 30, data.len() - 1] {
+            std::fs::write(&path, &data[..len]).unwrap();
+            assert!(snapshot(&[]).load(&path).await.is_err(), "{}", len);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(snapshot(&[]).load(&path).await.is_ok());
+    }
+}
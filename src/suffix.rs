@@ -0,0 +1,302 @@
+use hickory_client::rr::RecordType;
+use rustc_hash::FxHashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Separates a domain from the record types an entry is limited to, as in
+/// `example.com.$dnstype=AAAA|TXT`.
+pub const TYPE_OPTION: &str = "$dnstype=";
+
+/// How entries are matched against the end of query names.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Matching {
+    /// The entry itself and its subdomains, so `ads.example.` doesn't match `notads.example.`.
+    #[default]
+    Labels,
+    /// Any name ending with the entry, label boundary or not, as older versions did.
+    Suffix,
+}
+
+/// A set of domains matched against the end of query names, built as a radix tree over the
+/// reversed domains so a lookup only walks the name instead of every entry.
+pub struct SuffixSet {
+    nodes: Vec<Node>,
+    /// The record types of entries limited to some, by the node of their domain.
+    typed: FxHashMap<u32, Vec<Box<[RecordType]>>>,
+    len: usize,
+    fingerprint: u64,
+}
+
+struct Node {
+    /// Bytes leading to this node, in reverse order.
+    edge: Box<[u8]>,
+    terminal: bool,
+    /// Sorted by the first byte of the child's edge.
+    children: Vec<(u8, u32)>,
+}
+
+impl SuffixSet {
+    pub fn new<S: AsRef<str>>(entries: impl IntoIterator<Item = S>) -> Self {
+        let mut set = SuffixSet {
+            nodes: vec![Node {
+                edge: Box::default(),
+                terminal: false,
+                children: Vec::new(),
+            }],
+            typed: FxHashMap::default(),
+            len: 0,
+            fingerprint: 0,
+        };
+        for entry in entries {
+            set.insert(entry.as_ref());
+        }
+        set.fingerprint ^= set.len as u64;
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Changes whenever the entries do, regardless of the order they were added in.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    /// Whether any entry is limited to some record types.
+    pub fn has_typed(&self) -> bool {
+        !self.typed.is_empty()
+    }
+
+    fn insert(&mut self, entry: &str) {
+        let mut hasher = DefaultHasher::new();
+        let added = match entry.split_once(TYPE_OPTION) {
+            None => {
+                let node = self.node(entry);
+                entry.hash(&mut hasher);
+                !std::mem::replace(&mut self.nodes[node].terminal, true)
+            }
+            Some((domain, types)) => {
+                let Some(types) = parse_types(types) else {
+                    return;
+                };
+                let node = self.node(domain);
+                (domain, &types).hash(&mut hasher);
+                let rules = self.typed.entry(node as u32).or_default();
+                let added = !rules.contains(&types);
+                if added {
+                    rules.push(types);
+                }
+                added
+            }
+        };
+        if added {
+            self.len += 1;
+            self.fingerprint ^= hasher.finish();
+        }
+    }
+
+    /// The node of `domain`, added if there's none yet.
+    fn node(&mut self, domain: &str) -> usize {
+        let key: Vec<u8> = domain.bytes().rev().collect();
+        let mut node = 0;
+        let mut rest = key.as_slice();
+
+        loop {
+            if rest.is_empty() {
+                return node;
+            }
+
+            let children = &self.nodes[node].children;
+            let child = match children.binary_search_by_key(&rest[0], |(first, _)| *first) {
+                Ok(i) => children[i].1 as usize,
+                Err(i) => {
+                    let leaf = self.push(rest, false);
+                    self.nodes[node].children.insert(i, (rest[0], leaf as u32));
+                    continue;
+                }
+            };
+
+            let edge = &self.nodes[child].edge;
+            let common = edge.iter().zip(rest).take_while(|(a, b)| a == b).count();
+            if common < edge.len() {
+                // Split the edge where the new entry branches off.
+                let (head, tail) = edge.split_at(common);
+                let (head, tail) = (head.to_vec(), tail.to_vec());
+                let split = self.push(&head, false);
+                self.nodes[split].children.push((tail[0], child as u32));
+                self.nodes[child].edge = tail.into_boxed_slice();
+
+                let children = &mut self.nodes[node].children;
+                let i = children
+                    .binary_search_by_key(&rest[0], |(first, _)| *first)
+                    .expect("the split edge starts like the one it replaces");
+                children[i].1 = split as u32;
+                node = split;
+            } else {
+                node = child;
+            }
+            rest = &rest[common..];
+        }
+    }
+
+    fn push(&mut self, edge: &[u8], terminal: bool) -> usize {
+        self.nodes.push(Node {
+            edge: edge.into(),
+            terminal,
+            children: Vec::new(),
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Whether `domain` ends with any of the entries not limited to some record types.
+    pub fn matches(&self, domain: &str, matching: Matching) -> bool {
+        self.walk(domain, matching, |node, _| node.terminal)
+    }
+
+    /// Whether `domain` ends with any of the entries covering `query_type`.
+    pub fn matches_type(&self, domain: &str, query_type: RecordType, matching: Matching) -> bool {
+        self.walk(domain, matching, |node, i| {
+            node.terminal
+                || self
+                    .typed
+                    .get(&i)
+                    .is_some_and(|rules| rules.iter().any(|it| it.contains(&query_type)))
+        })
+    }
+
+    /// Whether `hit` holds for any node on the way to `domain` that `matching` accepts.
+    fn walk(&self, domain: &str, matching: Matching, hit: impl Fn(&Node, u32) -> bool) -> bool {
+        let bytes = domain.as_bytes();
+        let mut end = bytes.len();
+        let mut index = 0;
+        let mut node = &self.nodes[0];
+
+        loop {
+            let aligned = matching == Matching::Suffix || end == 0 || bytes[end - 1] == b'.';
+            if aligned && hit(node, index) {
+                return true;
+            }
+            let Some(&next) = end.checked_sub(1).map(|i| &bytes[i]) else {
+                return false;
+            };
+            let Ok(i) = node
+                .children
+                .binary_search_by_key(&next, |(first, _)| *first)
+            else {
+                return false;
+            };
+
+            index = node.children[i].1;
+            let child = &self.nodes[index as usize];
+            if child.edge.len() > end
+                || !child
+                    .edge
+                    .iter()
+                    .zip(bytes[..end].iter().rev())
+                    .all(|(a, b)| a == b)
+            {
+                return false;
+            }
+            end -= child.edge.len();
+            node = child;
+        }
+    }
+}
+
+/// `AAAA|TXT` as record types, sorted so the same ones listed in another order compare equal.
+pub(crate) fn parse_types(types: &str) -> Option<Box<[RecordType]>> {
+    let mut types = types
+        .split('|')
+        .map(|it| RecordType::from_str(&it.to_ascii_uppercase()).ok())
+        .collect::<Option<Vec<_>>>()?;
+    types.sort_by_key(|it| u16::from(*it));
+    types.dedup();
+    Some(types.into_boxed_slice())
+}
+
+impl Default for SuffixSet {
+    fn default() -> Self {
+        SuffixSet::new(std::iter::empty::<&str>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Matching::*;
+    use super::*;
+
+    #[test]
+    fn matches_entries_and_names_ending_with_them() {
+        let set = SuffixSet::new(["example.com.", "ads.example.", "tracker.example."]);
+
+        assert_eq!(set.len(), 3);
+        assert!(set.matches("example.com.", Labels));
+        assert!(set.matches("www.example.com.", Labels));
+        assert!(set.matches("a.b.ads.example.", Labels));
+        assert!(set.matches("tracker.example.", Labels));
+        assert!(!set.matches("example.org.", Labels));
+        assert!(!set.matches("com.", Labels));
+        assert!(!set.matches("example.", Labels));
+        assert!(!set.matches("", Labels));
+    }
+
+    #[test]
+    fn matches_on_label_boundaries_unless_asked_not_to() {
+        let set = SuffixSet::new(["ads.example.", "exfil.example.$dnstype=TXT"]);
+
+        assert!(!set.matches("notads.example.", Labels));
+        assert!(set.matches("notads.example.", Suffix));
+        assert!(!set.matches_type("notexfil.example.", RecordType::TXT, Labels));
+        assert!(set.matches_type("notexfil.example.", RecordType::TXT, Suffix));
+    }
+
+    #[test]
+    fn splits_shared_suffixes() {
+        // Inserted in an order that splits edges both ways.
+        let set = SuffixSet::new(["bexample.", "aexample.", "example.", "ample."]);
+
+        for domain in ["bexample.", "aexample.", "example.", "ample.", "x.ample."] {
+            assert!(set.matches(domain, Labels), "{}", domain);
+        }
+        assert!(!set.matches("mple.", Labels));
+        assert!(!set.matches("other.", Labels));
+    }
+
+    #[test]
+    fn limits_typed_entries_to_their_record_types() {
+        let set = SuffixSet::new([
+            "dualstack.example.$dnstype=AAAA",
+            "exfil.example.$dnstype=txt|NULL",
+            "exfil.example.$dnstype=NULL|TXT",
+            "bogus.example.$dnstype=NOPE",
+            "ads.example.",
+        ]);
+
+        assert_eq!(set.len(), 3);
+        assert!(set.has_typed());
+        assert!(set.matches_type("www.dualstack.example.", RecordType::AAAA, Labels));
+        assert!(!set.matches_type("www.dualstack.example.", RecordType::A, Labels));
+        assert!(!set.matches("www.dualstack.example.", Labels));
+        assert!(set.matches_type("a.exfil.example.", RecordType::TXT, Labels));
+        assert!(!set.matches_type("bogus.example.", RecordType::A, Labels));
+        assert!(set.matches_type("ads.example.", RecordType::MX, Labels));
+    }
+
+    #[test]
+    fn fingerprint_ignores_order_and_duplicates() {
+        let a = SuffixSet::new(["a.example.", "b.example."]);
+        let b = SuffixSet::new(["b.example.", "a.example.", "b.example."]);
+        let c = SuffixSet::new(["a.example."]);
+
+        assert_eq!(b.len(), 2);
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_ne!(a.fingerprint(), c.fingerprint());
+        assert!(SuffixSet::default().is_empty());
+    }
+}
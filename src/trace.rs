@@ -1,21 +1,33 @@
+#[cfg(feature = "otel")]
 use opentelemetry;
+#[cfg(feature = "otel")]
 use opentelemetry::metrics::MeterProvider;
+#[cfg(feature = "otel")]
 use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otel")]
 use opentelemetry::KeyValue;
+#[cfg(feature = "otel")]
 use opentelemetry_otlp::{Protocol, WithExportConfig};
+#[cfg(feature = "otel")]
 use opentelemetry_sdk::Resource;
+#[cfg(feature = "otel")]
 use std::time::Duration;
-use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::registry::Registry;
 use tracing_subscriber::util::SubscriberInitExt;
 
+#[cfg(feature = "otel")]
 pub struct OtelInitGuard();
 
+#[cfg(feature = "otel")]
 impl Drop for OtelInitGuard {
     fn drop(&mut self) {
         opentelemetry::global::shutdown_tracer_provider();
     }
 }
 
+#[cfg(feature = "otel")]
 fn build_meter_provider(
     service: &'static str,
     version: &'static str,
@@ -46,10 +58,12 @@ fn build_meter_provider(
         .build()
 }
 
+#[cfg(feature = "otel")]
 pub fn init_tracing(
     service: &'static str,
     version: &'static str,
     endpoint: String,
+    tokio_console: bool,
 ) -> OtelInitGuard {
     use opentelemetry_sdk::trace::{RandomIdGenerator, Sampler};
 
@@ -77,19 +91,35 @@ pub fn init_tracing(
     let otel_metrics_layer =
         tracing_opentelemetry::MetricsLayer::new(build_meter_provider(service, version, &endpoint));
 
-    tracing_subscriber::Registry::default()
-        .with(tracing_subscriber::fmt::Layer::new())
-        .with(otel_trace_layer)
-        .with(otel_metrics_layer)
-        .with(tracing_subscriber::filter::EnvFilter::from_default_env())
+    Registry::default()
+        .with(console_layer(tokio_console))
+        .with(
+            tracing_subscriber::fmt::Layer::new()
+                .and_then(otel_trace_layer)
+                .and_then(otel_metrics_layer)
+                .with_filter(EnvFilter::from_default_env()),
+        )
         .init();
 
     OtelInitGuard()
 }
 
-pub fn init_tracing_without_otel() {
-    tracing_subscriber::Registry::default()
-        .with(tracing_subscriber::fmt::Layer::new())
-        .with(tracing_subscriber::filter::EnvFilter::from_default_env())
+pub fn init_tracing_without_otel(tokio_console: bool) {
+    Registry::default()
+        .with(console_layer(tokio_console))
+        .with(tracing_subscriber::fmt::Layer::new().with_filter(EnvFilter::from_default_env()))
         .init();
 }
+
+/// The layer tokio-console connects to (on 127.0.0.1:6669 unless `TOKIO_CONSOLE_BIND` says
+/// otherwise), when asked for. It records the task and resource events of tokio whatever
+/// `RUST_LOG` says, so the log filter only applies to the other layers.
+#[cfg(feature = "tokio-console")]
+fn console_layer(enabled: bool) -> Option<impl Layer<Registry>> {
+    enabled.then(console_subscriber::spawn)
+}
+
+#[cfg(not(feature = "tokio-console"))]
+fn console_layer(_enabled: bool) -> Option<tracing_subscriber::layer::Identity> {
+    None
+}
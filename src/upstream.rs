@@ -0,0 +1,891 @@
+use crate::capture::WireCapture;
+use crate::metrics::record_stage_duration;
+use arc_swap::ArcSwapOption;
+use hickory_client::client::AsyncClient;
+use hickory_client::op::{DnsResponse, Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_client::rr::{DNSClass, Name, RData, Record, RecordType};
+use hickory_client::tcp::TcpClientStream;
+use hickory_client::udp::UdpClientStream;
+use hickory_proto::error::ProtoError;
+#[cfg(feature = "encrypted-upstream")]
+use hickory_proto::h2::HttpsClientStreamBuilder;
+use hickory_proto::iocompat::AsyncIoTokioAsStd;
+use hickory_proto::op::update_message::MAX_PAYLOAD_LEN;
+#[cfg(feature = "encrypted-upstream")]
+use hickory_proto::quic::QuicClientStream;
+use hickory_proto::rr::rdata::opt::EdnsOption;
+#[cfg(feature = "encrypted-upstream")]
+use hickory_proto::rustls::tls_client_connect;
+use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer};
+use rand::Rng;
+#[cfg(feature = "encrypted-upstream")]
+use reqwest::Url;
+#[cfg(feature = "encrypted-upstream")]
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tracing::{debug, instrument, warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Consecutive query failures after which an upstream is skipped.
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// How long a fallback connection is used before the primary transport is tried again.
+const FALLBACK_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub enum Transport {
+    Udp(SocketAddr),
+    Tls { addr: SocketAddr, name: String },
+    Https { addr: SocketAddr, name: String },
+    Quic { addr: SocketAddr, name: String },
+}
+
+#[cfg(feature = "encrypted-upstream")]
+impl Transport {
+    /// Builds a DNS-over-HTTPS transport from a `https://host[:port]/dns-query` url.
+    ///
+    /// The host is resolved with the system resolver unless a bootstrap address is given.
+    pub async fn https(url: &Url, bootstrap: Option<IpAddr>) -> anyhow::Result<Self> {
+        if url.scheme() != "https" {
+            anyhow::bail!("DoH upstream must be an https url: {}", url);
+        }
+
+        if !matches!(url.path(), "" | "/" | "/dns-query") {
+            anyhow::bail!("DoH upstream path must be /dns-query: {}", url);
+        }
+
+        let name = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("DoH upstream has no host: {}", url))?
+            .to_string();
+        let port = url.port().unwrap_or(443);
+
+        let ip = match (bootstrap, name.parse::<IpAddr>()) {
+            (Some(ip), _) => ip,
+            (None, Ok(ip)) => ip,
+            (None, Err(_)) => tokio::net::lookup_host((name.as_str(), port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("could not resolve DoH upstream {}", name))?
+                .ip(),
+        };
+
+        Ok(Transport::Https {
+            addr: SocketAddr::new(ip, port),
+            name,
+        })
+    }
+}
+
+impl Transport {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Transport::Udp(addr) => *addr,
+            Transport::Tls { addr, .. }
+            | Transport::Https { addr, .. }
+            | Transport::Quic { addr, .. } => *addr,
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp(addr) => write!(f, "udp://{}", addr),
+            Transport::Tls { addr, name } => write!(f, "tls://{}#{}", addr, name),
+            Transport::Https { addr, name } => write!(f, "https://{}/dns-query#{}", addr, name),
+            Transport::Quic { addr, name } => write!(f, "quic://{}#{}", addr, name),
+        }
+    }
+}
+
+struct Connection {
+    client: AsyncClient,
+    fallback: bool,
+    established: Instant,
+}
+
+/// A pooled connection that is read without locking and only locked while (re)connecting.
+struct Slot {
+    connection: ArcSwapOption<Connection>,
+    connecting: Mutex<()>,
+}
+
+struct Health {
+    failures: AtomicU32,
+    skip_until: std::sync::Mutex<Option<Instant>>,
+}
+
+pub struct Upstream {
+    transport: Transport,
+    /// Label of the upstream in metrics.
+    label: String,
+    fallback: Option<Transport>,
+    slots: Vec<Slot>,
+    next_slot: AtomicUsize,
+    health: Health,
+    cooldown: Duration,
+    randomize_case: bool,
+    capture: Option<Arc<WireCapture>>,
+}
+
+impl Upstream {
+    pub fn new(transport: Transport) -> Self {
+        let upstream = Upstream {
+            label: transport.to_string(),
+            transport,
+            fallback: None,
+            slots: Vec::new(),
+            next_slot: AtomicUsize::new(0),
+            health: Health {
+                failures: AtomicU32::new(0),
+                skip_until: std::sync::Mutex::new(None),
+            },
+            cooldown: Duration::from_secs(30),
+            randomize_case: false,
+            capture: None,
+        };
+        upstream.with_connections(1)
+    }
+
+    /// Labels this upstream's metrics with `name` (e.g. "cloudflare") instead of its address.
+    pub fn with_name(mut self, name: String) -> Self {
+        self.label = name;
+        self
+    }
+
+    /// Spreads queries over `connections` connections to the upstream.
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        self.slots = (0..connections.max(1))
+            .map(|_| Slot {
+                connection: ArcSwapOption::empty(),
+                connecting: Mutex::new(()),
+            })
+            .collect();
+        self
+    }
+
+    /// Uses `fallback` when a connection over the primary transport cannot be established.
+    pub fn with_fallback(mut self, fallback: Transport) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Skips this upstream for `cooldown` once it is considered unhealthy.
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Randomizes the query name case over UDP (DNS 0x20) and rejects answers that don't echo it.
+    pub fn with_case_randomization(mut self, randomize_case: bool) -> Self {
+        self.randomize_case = randomize_case;
+        self
+    }
+
+    /// Writes the sampled queries to this upstream and their answers to `capture`.
+    pub fn with_capture(mut self, capture: Arc<WireCapture>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn is_available(&self) -> bool {
+        let skip_until = self.health.skip_until.lock().unwrap();
+        skip_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    #[instrument(skip(self, options), fields(upstream = %self.transport))]
+    pub async fn query(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        options: &[EdnsOption],
+    ) -> anyhow::Result<DnsResponse> {
+        let start = Instant::now();
+        let result = self
+            .query_inner(name, query_class, query_type, options)
+            .await;
+        metrics::histogram!("dns_upstream_duration_seconds", "upstream" => self.label.clone())
+            .record(start.elapsed().as_secs_f64());
+
+        self.record_result(result.is_ok());
+        result
+    }
+
+    /// Opens the circuit after `FAILURE_THRESHOLD` failed queries in a row.
+    fn record_result(&self, ok: bool) {
+        if ok {
+            self.health.failures.store(0, Ordering::Relaxed);
+        } else {
+            metrics::counter!("dns_upstream_errors", "upstream" => self.label.clone()).increment(1);
+            if self.health.failures.fetch_add(1, Ordering::Relaxed) + 1 >= FAILURE_THRESHOLD {
+                self.mark_unhealthy();
+            }
+        }
+    }
+
+    /// Sends a lightweight query and opens or closes the circuit depending on the outcome.
+    #[instrument(skip(self), fields(upstream = %self.transport))]
+    pub async fn probe(&self) {
+        match self
+            .query_inner(Name::root(), DNSClass::IN, RecordType::NS, &[])
+            .await
+        {
+            Ok(_) => self.mark_healthy(),
+            Err(e) => {
+                warn!("health check failed {}: {}", self.transport, e);
+                self.mark_unhealthy();
+            }
+        }
+    }
+
+    /// Drops the pooled connections, so the next queries reconnect, and forgets past failures.
+    pub fn reset(&self) {
+        for slot in &self.slots {
+            slot.connection.store(None);
+        }
+        self.mark_healthy();
+    }
+
+    fn mark_healthy(&self) {
+        self.health.failures.store(0, Ordering::Relaxed);
+        *self.health.skip_until.lock().unwrap() = None;
+        self.record_health(true);
+    }
+
+    fn mark_unhealthy(&self) {
+        *self.health.skip_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+        self.record_health(false);
+    }
+
+    fn record_health(&self, healthy: bool) {
+        metrics::gauge!("dns_upstream_healthy", "upstream" => self.label.clone()).set(if healthy {
+            1.0
+        } else {
+            0.0
+        });
+    }
+
+    async fn query_inner(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        options: &[EdnsOption],
+    ) -> anyhow::Result<DnsResponse> {
+        let slot = &self.slots[self.next_slot.fetch_add(1, Ordering::Relaxed) % self.slots.len()];
+        let (client, transport) = self.client(slot).await?;
+
+        let randomized = self.randomize_case && matches!(transport, Transport::Udp(_));
+        let sent = if randomized {
+            randomize_case(&name)?
+        } else {
+            name.clone()
+        };
+        let capture = self
+            .capture
+            .as_deref()
+            .filter(|it| it.samples(&name))
+            .map(|it| (it, transport.addr()));
+
+        let mut response = match send_query(
+            &client,
+            sent.clone(),
+            query_class,
+            query_type,
+            options,
+            capture,
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) if !matches!(transport, Transport::Udp(_)) => {
+                // The session may have been closed by the upstream while idle,
+                // so reconnect once before giving up.
+                warn!("upstream query failed, reconnecting {}: {}", transport, e);
+                slot.connection.store(None);
+                let (client, _) = self.client(slot).await?;
+                send_query(
+                    &client,
+                    sent.clone(),
+                    query_class,
+                    query_type,
+                    options,
+                    capture,
+                )
+                .await?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if randomized {
+            if !response.queries().iter().all(|it| it.name().eq_case(&sent)) {
+                metrics::counter!("dns_upstream_case_mismatch", "upstream" => self.label.clone())
+                    .increment(1);
+                anyhow::bail!(
+                    "upstream answer for {} did not echo the query name case",
+                    sent
+                );
+            }
+            response = restore_case(response, &sent, &name)?;
+        }
+
+        if response.truncated() {
+            if let Transport::Udp(addr) = transport {
+                debug!("Retrying truncated upstream response over TCP {}", &name);
+                metrics::counter!("dns_requests_tcp_retry").increment(1);
+                return query_over_tcp(*addr, name, query_class, query_type, options, capture)
+                    .await;
+            }
+        }
+
+        Ok(response)
+    }
+
+    async fn client(&self, slot: &Slot) -> anyhow::Result<(AsyncClient, &Transport)> {
+        if let Some(client) = self.usable(slot) {
+            return Ok(client);
+        }
+
+        let _connecting = slot.connecting.lock().await;
+
+        // Another query may have connected while we were waiting.
+        if let Some(client) = self.usable(slot) {
+            return Ok(client);
+        }
+
+        let (client, fallback) = match (connect(&self.transport).await, &self.fallback) {
+            (Ok(client), _) => (client, false),
+            (Err(e), Some(fallback)) => {
+                warn!(
+                    "could not connect to {}, falling back to {}: {}",
+                    self.transport, fallback, e
+                );
+                metrics::counter!("dns_upstream_fallback", "upstream" => self.label.clone())
+                    .increment(1);
+                (connect(fallback).await?, true)
+            }
+            (Err(e), None) => return Err(e),
+        };
+
+        slot.connection.store(Some(Arc::new(Connection {
+            client: client.clone(),
+            fallback,
+            established: Instant::now(),
+        })));
+        Ok((client, self.active(fallback)))
+    }
+
+    fn usable(&self, slot: &Slot) -> Option<(AsyncClient, &Transport)> {
+        let connection = slot.connection.load();
+        let connection = connection.as_ref()?;
+
+        if connection.fallback && connection.established.elapsed() >= FALLBACK_RETRY_INTERVAL {
+            return None;
+        }
+
+        Some((connection.client.clone(), self.active(connection.fallback)))
+    }
+
+    fn active(&self, fallback: bool) -> &Transport {
+        match (&self.fallback, fallback) {
+            (Some(transport), true) => transport,
+            _ => &self.transport,
+        }
+    }
+}
+
+/// Where `StubRequestHandler` sends the queries it forwards.
+#[async_trait::async_trait]
+pub trait UpstreamClient: Send + Sync {
+    async fn query(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        options: &[EdnsOption],
+    ) -> anyhow::Result<DnsResponse>;
+}
+
+/// Upstreams tried in order, skipping the ones that are currently unhealthy.
+pub struct UpstreamGroup {
+    upstreams: Vec<Arc<Upstream>>,
+}
+
+impl UpstreamGroup {
+    pub fn new(upstreams: Vec<Arc<Upstream>>) -> Self {
+        assert!(!upstreams.is_empty(), "upstream group must not be empty");
+        UpstreamGroup { upstreams }
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+}
+
+#[async_trait::async_trait]
+impl UpstreamClient for UpstreamGroup {
+    async fn query(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        options: &[EdnsOption],
+    ) -> anyhow::Result<DnsResponse> {
+        let mut last_error = None;
+        for upstream in &self.upstreams {
+            if !upstream.is_available() {
+                continue;
+            }
+
+            match upstream
+                .query(name.clone(), query_class, query_type, options)
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match last_error {
+            Some(e) => Err(e),
+            // When every upstream is down, trying the first one beats failing outright.
+            None => {
+                self.upstreams[0]
+                    .query(name, query_class, query_type, options)
+                    .await
+            }
+        }
+    }
+}
+
+/// Sends queries under `zones` to every upstream as well and reports the ones answering
+/// differently from the first, to spot upstream censorship or hijacking.
+///
+/// Answers differ when their rcodes do or their addresses have none in common, so CDNs
+/// rotating addresses don't count. Discrepancies are logged under the `advoid::diff` target and
+/// counted in `dns_upstream_discrepancies`; latencies go to `dns_upstream_diff_duration_seconds`.
+pub struct UpstreamDiff {
+    upstreams: Vec<Arc<Upstream>>,
+    zones: Vec<Name>,
+}
+
+/// An upstream's answer as compared by `UpstreamDiff`.
+#[derive(Debug, PartialEq)]
+enum Compared {
+    Answer(ResponseCode, BTreeSet<IpAddr>),
+    Error(String),
+}
+
+impl UpstreamDiff {
+    pub fn new(upstreams: Vec<Arc<Upstream>>, zones: Vec<Name>) -> Self {
+        UpstreamDiff { upstreams, zones }
+    }
+
+    pub(crate) fn covers(&self, name: &Name) -> bool {
+        self.upstreams.len() > 1 && self.zones.iter().any(|it| it.zone_of(name))
+    }
+
+    pub(crate) async fn compare(&self, name: Name, query_class: DNSClass, query_type: RecordType) {
+        let tasks: Vec<_> = self
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let (upstream, name) = (upstream.clone(), name.clone());
+                tokio::spawn(async move {
+                    let start = Instant::now();
+                    let answer = match upstream.query(name, query_class, query_type, &[]).await {
+                        Ok(response) => Compared::Answer(
+                            response.response_code(),
+                            response
+                                .answers()
+                                .iter()
+                                .filter_map(|it| it.data().and_then(RData::ip_addr))
+                                .collect(),
+                        ),
+                        Err(e) => Compared::Error(e.to_string()),
+                    };
+                    let elapsed = start.elapsed();
+                    metrics::histogram!(
+                        "dns_upstream_diff_duration_seconds",
+                        "upstream" => upstream.label.clone()
+                    )
+                    .record(elapsed.as_secs_f64());
+                    (answer, elapsed)
+                })
+            })
+            .collect();
+        let mut answers = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            match task.await {
+                Ok(answer) => answers.push(answer),
+                Err(_) => return,
+            }
+        }
+
+        let (expected, expected_elapsed) = &answers[0];
+        for (upstream, (answer, elapsed)) in self.upstreams.iter().zip(&answers).skip(1) {
+            let Some(kind) = discrepancy(expected, answer) else {
+                continue;
+            };
+            metrics::counter!(
+                "dns_upstream_discrepancies",
+                "upstream" => upstream.label.clone(),
+                "kind" => kind
+            )
+            .increment(1);
+            tracing::warn!(
+                target: "advoid::diff",
+                name = %name,
+                query_type = %query_type,
+                upstream = %upstream.label,
+                reference = %self.upstreams[0].label,
+                kind,
+                "upstream answered {:?} in {:?}, reference {:?} in {:?}",
+                answer,
+                elapsed,
+                expected,
+                expected_elapsed
+            );
+        }
+    }
+}
+
+/// How `answer` differs from the `expected` one, if it does.
+fn discrepancy(expected: &Compared, answer: &Compared) -> Option<&'static str> {
+    match (expected, answer) {
+        (Compared::Answer(a, _), Compared::Answer(b, _)) if a != b => Some("rcode"),
+        (Compared::Answer(_, a), Compared::Answer(_, b))
+            if a.is_disjoint(b) && a.len() + b.len() > 0 =>
+        {
+            Some("addresses")
+        }
+        (Compared::Answer(..), Compared::Answer(..)) => None,
+        (Compared::Error(_), Compared::Error(_)) => None,
+        _ => Some("error"),
+    }
+}
+
+pub fn spawn_health_checks(upstreams: Vec<Arc<Upstream>>, interval: Duration) {
+    for upstream in &upstreams {
+        upstream.record_health(true);
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for upstream in &upstreams {
+                upstream.probe().await;
+            }
+        }
+    });
+}
+
+async fn connect(transport: &Transport) -> anyhow::Result<AsyncClient> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect_transport(transport)).await {
+        Ok(client) => client,
+        Err(_) => anyhow::bail!("connection to {} timed out", transport),
+    }
+}
+
+async fn connect_transport(transport: &Transport) -> anyhow::Result<AsyncClient> {
+    // The background task finishes on its own when the connection is closed.
+    match transport {
+        Transport::Udp(addr) => {
+            let conn = UdpClientStream::<UdpSocket>::new(*addr);
+            let (client, background) = AsyncClient::connect(conn).await?;
+            let _handle = tokio::spawn(background);
+            Ok(client)
+        }
+        #[cfg(feature = "encrypted-upstream")]
+        Transport::Tls { addr, name } => {
+            let (stream, sender) = tls_client_connect::<AsyncIoTokioAsStd<TcpStream>>(
+                *addr,
+                name.clone(),
+                Arc::new(tls_client_config()),
+            );
+            let (client, background) = AsyncClient::new(stream, sender, None).await?;
+            let _handle = tokio::spawn(background);
+            Ok(client)
+        }
+        #[cfg(feature = "encrypted-upstream")]
+        Transport::Https { addr, name } => {
+            let conn = HttpsClientStreamBuilder::with_client_config(Arc::new(tls_client_config()))
+                .build::<AsyncIoTokioAsStd<TcpStream>>(*addr, name.clone());
+            let (client, background) = AsyncClient::connect(conn).await?;
+            let _handle = tokio::spawn(background);
+            Ok(client)
+        }
+        #[cfg(feature = "encrypted-upstream")]
+        Transport::Quic { addr, name } => {
+            let mut builder = QuicClientStream::builder();
+            builder.crypto_config(tls13_client_config());
+            let conn = builder.build(*addr, name.clone());
+            let (client, background) = AsyncClient::connect(conn).await?;
+            let _handle = tokio::spawn(background);
+            Ok(client)
+        }
+        #[cfg(not(feature = "encrypted-upstream"))]
+        Transport::Tls { .. } | Transport::Https { .. } | Transport::Quic { .. } => {
+            anyhow::bail!("{} needs advoid built with encrypted-upstream", transport)
+        }
+    }
+}
+
+fn randomize_case(name: &Name) -> anyhow::Result<Name> {
+    let mut rng = rand::thread_rng();
+    let randomized: String = name
+        .to_ascii()
+        .chars()
+        .map(|c| {
+            if rng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect();
+    Ok(Name::from_ascii(randomized)?)
+}
+
+/// Puts the original query name back on the records that echoed the randomized one.
+fn restore_case(response: DnsResponse, sent: &Name, name: &Name) -> anyhow::Result<DnsResponse> {
+    let mut message = response.into_message();
+
+    for query in message.queries_mut() {
+        query.set_name(name.clone());
+    }
+
+    let restore = |records: &mut Vec<Record>| {
+        for record in records.iter_mut().filter(|it| it.name() == sent) {
+            record.set_name(name.clone());
+        }
+    };
+    restore(message.answers_mut());
+    restore(message.name_servers_mut());
+    restore(message.additionals_mut());
+
+    Ok(DnsResponse::from_message(message)?)
+}
+
+/// Sends a query carrying `options` in its EDNS section.
+async fn send_query(
+    client: &AsyncClient,
+    name: Name,
+    query_class: DNSClass,
+    query_type: RecordType,
+    options: &[EdnsOption],
+    capture: Option<(&WireCapture, SocketAddr)>,
+) -> Result<DnsResponse, ProtoError> {
+    let mut query = Query::query(name, query_type);
+    query.set_query_class(query_class);
+
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD_LEN).set_version(0);
+    for option in options {
+        edns.options_mut().insert(option.clone());
+    }
+
+    let mut message = Message::new();
+    message
+        .add_query(query)
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .set_edns(edns);
+
+    let captured = capture.map(|it| (it, message.clone(), SystemTime::now()));
+    let options = DnsRequestOptions::default();
+    let response = client
+        .send(DnsRequest::new(message, options))
+        .first_answer()
+        .await;
+    if let Some(((capture, upstream), request, sent)) = captured {
+        capture.record(upstream, request, sent, &response);
+    }
+    response
+}
+
+#[instrument(skip(options, capture))]
+async fn query_over_tcp(
+    addr: SocketAddr,
+    name: Name,
+    query_class: DNSClass,
+    query_type: RecordType,
+    options: &[EdnsOption],
+    capture: Option<(&WireCapture, SocketAddr)>,
+) -> anyhow::Result<DnsResponse> {
+    let start = Instant::now();
+    let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(addr);
+    let (upstream, background) = AsyncClient::new(stream, sender, None).await?;
+    let _handle = tokio::spawn(background);
+
+    let response = send_query(&upstream, name, query_class, query_type, options, capture).await;
+    record_stage_duration("upstream_tcp", start);
+    Ok(response?)
+}
+
+#[cfg(feature = "encrypted-upstream")]
+fn tls_client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store())
+        .with_no_client_auth()
+}
+
+#[cfg(feature = "encrypted-upstream")]
+/// DNS-over-QUIC requires TLS 1.3.
+fn tls13_client_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .expect("TLS 1.3 is supported")
+        .with_root_certificates(root_store())
+        .with_no_client_auth()
+}
+
+#[cfg(feature = "encrypted-upstream")]
+fn root_store() -> RootCertStore {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    root_store
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_discrepancies_apart() {
+        let answer = |code, addresses: &[[u8; 4]]| {
+            Compared::Answer(code, addresses.iter().map(|it| IpAddr::from(*it)).collect())
+        };
+        let ok = |addresses: &[[u8; 4]]| answer(ResponseCode::NoError, addresses);
+        let error = Compared::Error("timed out".to_string());
+
+        let cdn = ok(&[[192, 0, 2, 1], [192, 0, 2, 2]]);
+        assert_eq!(
+            discrepancy(&cdn, &ok(&[[192, 0, 2, 1], [192, 0, 2, 2]])),
+            None
+        );
+        // CDNs rotate addresses, so one in common is enough.
+        assert_eq!(
+            discrepancy(&cdn, &ok(&[[192, 0, 2, 2], [192, 0, 2, 3]])),
+            None
+        );
+        assert_eq!(
+            discrepancy(&cdn, &ok(&[[203, 0, 113, 1]])),
+            Some("addresses")
+        );
+        assert_eq!(discrepancy(&cdn, &ok(&[])), Some("addresses"));
+        assert_eq!(discrepancy(&ok(&[]), &ok(&[])), None);
+        assert_eq!(
+            discrepancy(&ok(&[]), &answer(ResponseCode::NXDomain, &[])),
+            Some("rcode")
+        );
+        assert_eq!(discrepancy(&cdn, &error), Some("error"));
+        assert_eq!(discrepancy(&error, &cdn), Some("error"));
+        assert_eq!(discrepancy(&error, &error), None);
+    }
+
+    #[test]
+    fn opens_the_circuit_after_failures_and_closes_it_on_success() {
+        let upstream = Upstream::new(Transport::Udp("127.0.0.1:53".parse().unwrap()))
+            .with_cooldown(Duration::from_millis(20));
+        for _ in 1..FAILURE_THRESHOLD {
+            upstream.record_result(false);
+        }
+        assert!(upstream.is_available());
+
+        // Open: skipped until the cooldown is over.
+        upstream.record_result(false);
+        assert!(!upstream.is_available());
+
+        // Half-open: tried again, and skipped again as soon as a query fails.
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(upstream.is_available());
+        upstream.record_result(false);
+        assert!(!upstream.is_available());
+
+        // Closed: a successful health check lets it through, and single failures don't open it.
+        upstream.mark_healthy();
+        assert!(upstream.is_available());
+        upstream.record_result(false);
+        assert!(upstream.is_available());
+    }
+
+    /// An upstream answering A queries with 192.0.2.1, echoing the query name or its lowercase.
+    async fn fake_upstream(echo_case: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let query = request.queries()[0].clone();
+                let name = match echo_case {
+                    true => query.name().clone(),
+                    false => query.name().to_lowercase(),
+                };
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .add_query(Query::query(name.clone(), query.query_type()))
+                    .add_answer(Record::from_rdata(
+                        name,
+                        300,
+                        RData::A(hickory_client::rr::rdata::A::new(192, 0, 2, 1)),
+                    ));
+                let _ = socket.send_to(&response.to_vec().unwrap(), from).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn rejects_answers_not_echoing_the_randomized_case() {
+        let name = Name::from_ascii("www.example-with-a-long-name.com.").unwrap();
+        let randomized = randomize_case(&name).unwrap();
+        assert_eq!(randomized, name);
+        // 26 letters keep their case at a chance of one in 2^26.
+        assert!(!randomized.eq_case(&name));
+
+        let upstream =
+            Upstream::new(Transport::Udp(fake_upstream(true).await)).with_case_randomization(true);
+        let response = upstream
+            .query(name.clone(), DNSClass::IN, RecordType::A, &[])
+            .await
+            .unwrap();
+        assert!(response.queries()[0].name().eq_case(&name));
+        assert!(response.answers()[0].name().eq_case(&name));
+
+        let upstream =
+            Upstream::new(Transport::Udp(fake_upstream(false).await)).with_case_randomization(true);
+        let error = upstream
+            .query(name.clone(), DNSClass::IN, RecordType::A, &[])
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("did not echo the query name case"));
+    }
+}
@@ -0,0 +1,248 @@
+use hickory_client::op::ResponseCode;
+use hickory_client::rr::{Name, RData, Record, RecordType};
+use hickory_proto::rr::rdata::CNAME;
+use hickory_proto::serialize::txt::Parser;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::path::Path;
+
+/// CNAMEs followed within a zone or the local records before giving up on a loop.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The answer to a query for a name in a local zone.
+pub(crate) struct ZoneAnswer {
+    pub response_code: ResponseCode,
+    pub answers: Vec<Record>,
+    /// The SOA record of the zone for negative answers.
+    pub authority: Vec<Record>,
+}
+
+/// Zones advoid is authoritative for, loaded from zone files. Names under them are never
+/// forwarded: those missing from the file are answered with NXDOMAIN. Delegations to other
+/// name servers within a zone aren't followed.
+#[derive(Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+struct Zone {
+    origin: Name,
+    soa: Record,
+    /// By owner name, lowercase.
+    records: FxHashMap<Name, Vec<Record>>,
+    /// Owner names and the names between them and the origin, which exist without records.
+    names: FxHashSet<Name>,
+}
+
+impl Zones {
+    /// Adds the zone `origin` read from the zone file at `path`.
+    pub async fn load(&mut self, origin: Name, path: &Path) -> anyhow::Result<usize> {
+        let text = tokio::fs::read_to_string(path).await?;
+        self.add(origin, &text, Some(path))
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn add(&mut self, origin: Name, text: &str, path: Option<&Path>) -> anyhow::Result<usize> {
+        let origin = origin.to_lowercase();
+        let (_, sets) =
+            Parser::new(text, path.map(Path::to_path_buf), Some(origin.clone())).parse()?;
+
+        let mut zone = Zone {
+            soa: Record::default(),
+            origin: origin.clone(),
+            records: FxHashMap::default(),
+            names: FxHashSet::default(),
+        };
+        let mut count = 0;
+        for record in sets.values().flat_map(|it| it.records_without_rrsigs()) {
+            let owner = record.name().to_lowercase();
+            anyhow::ensure!(origin.zone_of(&owner), "{} is not under {}", owner, origin);
+            if record.record_type() == RecordType::SOA && owner == origin {
+                zone.soa = record.clone();
+            }
+
+            let mut name = owner.clone();
+            while name.num_labels() > origin.num_labels() && zone.names.insert(name.clone()) {
+                name = name.base_name();
+            }
+            zone.records.entry(owner).or_default().push(record.clone());
+            count += 1;
+        }
+        anyhow::ensure!(
+            zone.soa.record_type() == RecordType::SOA,
+            "no SOA record for {}",
+            origin
+        );
+        zone.names.insert(origin);
+
+        self.zones.push(zone);
+        // The most specific zone is tried first.
+        self.zones
+            .sort_by_key(|it| std::cmp::Reverse(it.origin.num_labels()));
+        Ok(count)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The answer to a `query_type` query for `name`, or `None` if it isn't in any zone.
+    pub(crate) fn lookup(&self, name: &Name, query_type: RecordType) -> Option<ZoneAnswer> {
+        let name = name.to_lowercase();
+        let zone = self.zones.iter().find(|it| it.origin.zone_of(&name))?;
+        Some(zone.lookup(name, query_type))
+    }
+}
+
+impl Zone {
+    fn lookup(&self, name: Name, query_type: RecordType) -> ZoneAnswer {
+        let (answers, owner) = follow_cnames(name.clone(), query_type, |it| self.records_of(it))
+            .unwrap_or((Vec::new(), name));
+
+        let response_code = if answers.is_empty() && !self.names.contains(&owner) {
+            ResponseCode::NXDomain
+        } else {
+            ResponseCode::NoError
+        };
+        let authority = match answers.is_empty() {
+            true => vec![self.soa.clone()],
+            false => Vec::new(),
+        };
+        ZoneAnswer {
+            response_code,
+            answers,
+            authority,
+        }
+    }
+
+    /// The records of `name`, synthesized from the closest wildcard when it has none.
+    fn records_of(&self, name: &Name) -> Option<Vec<Record>> {
+        if !self.origin.zone_of(name) {
+            return None;
+        }
+        records_or_wildcard(&self.records, name, |it| self.names.contains(it))
+    }
+}
+
+/// The answer to a `query_type` query for `name` and the last name looked up, following
+/// CNAMEs to names `records_of` has records for, or `None` if `name` has none itself. CNAMEs
+/// to other names are answered as they are.
+pub(crate) fn follow_cnames(
+    name: Name,
+    query_type: RecordType,
+    records_of: impl Fn(&Name) -> Option<Vec<Record>>,
+) -> Option<(Vec<Record>, Name)> {
+    let mut records = records_of(&name)?;
+    let mut answers = Vec::new();
+    let mut owner = name;
+    for _ in 0..MAX_CNAME_CHAIN {
+        let cname = records.iter().find_map(|it| match it.data() {
+            Some(RData::CNAME(CNAME(target))) if query_type != RecordType::CNAME => {
+                Some(target.to_lowercase())
+            }
+            _ => None,
+        });
+        let matching = records.into_iter().filter(|it| match cname {
+            Some(_) => it.record_type() == RecordType::CNAME,
+            None => query_type == RecordType::ANY || it.record_type() == query_type,
+        });
+        answers.extend(matching);
+        let Some(target) = cname else {
+            break;
+        };
+        owner = target;
+        match records_of(&owner) {
+            Some(next) => records = next,
+            None => break,
+        }
+    }
+    Some((answers, owner))
+}
+
+/// The records of `name`, or those of the closest wildcard above it (`*.lab.home`) renamed to
+/// it. Wildcards aren't looked for above names `exists` is true for, which stand in their way
+/// even without records of their own.
+pub(crate) fn records_or_wildcard(
+    records: &FxHashMap<Name, Vec<Record>>,
+    name: &Name,
+    exists: impl Fn(&Name) -> bool,
+) -> Option<Vec<Record>> {
+    if let Some(records) = records.get(name) {
+        return Some(records.clone());
+    }
+
+    let mut closest = name.clone();
+    while !closest.is_root() && !exists(&closest) {
+        closest = closest.base_name();
+        let wildcard = Name::from_ascii("*").ok()?.append_domain(&closest).ok()?;
+        if let Some(records) = records.get(&wildcard) {
+            return Some(
+                records
+                    .iter()
+                    .map(|it| {
+                        let mut record = it.clone();
+                        record.set_name(name.clone());
+                        record
+                    })
+                    .collect(),
+            );
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn answers_names_in_zones_authoritatively() {
+        let mut zones = Zones::default();
+        let zone = "$TTL 300\n\
+                    @ IN SOA ns.home. admin.home. 1 3600 600 86400 60\n\
+                    @ IN NS ns.home.\n\
+                    ns IN A 192.168.1.2\n\
+                    nas.lab IN A 192.168.1.10\n\
+                    files IN CNAME nas.lab\n\
+                    *.apps IN A 192.168.1.20\n";
+        zones
+            .add(Name::from_str("home.").unwrap(), zone, None)
+            .unwrap();
+        let lookup = |name, query_type| {
+            let answer = zones
+                .lookup(&Name::from_str(name).unwrap(), query_type)
+                .unwrap();
+            let types: Vec<_> = answer.answers.iter().map(|it| it.record_type()).collect();
+            (answer.response_code, types, answer.authority.len())
+        };
+
+        assert_eq!(
+            lookup("HOME.", RecordType::NS),
+            (ResponseCode::NoError, vec![RecordType::NS], 0)
+        );
+        assert_eq!(
+            lookup("files.home.", RecordType::A),
+            (
+                ResponseCode::NoError,
+                vec![RecordType::CNAME, RecordType::A],
+                0
+            )
+        );
+        assert_eq!(
+            lookup("grafana.apps.home.", RecordType::A),
+            (ResponseCode::NoError, vec![RecordType::A], 0)
+        );
+        // Exists without records of its own.
+        assert_eq!(
+            lookup("lab.home.", RecordType::A),
+            (ResponseCode::NoError, vec![], 1)
+        );
+        assert_eq!(
+            lookup("nas.home.", RecordType::A),
+            (ResponseCode::NXDomain, vec![], 1)
+        );
+        assert!(zones
+            .lookup(&Name::from_str("example.com.").unwrap(), RecordType::A)
+            .is_none());
+    }
+}